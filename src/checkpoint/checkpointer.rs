@@ -0,0 +1,696 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    interface::record::KinesisClientRecord, lease::manager::LeaseManager,
+    util::retry::ExponentialBackoff,
+};
+
+use super::{store::CheckpointStore, Checkpoint, CheckpointError};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum CheckpointerStatus {
+    Active,
+    LeaseLost,
+    ShutDown,
+}
+
+struct CoalescingState {
+    window: Duration,
+    last_write_time: Option<Instant>,
+    has_pending: bool,
+    failed_write: Option<CheckpointError>,
+    /// Set after a held back checkpoint failed to save, which isn't tried again before then.
+    retry_time: Option<Instant>,
+    backoff: ExponentialBackoff,
+}
+
+struct CheckpointerState {
+    status: CheckpointerStatus,
+    last_checkpoint: Checkpoint,
+    largest_permitted: Option<Checkpoint>,
+    at_shard_end: bool,
+    coalescing: Option<CoalescingState>,
+}
+
+/// Handed to a `RecordProcessor` so it can record its progress through a shard.
+///
+/// Requests are validated against the records delivered so far: a processor can't checkpoint past
+/// the last record it was given, behind the shard's current checkpoint, or once the lease is gone.
+///
+/// With write coalescing enabled, checkpoints made within the window of the last write are held
+/// back and saved together. A failed coalesced write is returned by the next call. `SHARD_END`,
+/// and checkpoints made once the shard is being let go, are always written straight away.
+pub struct RecordProcessorCheckpointer {
+    lease_key: String,
+    checkpoint_store: Arc<dyn CheckpointStore>,
+    lease_manager: Arc<LeaseManager>,
+    state: Mutex<CheckpointerState>,
+}
+
+impl RecordProcessorCheckpointer {
+    pub(crate) fn new(
+        lease_key: String,
+        initial_checkpoint: Checkpoint,
+        coalescing_window: Option<Duration>,
+        checkpoint_store: Arc<dyn CheckpointStore>,
+        lease_manager: Arc<LeaseManager>,
+    ) -> Self {
+        Self {
+            lease_key,
+            checkpoint_store,
+            lease_manager,
+            state: Mutex::new(CheckpointerState {
+                status: CheckpointerStatus::Active,
+                last_checkpoint: initial_checkpoint,
+                largest_permitted: None,
+                at_shard_end: false,
+                coalescing: coalescing_window.map(|window| CoalescingState {
+                    window,
+                    last_write_time: None,
+                    has_pending: false,
+                    failed_write: None,
+                    retry_time: None,
+                    backoff: ExponentialBackoff::new(
+                        Duration::from_secs(1),
+                        Duration::from_secs(60),
+                    ),
+                }),
+            }),
+        }
+    }
+
+    /// Checkpoints at the last record delivered to the processor, or at `SHARD_END` once the
+    /// shard has been read to its end.
+    pub async fn checkpoint(&self) -> Result<(), CheckpointError> {
+        let mut state = self.state.lock().await;
+        let requested = if state.at_shard_end {
+            Checkpoint::ShardEnd
+        } else {
+            state.largest_permitted.clone().ok_or_else(|| {
+                CheckpointError::SequenceNumberOutOfRange {
+                    requested: state.last_checkpoint.clone(),
+                    largest_permitted: None,
+                }
+            })?
+        };
+        self.checkpoint_locked(&mut state, requested).await
+    }
+
+    /// Checkpoints at the given sequence number, which must have already been delivered.
+    pub async fn checkpoint_at(&self, sequence_number: &str) -> Result<(), CheckpointError> {
+        let requested = match Checkpoint::from_lease_value(sequence_number) {
+            Some(checkpoint @ Checkpoint::SequenceNumber(_)) => checkpoint,
+            _ => {
+                return Err(CheckpointError::InvalidSequenceNumber(
+                    sequence_number.to_string(),
+                ))
+            }
+        };
+        let mut state = self.state.lock().await;
+        self.checkpoint_locked(&mut state, requested).await
+    }
+
+    /// Checkpoints at the given record.
+    pub async fn checkpoint_record(
+        &self,
+        record: &KinesisClientRecord,
+    ) -> Result<(), CheckpointError> {
+        self.checkpoint_at(&record.sequence_number).await
+    }
+
+    /// Saves any checkpoint held back by write coalescing.
+    pub async fn flush(&self) -> Result<(), CheckpointError> {
+        let mut state = self.state.lock().await;
+        if let Some(err) = Self::take_failed_write(&mut state) {
+            if !Self::has_pending(&state) {
+                return Err(err);
+            }
+        }
+        self.flush_locked(&mut state).await
+    }
+
+    /// The last checkpoint accepted for this shard.
+    pub async fn last_checkpoint(&self) -> Checkpoint {
+        self.state.lock().await.last_checkpoint.clone()
+    }
+
+    pub(crate) async fn set_largest_permitted(&self, sequence_number: &str) {
+        let mut state = self.state.lock().await;
+        state.largest_permitted = Some(Checkpoint::SequenceNumber(sequence_number.to_string()));
+    }
+
+    pub(crate) async fn mark_shard_end(&self) {
+        self.state.lock().await.at_shard_end = true;
+    }
+
+    /// Saves a held back checkpoint once its window has passed, keeping any failure for the
+    /// processor's next call. A failed save is tried again after a backoff.
+    pub(crate) async fn flush_if_due(&self) {
+        let mut state = self.state.lock().await;
+        let due = match &state.coalescing {
+            Some(coalescing) => {
+                coalescing.has_pending
+                    && !matches!(
+                        coalescing.last_write_time,
+                        Some(time) if time.elapsed() < coalescing.window
+                    )
+                    && !matches!(coalescing.retry_time, Some(time) if Instant::now() < time)
+            }
+            None => false,
+        };
+        if due {
+            if let Err(err) = self.flush_locked(&mut state).await {
+                if let Some(coalescing) = state.coalescing.as_mut() {
+                    coalescing.failed_write = Some(err);
+                    coalescing.retry_time = Some(Instant::now() + coalescing.backoff.next_delay());
+                }
+            }
+        }
+    }
+
+    /// Saves any held back checkpoint and writes every later one straight away, so the calls a
+    /// processor makes as its shard is let go see their own failures.
+    pub(crate) async fn stop_coalescing(&self) -> Result<(), CheckpointError> {
+        let mut state = self.state.lock().await;
+        let flushed = match Self::take_failed_write(&mut state) {
+            Some(err) if !Self::has_pending(&state) => Err(err),
+            _ => self.flush_locked(&mut state).await,
+        };
+        if let Some(coalescing) = state.coalescing.as_mut() {
+            coalescing.window = Duration::ZERO;
+        }
+        flushed
+    }
+
+    /// When the checkpoint held back by write coalescing is due to be saved, if there is one.
+    pub(crate) async fn flush_deadline(&self) -> Option<Instant> {
+        let state = self.state.lock().await;
+        match &state.coalescing {
+            Some(coalescing) if coalescing.has_pending => {
+                let deadline = coalescing
+                    .last_write_time
+                    .map_or_else(Instant::now, |time| time + coalescing.window);
+                Some(
+                    coalescing
+                        .retry_time
+                        .map_or(deadline, |time| time.max(deadline)),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// Refuses any further checkpoints. Whatever write coalescing held back is dropped: the lease
+    /// is already gone by the time the worker hears about it, so it couldn't be saved anyway.
+    pub(crate) async fn mark_lease_lost(&self) {
+        let mut state = self.state.lock().await;
+        if let Some(coalescing) = state.coalescing.as_mut() {
+            coalescing.has_pending = false;
+        }
+        state.status = CheckpointerStatus::LeaseLost;
+    }
+
+    pub(crate) async fn mark_shut_down(&self) {
+        let mut state = self.state.lock().await;
+        if state.status == CheckpointerStatus::Active {
+            state.status = CheckpointerStatus::ShutDown;
+        }
+    }
+
+    fn has_pending(state: &CheckpointerState) -> bool {
+        matches!(&state.coalescing, Some(coalescing) if coalescing.has_pending)
+    }
+
+    fn take_failed_write(state: &mut CheckpointerState) -> Option<CheckpointError> {
+        state
+            .coalescing
+            .as_mut()
+            .and_then(|coalescing| coalescing.failed_write.take())
+    }
+
+    async fn checkpoint_locked(
+        &self,
+        state: &mut CheckpointerState,
+        requested: Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        match state.status {
+            CheckpointerStatus::Active => {}
+            CheckpointerStatus::LeaseLost => return Err(CheckpointError::LeaseLost),
+            CheckpointerStatus::ShutDown => return Err(CheckpointError::ShutDown),
+        }
+        if let Some(err) = Self::take_failed_write(state) {
+            return Err(err);
+        }
+
+        let within_delivered = match (&requested, &state.largest_permitted) {
+            (Checkpoint::ShardEnd, _) => state.at_shard_end,
+            (_, Some(largest)) => requested <= *largest,
+            (_, None) => false,
+        };
+        if !within_delivered {
+            return Err(CheckpointError::SequenceNumberOutOfRange {
+                requested,
+                largest_permitted: state.largest_permitted.clone(),
+            });
+        }
+        if requested < state.last_checkpoint {
+            return Err(CheckpointError::BehindCurrentCheckpoint {
+                requested,
+                current: state.last_checkpoint.clone(),
+            });
+        }
+        if requested == state.last_checkpoint && !Self::has_pending(state) {
+            return Ok(());
+        }
+
+        // The end of a shard is written straight away, so its children can start
+        let coalescing = state
+            .coalescing
+            .as_mut()
+            .filter(|_| requested != Checkpoint::ShardEnd);
+        if let Some(coalescing) = coalescing {
+            let within_window = matches!(
+                coalescing.last_write_time,
+                Some(time) if time.elapsed() < coalescing.window
+            );
+            if within_window {
+                coalescing.has_pending = true;
+                state.last_checkpoint = requested;
+                return Ok(());
+            }
+        }
+
+        self.write_locked(state, requested).await
+    }
+
+    async fn flush_locked(&self, state: &mut CheckpointerState) -> Result<(), CheckpointError> {
+        if !Self::has_pending(state) {
+            return Ok(());
+        }
+        let checkpoint = state.last_checkpoint.clone();
+        self.write_locked(state, checkpoint).await
+    }
+
+    async fn write_locked(
+        &self,
+        state: &mut CheckpointerState,
+        checkpoint: Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        if !self.lease_manager.holds_lease(&self.lease_key).await {
+            state.status = CheckpointerStatus::LeaseLost;
+            return Err(CheckpointError::LeaseLost);
+        }
+        let mut written = self
+            .checkpoint_store
+            .set_checkpoint(&self.lease_key, &checkpoint)
+            .await;
+        // Child shards, lease cleanup and the hash range audit go by the lease, so the end of the
+        // shard is recorded there too when checkpoints are kept elsewhere
+        if written.is_ok()
+            && checkpoint == Checkpoint::ShardEnd
+            && self.lease_manager.get_checkpoint(&self.lease_key).await
+                != Some(Checkpoint::ShardEnd)
+        {
+            written = self
+                .lease_manager
+                .update_checkpoint(&self.lease_key, &checkpoint)
+                .await;
+        }
+        match written {
+            Ok(()) => {
+                state.last_checkpoint = checkpoint;
+                if let Some(coalescing) = state.coalescing.as_mut() {
+                    coalescing.has_pending = false;
+                    coalescing.last_write_time = Some(Instant::now());
+                    coalescing.retry_time = None;
+                    coalescing.backoff.reset();
+                }
+                Ok(())
+            }
+            Err(CheckpointError::LeaseLost) => {
+                state.status = CheckpointerStatus::LeaseLost;
+                Err(CheckpointError::LeaseLost)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        checkpoint::{FileCheckpointStore, LeaseCheckpointStore},
+        config::ShardFilter,
+        kinesis::StreamDescriptor,
+        lease::{broker::LeaseBroker, Lease},
+        util::fake_aws::FakeLeaseTable,
+    };
+
+    use super::*;
+
+    const LEASE_KEY: &str = "shardId-000000000001";
+    // Real sequence numbers are 56 digits, past what a u128 can hold
+    const FIRST: &str = "49590338271490256608559692538361571095921575989136588898";
+    const SECOND: &str = "49590338271490256608559692540925702759324208523137515618";
+    const THIRD: &str = "49590338271490256608559692542173821402117734662428786690";
+
+    fn sequence_number(sequence_number: &str) -> Checkpoint {
+        Checkpoint::SequenceNumber(sequence_number.to_string())
+    }
+
+    fn table_checkpoint(table: &FakeLeaseTable) -> Option<Checkpoint> {
+        table
+            .get(LEASE_KEY)
+            .and_then(|lease| lease.checkpoint)
+            .and_then(|value| Checkpoint::from_lease_value(&value))
+    }
+
+    /// A lease manager holding the shard's lease, which is also in the table.
+    async fn lease_manager(table: &FakeLeaseTable) -> Arc<LeaseManager> {
+        let lease = Lease {
+            lease_owner: Some("worker-1".to_string()),
+            ..Lease::new(
+                LEASE_KEY.to_string(),
+                Checkpoint::TrimHorizon.to_lease_value(),
+                Vec::new(),
+                "0".to_string(),
+                u128::MAX.to_string(),
+            )
+        };
+        table.put(&lease);
+        let lease_manager = Arc::new(LeaseManager::new(
+            LeaseBroker::new(table.client(), "leases".to_string()),
+            "worker-1".to_string(),
+            StreamDescriptor::from_name("test"),
+            ShardFilter::All,
+        ));
+        lease_manager.hold_lease(lease).await;
+        lease_manager
+    }
+
+    async fn checkpointer(
+        table: &FakeLeaseTable,
+        coalescing_window: Option<Duration>,
+    ) -> RecordProcessorCheckpointer {
+        let lease_manager = lease_manager(table).await;
+        RecordProcessorCheckpointer::new(
+            LEASE_KEY.to_string(),
+            Checkpoint::TrimHorizon,
+            coalescing_window,
+            Arc::new(LeaseCheckpointStore::new(lease_manager.clone())),
+            lease_manager,
+        )
+    }
+
+    #[tokio::test]
+    async fn rejects_checkpoints_past_the_last_delivered_record() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, None).await;
+        assert_eq!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::SequenceNumberOutOfRange {
+                requested: Checkpoint::TrimHorizon,
+                largest_permitted: None,
+            })
+        );
+
+        checkpointer.set_largest_permitted(SECOND).await;
+        assert_eq!(
+            checkpointer.checkpoint_at(THIRD).await,
+            Err(CheckpointError::SequenceNumberOutOfRange {
+                requested: sequence_number(THIRD),
+                largest_permitted: Some(sequence_number(SECOND)),
+            })
+        );
+        assert_eq!(
+            checkpointer.checkpoint_at("not a sequence number").await,
+            Err(CheckpointError::InvalidSequenceNumber(
+                "not a sequence number".to_string()
+            ))
+        );
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::TrimHorizon));
+
+        assert_eq!(checkpointer.checkpoint_at(FIRST).await, Ok(()));
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        assert_eq!(checkpointer.checkpoint().await, Ok(()));
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(SECOND)));
+        assert_eq!(
+            checkpointer.last_checkpoint().await,
+            sequence_number(SECOND)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_checkpoints_behind_the_current_one() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, None).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(SECOND).await.unwrap();
+
+        assert_eq!(
+            checkpointer.checkpoint_at(FIRST).await,
+            Err(CheckpointError::BehindCurrentCheckpoint {
+                requested: sequence_number(FIRST),
+                current: sequence_number(SECOND),
+            })
+        );
+        // Checkpointing where we already are is fine
+        assert_eq!(checkpointer.checkpoint_at(SECOND).await, Ok(()));
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(SECOND)));
+    }
+
+    #[tokio::test]
+    async fn rejects_checkpoints_once_the_lease_is_taken() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, None).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+
+        let mut taken = table.get(LEASE_KEY).unwrap();
+        taken.lease_owner = Some("worker-2".to_string());
+        taken.lease_counter += 1;
+        table.put(&taken);
+
+        assert_eq!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::LeaseLost)
+        );
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        // Nothing more is even attempted
+        assert_eq!(
+            checkpointer.checkpoint_at(FIRST).await,
+            Err(CheckpointError::LeaseLost)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_checkpoints_once_the_lease_is_released() {
+        let table = FakeLeaseTable::default();
+        let lease_manager = lease_manager(&table).await;
+        let checkpointer = RecordProcessorCheckpointer::new(
+            LEASE_KEY.to_string(),
+            Checkpoint::TrimHorizon,
+            None,
+            Arc::new(LeaseCheckpointStore::new(lease_manager.clone())),
+            lease_manager.clone(),
+        );
+        checkpointer.set_largest_permitted(FIRST).await;
+
+        lease_manager.release_lease(LEASE_KEY).await;
+        assert_eq!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::LeaseLost)
+        );
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::TrimHorizon));
+        // Anyone can take it now
+        assert_eq!(table.get(LEASE_KEY).unwrap().lease_owner, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_checkpoints_after_shutdown_or_lease_loss() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, None).await;
+        checkpointer.set_largest_permitted(FIRST).await;
+        checkpointer.mark_shut_down().await;
+        assert_eq!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::ShutDown)
+        );
+
+        let checkpointer = self::checkpointer(&table, None).await;
+        checkpointer.set_largest_permitted(FIRST).await;
+        checkpointer.mark_lease_lost().await;
+        assert_eq!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::LeaseLost)
+        );
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::TrimHorizon));
+    }
+
+    #[tokio::test]
+    async fn checkpoints_shard_end_only_once_the_shard_has_ended() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, None).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        assert_eq!(checkpointer.checkpoint().await, Ok(()));
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(SECOND)));
+
+        checkpointer.mark_shard_end().await;
+        assert_eq!(checkpointer.checkpoint().await, Ok(()));
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::ShardEnd));
+        assert_eq!(checkpointer.last_checkpoint().await, Checkpoint::ShardEnd);
+        assert_eq!(
+            checkpointer.checkpoint_at(SECOND).await,
+            Err(CheckpointError::BehindCurrentCheckpoint {
+                requested: sequence_number(SECOND),
+                current: Checkpoint::ShardEnd,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn records_shard_end_on_the_lease_with_another_store() {
+        let table = FakeLeaseTable::default();
+        let lease_manager = lease_manager(&table).await;
+        let directory = std::env::temp_dir().join(format!(
+            "kinesis_kcl-checkpointer-{:016x}",
+            rand::random::<u64>()
+        ));
+        let store = Arc::new(FileCheckpointStore::new(&directory));
+        let checkpointer = RecordProcessorCheckpointer::new(
+            LEASE_KEY.to_string(),
+            Checkpoint::TrimHorizon,
+            None,
+            store.clone(),
+            lease_manager,
+        );
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint().await.unwrap();
+        // Only the end of the shard goes to the lease
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::TrimHorizon));
+
+        checkpointer.mark_shard_end().await;
+        checkpointer.checkpoint().await.unwrap();
+        assert_eq!(
+            store.get_checkpoint(LEASE_KEY).await,
+            Ok(Some(Checkpoint::ShardEnd))
+        );
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::ShardEnd));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn holds_back_checkpoints_within_the_coalescing_window() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, Some(Duration::from_secs(3600))).await;
+        checkpointer.set_largest_permitted(THIRD).await;
+        assert_eq!(checkpointer.flush_deadline().await, None);
+
+        // The first write goes straight through and opens the window
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        checkpointer.checkpoint_at(SECOND).await.unwrap();
+        checkpointer.checkpoint_at(THIRD).await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        assert_eq!(checkpointer.last_checkpoint().await, sequence_number(THIRD));
+        let deadline = checkpointer.flush_deadline().await.unwrap();
+        assert!(deadline > Instant::now() + Duration::from_secs(3500));
+
+        checkpointer.flush_if_due().await;
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        checkpointer.flush().await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(THIRD)));
+        assert_eq!(checkpointer.flush_deadline().await, None);
+    }
+
+    #[tokio::test]
+    async fn writes_held_back_checkpoints_once_the_window_expires() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, Some(Duration::from_millis(200))).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+        checkpointer.checkpoint_at(SECOND).await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(checkpointer.flush_deadline().await.unwrap() <= Instant::now());
+        checkpointer.flush_if_due().await;
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(SECOND)));
+    }
+
+    #[tokio::test]
+    async fn writes_shard_end_and_final_checkpoints_straight_away() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, Some(Duration::from_secs(3600))).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+
+        checkpointer.mark_shard_end().await;
+        checkpointer.checkpoint().await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(Checkpoint::ShardEnd));
+
+        let checkpointer = self::checkpointer(&table, Some(Duration::from_secs(3600))).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+        checkpointer.checkpoint_at(SECOND).await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        // The held back checkpoint is saved, and later ones aren't held back
+        checkpointer.stop_coalescing().await.unwrap();
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(SECOND)));
+    }
+
+    #[tokio::test]
+    async fn backs_off_after_a_held_back_checkpoint_fails_to_save() {
+        let table = FakeLeaseTable::default();
+        let lease_manager = lease_manager(&table).await;
+        let directory = std::env::temp_dir().join(format!(
+            "kinesis_kcl-checkpointer-{:016x}",
+            rand::random::<u64>()
+        ));
+        let checkpointer = RecordProcessorCheckpointer::new(
+            LEASE_KEY.to_string(),
+            Checkpoint::TrimHorizon,
+            Some(Duration::from_millis(200)),
+            Arc::new(FileCheckpointStore::new(&directory)),
+            lease_manager,
+        );
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+        checkpointer.checkpoint_at(SECOND).await.unwrap();
+        // A file where the directory should be makes every later write fail
+        std::fs::remove_dir_all(&directory).unwrap();
+        std::fs::write(&directory, "").unwrap();
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        checkpointer.flush_if_due().await;
+        // The worker isn't woken straight away to try again
+        assert!(checkpointer.flush_deadline().await.unwrap() > Instant::now());
+        assert!(matches!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::Store(_))
+        ));
+        let _ = std::fs::remove_file(directory);
+    }
+
+    #[tokio::test]
+    async fn drops_held_back_checkpoints_when_the_lease_is_lost() {
+        let table = FakeLeaseTable::default();
+        let checkpointer = checkpointer(&table, Some(Duration::from_secs(3600))).await;
+        checkpointer.set_largest_permitted(SECOND).await;
+        checkpointer.checkpoint_at(FIRST).await.unwrap();
+        checkpointer.checkpoint_at(SECOND).await.unwrap();
+
+        checkpointer.mark_lease_lost().await;
+        assert_eq!(table_checkpoint(&table), Some(sequence_number(FIRST)));
+        assert_eq!(checkpointer.flush_deadline().await, None);
+        assert_eq!(
+            checkpointer.checkpoint().await,
+            Err(CheckpointError::LeaseLost)
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::{cmp::Ordering, fmt};
+
+mod checkpointer;
+mod policy;
+mod rewind;
+mod store;
+
+pub use checkpointer::RecordProcessorCheckpointer;
+pub(crate) use policy::AutoCheckpointTracker;
+pub use policy::{AutoCheckpointConfig, CheckpointMode};
+pub use rewind::{CheckpointAdmin, CheckpointAdminError, RewindTarget};
+pub(crate) use store::{load_checkpoint, LeaseCheckpointStore};
+pub use store::{CheckpointStore, FileCheckpointStore};
+
+static TRIM_HORIZON: &str = "TRIM_HORIZON";
+static LATEST: &str = "LATEST";
+static SHARD_END: &str = "SHARD_END";
+static AT_TIMESTAMP_PREFIX: &str = "AT_TIMESTAMP:";
+
+/// Sequence numbers are decimal strings, usually longer than any integer type can hold.
+pub(crate) fn is_sequence_number(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Compares sequence numbers by value without parsing them: the one with more significant digits
+/// is larger, and ones of the same length compare as strings.
+pub(crate) fn compare_sequence_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// A position in a shard that a lease can be checkpointed at.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Checkpoint {
+    TrimHorizon,
+    Latest,
+    /// Milliseconds since the epoch.
+    AtTimestamp(u64),
+    SequenceNumber(String),
+    ShardEnd,
+}
+
+impl Checkpoint {
+    pub(crate) fn from_lease_value(value: &str) -> Option<Self> {
+        if value == TRIM_HORIZON {
+            Some(Checkpoint::TrimHorizon)
+        } else if value == LATEST {
+            Some(Checkpoint::Latest)
+        } else if value == SHARD_END {
+            Some(Checkpoint::ShardEnd)
+        } else if let Some(millis) = value.strip_prefix(AT_TIMESTAMP_PREFIX) {
+            millis.parse().ok().map(Checkpoint::AtTimestamp)
+        } else if is_sequence_number(value) {
+            Some(Checkpoint::SequenceNumber(value.to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn to_lease_value(&self) -> String {
+        match self {
+            Checkpoint::TrimHorizon => TRIM_HORIZON.to_string(),
+            Checkpoint::Latest => LATEST.to_string(),
+            Checkpoint::AtTimestamp(millis) => format!("{}{}", AT_TIMESTAMP_PREFIX, millis),
+            Checkpoint::SequenceNumber(sequence_number) => sequence_number.clone(),
+            Checkpoint::ShardEnd => SHARD_END.to_string(),
+        }
+    }
+}
+
+impl PartialOrd for Checkpoint {
+    /// `LATEST` and timestamps have no fixed place in a shard, so they are only comparable to
+    /// themselves.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Checkpoint::Latest, _)
+            | (_, Checkpoint::Latest)
+            | (Checkpoint::AtTimestamp(_), _)
+            | (_, Checkpoint::AtTimestamp(_)) => {
+                if self == other {
+                    Some(Ordering::Equal)
+                } else {
+                    None
+                }
+            }
+            (Checkpoint::TrimHorizon, Checkpoint::TrimHorizon) => Some(Ordering::Equal),
+            (Checkpoint::TrimHorizon, _) => Some(Ordering::Less),
+            (_, Checkpoint::TrimHorizon) => Some(Ordering::Greater),
+            (Checkpoint::ShardEnd, Checkpoint::ShardEnd) => Some(Ordering::Equal),
+            (Checkpoint::ShardEnd, _) => Some(Ordering::Greater),
+            (_, Checkpoint::ShardEnd) => Some(Ordering::Less),
+            (Checkpoint::SequenceNumber(a), Checkpoint::SequenceNumber(b)) => {
+                Some(compare_sequence_numbers(a, b))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_lease_value())
+    }
+}
+
+/// The reasons a checkpoint request can be rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckpointError {
+    /// The sequence number could not be parsed.
+    InvalidSequenceNumber(String),
+    /// The sequence number is past the last record delivered to the processor.
+    SequenceNumberOutOfRange {
+        requested: Checkpoint,
+        largest_permitted: Option<Checkpoint>,
+    },
+    /// The sequence number is behind the shard's current checkpoint.
+    BehindCurrentCheckpoint {
+        requested: Checkpoint,
+        current: Checkpoint,
+    },
+    /// This worker no longer holds the lease for the shard.
+    LeaseLost,
+    /// The shard consumer has already shut down.
+    ShutDown,
+    /// The checkpoint could not be written.
+    Store(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::InvalidSequenceNumber(value) => {
+                write!(f, "'{}' is not a valid sequence number", value)
+            }
+            CheckpointError::SequenceNumberOutOfRange {
+                requested,
+                largest_permitted: Some(largest),
+            } => write!(
+                f,
+                "Checkpoint {} is past the last delivered record {}",
+                requested, largest
+            ),
+            CheckpointError::SequenceNumberOutOfRange {
+                requested,
+                largest_permitted: None,
+            } => write!(
+                f,
+                "Checkpoint {} requested before any records were delivered",
+                requested
+            ),
+            CheckpointError::BehindCurrentCheckpoint { requested, current } => write!(
+                f,
+                "Checkpoint {} is behind the current checkpoint {}",
+                requested, current
+            ),
+            CheckpointError::LeaseLost => f.write_str("The lease for this shard was lost"),
+            CheckpointError::ShutDown => f.write_str("The shard consumer has shut down"),
+            CheckpointError::Store(message) => {
+                write!(f, "Failed to save checkpoint: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Real sequence numbers are 56 digits, past what a u128 can hold
+    const EARLIER: &str = "49590338271490256608559692538361571095921575989136588898";
+    const LATER: &str = "49590338271490256608559692540925702759324208523137515618";
+
+    #[test]
+    fn parses_lease_values() {
+        assert_eq!(
+            Checkpoint::from_lease_value("TRIM_HORIZON"),
+            Some(Checkpoint::TrimHorizon)
+        );
+        assert_eq!(
+            Checkpoint::from_lease_value("LATEST"),
+            Some(Checkpoint::Latest)
+        );
+        assert_eq!(
+            Checkpoint::from_lease_value("SHARD_END"),
+            Some(Checkpoint::ShardEnd)
+        );
+        assert_eq!(
+            Checkpoint::from_lease_value("AT_TIMESTAMP:1600000000000"),
+            Some(Checkpoint::AtTimestamp(1_600_000_000_000))
+        );
+        assert_eq!(
+            Checkpoint::from_lease_value(EARLIER),
+            Some(Checkpoint::SequenceNumber(EARLIER.to_string()))
+        );
+        assert_eq!(Checkpoint::from_lease_value(""), None);
+        assert_eq!(Checkpoint::from_lease_value("AT_TIMESTAMP:soon"), None);
+        assert_eq!(
+            Checkpoint::from_lease_value(
+                "4959033827149025660855969253836157109592157598913658889a"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn lease_values_round_trip() {
+        for checkpoint in [
+            Checkpoint::TrimHorizon,
+            Checkpoint::Latest,
+            Checkpoint::AtTimestamp(1_600_000_000_000),
+            Checkpoint::SequenceNumber(LATER.to_string()),
+            Checkpoint::ShardEnd,
+        ] {
+            assert_eq!(
+                Checkpoint::from_lease_value(&checkpoint.to_lease_value()),
+                Some(checkpoint)
+            );
+        }
+    }
+
+    #[test]
+    fn orders_positions_in_a_shard() {
+        let earlier = Checkpoint::SequenceNumber(EARLIER.to_string());
+        let later = Checkpoint::SequenceNumber(LATER.to_string());
+        assert!(earlier < later);
+        assert!(Checkpoint::TrimHorizon < earlier);
+        assert!(later < Checkpoint::ShardEnd);
+        assert!(Checkpoint::TrimHorizon < Checkpoint::ShardEnd);
+        assert_eq!(later.partial_cmp(&later), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn latest_and_timestamps_only_compare_to_themselves() {
+        let sequence_number = Checkpoint::SequenceNumber(EARLIER.to_string());
+        assert_eq!(Checkpoint::Latest.partial_cmp(&sequence_number), None);
+        assert_eq!(
+            Checkpoint::AtTimestamp(1).partial_cmp(&Checkpoint::TrimHorizon),
+            None
+        );
+        assert_eq!(
+            Checkpoint::AtTimestamp(1).partial_cmp(&Checkpoint::AtTimestamp(2)),
+            None
+        );
+        assert_eq!(
+            Checkpoint::Latest.partial_cmp(&Checkpoint::Latest),
+            Some(Ordering::Equal)
+        );
+    }
+
+    #[test]
+    fn compares_sequence_numbers_by_value() {
+        assert_eq!(compare_sequence_numbers(EARLIER, LATER), Ordering::Less);
+        assert_eq!(compare_sequence_numbers(LATER, EARLIER), Ordering::Greater);
+        assert_eq!(compare_sequence_numbers(LATER, LATER), Ordering::Equal);
+        // More digits is larger, whatever the leading digit
+        assert_eq!(compare_sequence_numbers("9", "10"), Ordering::Less);
+        assert_eq!(compare_sequence_numbers(EARLIER, "9"), Ordering::Greater);
+        // Leading zeros don't count
+        assert_eq!(compare_sequence_numbers("00042", "42"), Ordering::Equal);
+        assert_eq!(compare_sequence_numbers("0009", "10"), Ordering::Less);
+        assert_eq!(compare_sequence_numbers("0", "000"), Ordering::Equal);
+    }
+}
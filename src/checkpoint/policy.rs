@@ -0,0 +1,163 @@
+use std::time::{Duration, Instant};
+
+use crate::util::retry::ExponentialBackoff;
+
+/// How a `ShardWorker` decides when to checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CheckpointMode {
+    /// The processor checkpoints for itself through the checkpointer it is handed.
+    #[default]
+    Manual,
+    /// The worker checkpoints after successful batches, and at shard end and shutdown.
+    Automatic(AutoCheckpointConfig),
+}
+
+/// Throttles automatic checkpoints: one is written once either limit is reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoCheckpointConfig {
+    pub max_records: usize,
+    pub max_interval: Duration,
+}
+
+impl Default for AutoCheckpointConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 10_000,
+            max_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+pub(crate) struct AutoCheckpointTracker {
+    config: AutoCheckpointConfig,
+    records_since_checkpoint: usize,
+    last_checkpoint_time: Instant,
+    /// Set after a failed checkpoint, which isn't tried again before then.
+    retry_time: Option<Instant>,
+    backoff: ExponentialBackoff,
+}
+
+impl AutoCheckpointTracker {
+    pub(crate) fn new(config: AutoCheckpointConfig) -> Self {
+        Self {
+            config,
+            records_since_checkpoint: 0,
+            last_checkpoint_time: Instant::now(),
+            retry_time: None,
+            backoff: ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(60)),
+        }
+    }
+
+    /// Notes a successfully processed batch and returns whether a checkpoint is due.
+    pub(crate) fn record_batch(&mut self, record_count: usize) -> bool {
+        self.records_since_checkpoint += record_count;
+        self.has_pending()
+            && !matches!(self.retry_time, Some(time) if Instant::now() < time)
+            && (self.records_since_checkpoint >= self.config.max_records
+                || self.last_checkpoint_time.elapsed() >= self.config.max_interval)
+    }
+
+    /// When the records processed since the last checkpoint are due to be checkpointed, however
+    /// quiet the shard gets in the meantime.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        if self.has_pending() {
+            let deadline = self.last_checkpoint_time + self.config.max_interval;
+            Some(self.retry_time.map_or(deadline, |time| time.max(deadline)))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        self.records_since_checkpoint > 0
+    }
+
+    pub(crate) fn checkpointed(&mut self) {
+        self.records_since_checkpoint = 0;
+        self.last_checkpoint_time = Instant::now();
+        self.retry_time = None;
+        self.backoff.reset();
+    }
+
+    /// Holds the next attempt back, so a store that keeps failing isn't called after every batch.
+    pub(crate) fn checkpoint_failed(&mut self) {
+        self.retry_time = Some(Instant::now() + self.backoff.next_delay());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(max_records: usize, max_interval: Duration) -> AutoCheckpointTracker {
+        AutoCheckpointTracker::new(AutoCheckpointConfig {
+            max_records,
+            max_interval,
+        })
+    }
+
+    #[test]
+    fn checkpoints_once_enough_records_are_processed() {
+        let mut tracker = tracker(10, Duration::from_secs(60));
+        assert!(!tracker.record_batch(4));
+        assert!(!tracker.record_batch(5));
+        assert!(tracker.record_batch(1));
+
+        tracker.checkpointed();
+        assert!(!tracker.has_pending());
+        assert!(!tracker.record_batch(9));
+    }
+
+    #[test]
+    fn checkpoints_a_quiet_shard_once_enough_time_has_passed() {
+        let mut tracker = tracker(10, Duration::from_millis(20));
+        assert!(!tracker.record_batch(1));
+        std::thread::sleep(Duration::from_millis(30));
+        // Idle calls and timers report no records, but still find the checkpoint due
+        assert!(tracker.record_batch(0));
+    }
+
+    #[test]
+    fn nothing_is_due_without_new_records() {
+        let mut tracker = tracker(10, Duration::ZERO);
+        assert!(!tracker.record_batch(0));
+        assert_eq!(tracker.deadline(), None);
+
+        assert!(tracker.record_batch(1));
+        tracker.checkpointed();
+        assert!(!tracker.record_batch(0));
+    }
+
+    #[test]
+    fn deadline_follows_the_last_checkpoint() {
+        let mut tracker = tracker(10, Duration::from_secs(60));
+        assert_eq!(tracker.deadline(), None);
+
+        let before = Instant::now();
+        tracker.record_batch(1);
+        tracker.checkpointed();
+        tracker.record_batch(1);
+        let deadline = tracker.deadline().expect("Records are pending");
+        assert!(deadline >= before + Duration::from_secs(60));
+        assert!(deadline <= Instant::now() + Duration::from_secs(60));
+
+        tracker.checkpointed();
+        assert_eq!(tracker.deadline(), None);
+    }
+
+    #[test]
+    fn backs_off_after_a_failed_checkpoint() {
+        let mut tracker = tracker(1, Duration::ZERO);
+        assert!(tracker.record_batch(1));
+
+        let before = Instant::now();
+        tracker.checkpoint_failed();
+        assert!(!tracker.record_batch(1));
+        let deadline = tracker.deadline().expect("Records are pending");
+        assert!(deadline > before);
+        assert!(deadline <= Instant::now() + Duration::from_secs(1));
+
+        tracker.checkpointed();
+        assert!(tracker.record_batch(1));
+    }
+}
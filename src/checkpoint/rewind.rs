@@ -0,0 +1,608 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dynomite::dynamodb::DynamoDbClient;
+use futures_retry::FutureRetry;
+
+use crate::{
+    kinesis::StreamDescriptor,
+    lease::{broker::LeaseBroker, Lease, SharedLease},
+    status::StreamRecreation,
+    util::{exception::Exception, retry::FixedCountWithDelayStrategy},
+};
+
+use super::{is_sequence_number, Checkpoint, CheckpointStore};
+
+/// Where `CheckpointAdmin::rewind` moves an application's checkpoints to.
+#[derive(Debug, Clone)]
+pub enum RewindTarget {
+    TrimHorizon,
+    AtTimestamp(SystemTime),
+    /// Sequence numbers keyed by shard ID. Shards that aren't listed keep their checkpoint.
+    SequenceNumbers(HashMap<String, String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckpointAdminError {
+    /// Workers still hold these leases, or took them while the operation was running.
+    LeasesHeld(Vec<String>),
+    InvalidTarget(String),
+    Store(String),
+}
+
+impl fmt::Display for CheckpointAdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointAdminError::LeasesHeld(lease_keys) => {
+                write!(f, "Leases are held by workers: {}", lease_keys.join(", "))
+            }
+            CheckpointAdminError::InvalidTarget(message) => {
+                write!(f, "Invalid rewind target: {}", message)
+            }
+            CheckpointAdminError::Store(message) => {
+                write!(f, "Failed to update checkpoints: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointAdminError {}
+
+impl From<(Exception, usize)> for CheckpointAdminError {
+    fn from((ex, _): (Exception, usize)) -> Self {
+        match ex {
+            Exception::Retryable(msg) | Exception::NonRetryable(msg) => {
+                CheckpointAdminError::Store(msg)
+            }
+        }
+    }
+}
+
+/// Offline operations on an application's checkpoints, for replaying a stream after a bad deploy
+/// or bootstrapping a new application from another one's progress.
+///
+/// These only run while no workers hold the application's leases. With `force`, held leases are
+/// expired first, and their holders give them up at their next renewal.
+///
+/// Applications keeping checkpoints in a `CheckpointStore` of their own have to hand it over with
+/// `with_checkpoint_store`, or workers carry on from the checkpoints in it.
+///
+/// Only the stream's own leases are touched, so lease tables shared by several streams are fine.
+pub struct CheckpointAdmin {
+    lease_broker: LeaseBroker,
+    stream: StreamDescriptor,
+    checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+}
+
+fn retry_strategy() -> FixedCountWithDelayStrategy {
+    FixedCountWithDelayStrategy::new(3, Duration::from_millis(100))
+}
+
+impl CheckpointAdmin {
+    pub fn new(
+        dynamo_client: DynamoDbClient,
+        lease_table: impl Into<String>,
+        stream: StreamDescriptor,
+    ) -> Self {
+        Self {
+            lease_broker: LeaseBroker::new(dynamo_client, lease_table.into()),
+            stream,
+            checkpoint_store: None,
+        }
+    }
+
+    /// Where the application keeps its checkpoints, when that isn't the lease table. Checkpoints
+    /// are read from it first, and written to both it and the leases.
+    pub fn with_checkpoint_store(mut self, checkpoint_store: Arc<dyn CheckpointStore>) -> Self {
+        self.checkpoint_store = Some(checkpoint_store);
+        self
+    }
+
+    /// Moves every lease's checkpoint to the target, returning how many leases were updated.
+    pub async fn rewind(
+        &self,
+        target: &RewindTarget,
+        force: bool,
+    ) -> Result<usize, CheckpointAdminError> {
+        let target_checkpoint = match target {
+            RewindTarget::TrimHorizon => Some(Checkpoint::TrimHorizon),
+            RewindTarget::AtTimestamp(time) => {
+                let millis = time
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .and_then(|since_epoch| since_epoch.as_millis().try_into().ok())
+                    .ok_or_else(|| {
+                        CheckpointAdminError::InvalidTarget("Timestamp is out of range".to_string())
+                    })?;
+                Some(Checkpoint::AtTimestamp(millis))
+            }
+            RewindTarget::SequenceNumbers(sequence_numbers) => {
+                for sequence_number in sequence_numbers.values() {
+                    if !is_sequence_number(sequence_number) {
+                        return Err(CheckpointAdminError::InvalidTarget(format!(
+                            "'{}' is not a valid sequence number",
+                            sequence_number
+                        )));
+                    }
+                }
+                None
+            }
+        };
+
+        let leases = self.list_unheld_leases(force).await?;
+        let mut updates = Vec::new();
+        for lease in leases {
+            let shard_id = lease.read().await.shard_id().to_string();
+            let checkpoint = match (&target_checkpoint, target) {
+                (Some(checkpoint), _) => checkpoint.clone(),
+                (None, RewindTarget::SequenceNumbers(sequence_numbers)) => {
+                    match sequence_numbers.get(&shard_id) {
+                        Some(sequence_number) => {
+                            Checkpoint::SequenceNumber(sequence_number.clone())
+                        }
+                        None => continue,
+                    }
+                }
+                (None, _) => continue,
+            };
+            updates.push((lease, checkpoint));
+        }
+
+        self.reset_checkpoints(updates).await
+    }
+
+    /// Copies every checkpoint from another application, creating any leases this application
+    /// doesn't have yet. Returns how many leases were updated or created.
+    pub async fn copy_from(
+        &self,
+        source: &CheckpointAdmin,
+        force: bool,
+    ) -> Result<usize, CheckpointAdminError> {
+        let mut source_checkpoints = HashMap::new();
+        for source_lease in source.list_leases().await? {
+            let lease = source_lease.read().await.clone();
+            if let Some(checkpoint) = source.checkpoint_of(&lease).await? {
+                source_checkpoints.insert(lease.lease_key.clone(), (lease, checkpoint));
+            }
+        }
+
+        let mut updates = Vec::new();
+        for lease in self.list_unheld_leases(force).await? {
+            let lease_key = lease.read().await.lease_key.clone();
+            if let Some((_, checkpoint)) = source_checkpoints.remove(&lease_key) {
+                updates.push((lease, checkpoint));
+            }
+        }
+        let mut updated = self.reset_checkpoints(updates).await?;
+
+        for (_, (source_lease, checkpoint)) in source_checkpoints {
+            let new_lease = &Lease {
+                lease_owner: None,
+                last_renewal_nanos: 0,
+                lease_counter: 0,
+                checkpoint: Some(checkpoint.to_lease_value()),
+                ..source_lease
+            };
+            let (created, _) = FutureRetry::new(
+                move || self.lease_broker.create_lease_if_not_exists(new_lease),
+                retry_strategy(),
+            )
+            .await?;
+            if created {
+                self.store_checkpoint(&new_lease.lease_key, &checkpoint)
+                    .await?;
+                updated += 1;
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Deletes the leases an earlier stream of the same name left behind, as reported by
+    /// `WorkerScheduler::stream_recreation`, so the next shard sync creates the new stream's leases
+    /// from the initial position. Returns how many leases were deleted.
+    ///
+    /// With `force`, held leases are deleted too, and their holders find out at their next renewal.
+    pub async fn delete_stale_leases(
+        &self,
+        recreation: &StreamRecreation,
+        force: bool,
+    ) -> Result<usize, CheckpointAdminError> {
+        if !force {
+            let mut held = Vec::new();
+            for lease_key in recreation.stale_leases.iter() {
+                let (lease, _) = FutureRetry::new(
+                    move || self.lease_broker.get_lease(lease_key),
+                    retry_strategy(),
+                )
+                .await?;
+                if lease.and_then(|lease| lease.lease_owner).is_some() {
+                    held.push(lease_key.clone());
+                }
+            }
+            if !held.is_empty() {
+                return Err(CheckpointAdminError::LeasesHeld(held));
+            }
+        }
+
+        for lease_key in recreation.stale_leases.iter() {
+            FutureRetry::new(
+                move || self.lease_broker.delete_lease(lease_key, None),
+                retry_strategy(),
+            )
+            .await?;
+            // Otherwise the new stream's shard would start from the old one's checkpoint
+            if let Some(checkpoint_store) = &self.checkpoint_store {
+                checkpoint_store
+                    .delete_checkpoint(lease_key)
+                    .await
+                    .map_err(|err| CheckpointAdminError::Store(err.to_string()))?;
+            }
+        }
+
+        Ok(recreation.stale_leases.len())
+    }
+
+    /// The stream's leases, leaving out those of any other streams sharing the table.
+    async fn list_leases(&self) -> Result<Vec<SharedLease>, CheckpointAdminError> {
+        let (leases, _) = FutureRetry::new(
+            move || self.lease_broker.list_all_leases(),
+            retry_strategy(),
+        )
+        .await?;
+        let mut own_leases = Vec::new();
+        for lease in leases {
+            if self.stream.owns_lease_key(&lease.read().await.lease_key) {
+                own_leases.push(lease);
+            }
+        }
+        Ok(own_leases)
+    }
+
+    /// Where the shard's worker would start from: the store's checkpoint, or the lease's for
+    /// shards the store hasn't seen.
+    async fn checkpoint_of(
+        &self,
+        lease: &Lease,
+    ) -> Result<Option<Checkpoint>, CheckpointAdminError> {
+        if let Some(checkpoint_store) = &self.checkpoint_store {
+            let stored = checkpoint_store
+                .get_checkpoint(&lease.lease_key)
+                .await
+                .map_err(|err| CheckpointAdminError::Store(err.to_string()))?;
+            if stored.is_some() {
+                return Ok(stored);
+            }
+        }
+        Ok(lease
+            .checkpoint
+            .as_ref()
+            .and_then(|value| Checkpoint::from_lease_value(value)))
+    }
+
+    async fn store_checkpoint(
+        &self,
+        lease_key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointAdminError> {
+        match &self.checkpoint_store {
+            Some(checkpoint_store) => checkpoint_store
+                .set_checkpoint(lease_key, checkpoint)
+                .await
+                .map_err(|err| CheckpointAdminError::Store(err.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    async fn list_unheld_leases(
+        &self,
+        force: bool,
+    ) -> Result<Vec<SharedLease>, CheckpointAdminError> {
+        let leases = self.list_leases().await?;
+
+        let mut held = Vec::new();
+        for lease in leases.iter() {
+            let lease_guard = lease.read().await;
+            if lease_guard.lease_owner.is_some() {
+                held.push((lease.clone(), lease_guard.lease_key.clone()));
+            }
+        }
+        if !held.is_empty() && !force {
+            return Err(CheckpointAdminError::LeasesHeld(
+                held.into_iter().map(|(_, lease_key)| lease_key).collect(),
+            ));
+        }
+
+        let mut still_held = Vec::new();
+        for (lease, lease_key) in held {
+            let (evicted, _) = FutureRetry::new(
+                move || self.lease_broker.evict_lease(lease.clone()),
+                retry_strategy(),
+            )
+            .await?;
+            if !evicted {
+                still_held.push(lease_key);
+            }
+        }
+        if !still_held.is_empty() {
+            return Err(CheckpointAdminError::LeasesHeld(still_held));
+        }
+
+        Ok(leases)
+    }
+
+    async fn reset_checkpoints(
+        &self,
+        updates: Vec<(SharedLease, Checkpoint)>,
+    ) -> Result<usize, CheckpointAdminError> {
+        let mut updated = 0;
+        let mut taken = Vec::new();
+        for (lease, checkpoint) in updates.iter() {
+            let value = &checkpoint.to_lease_value();
+            let (reset, _) = FutureRetry::new(
+                move || self.lease_broker.reset_checkpoint(lease.clone(), value),
+                retry_strategy(),
+            )
+            .await?;
+            if reset {
+                let lease_key = lease.read().await.lease_key.clone();
+                self.store_checkpoint(&lease_key, checkpoint).await?;
+                updated += 1;
+            } else {
+                taken.push(lease.read().await.lease_key.clone());
+            }
+        }
+
+        if taken.is_empty() {
+            Ok(updated)
+        } else {
+            Err(CheckpointAdminError::LeasesHeld(taken))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        checkpoint::FileCheckpointStore,
+        util::fake_aws::{self, FakeLeaseTable},
+    };
+
+    use super::*;
+
+    const STREAM_ARN: &str = "arn:aws:kinesis:us-east-1:123456789012:stream/orders";
+    const OTHER_STREAM_ARN: &str = "arn:aws:kinesis:us-east-1:123456789012:stream/payments";
+    const EARLIER: &str = "49590338271490256608559692538361571095921575989136588898";
+    const LATER: &str = "49590338271490256608559692540925702759324208523137515618";
+
+    fn stream() -> StreamDescriptor {
+        StreamDescriptor::from_arn(STREAM_ARN).unwrap()
+    }
+
+    fn lease_key(shard_id: &str) -> String {
+        stream().lease_key(shard_id)
+    }
+
+    fn lease(lease_key: &str, checkpoint: Checkpoint, owner: Option<&str>) -> Lease {
+        Lease {
+            lease_owner: owner.map(str::to_string),
+            ..Lease::new(
+                lease_key.to_string(),
+                checkpoint.to_lease_value(),
+                Vec::new(),
+                "0".to_string(),
+                u128::MAX.to_string(),
+            )
+        }
+    }
+
+    fn checkpoint(table: &FakeLeaseTable, lease_key: &str) -> Option<Checkpoint> {
+        table
+            .get(lease_key)
+            .and_then(|lease| lease.checkpoint)
+            .and_then(|value| Checkpoint::from_lease_value(&value))
+    }
+
+    fn admin(table: &FakeLeaseTable) -> CheckpointAdmin {
+        CheckpointAdmin::new(table.client(), "leases", stream())
+    }
+
+    fn sequence_number(sequence_number: &str) -> Checkpoint {
+        Checkpoint::SequenceNumber(sequence_number.to_string())
+    }
+
+    #[tokio::test]
+    async fn rewinds_only_the_streams_own_leases() {
+        let table = FakeLeaseTable::default();
+        let other_lease_key = format!("{}:shardId-000000000001", OTHER_STREAM_ARN);
+        for lease_key in [
+            lease_key("shardId-000000000001"),
+            lease_key("shardId-000000000002"),
+            other_lease_key.clone(),
+        ] {
+            table.put(&lease(&lease_key, sequence_number(LATER), None));
+        }
+
+        assert_eq!(
+            admin(&table)
+                .rewind(&RewindTarget::TrimHorizon, false)
+                .await,
+            Ok(2)
+        );
+        assert_eq!(
+            checkpoint(&table, &lease_key("shardId-000000000001")),
+            Some(Checkpoint::TrimHorizon)
+        );
+        assert_eq!(
+            checkpoint(&table, &lease_key("shardId-000000000002")),
+            Some(Checkpoint::TrimHorizon)
+        );
+        assert_eq!(
+            checkpoint(&table, &other_lease_key),
+            Some(sequence_number(LATER))
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_held_leases_alone_unless_forced() {
+        let table = FakeLeaseTable::default();
+        let held = lease_key("shardId-000000000001");
+        table.put(&lease(&held, sequence_number(LATER), Some("worker-1")));
+        // Another stream's held leases don't get in the way
+        let other_lease_key = format!("{}:shardId-000000000001", OTHER_STREAM_ARN);
+        table.put(&lease(
+            &other_lease_key,
+            sequence_number(LATER),
+            Some("worker-2"),
+        ));
+
+        let admin = admin(&table);
+        assert_eq!(
+            admin.rewind(&RewindTarget::TrimHorizon, false).await,
+            Err(CheckpointAdminError::LeasesHeld(vec![held.clone()]))
+        );
+        assert_eq!(checkpoint(&table, &held), Some(sequence_number(LATER)));
+
+        assert_eq!(admin.rewind(&RewindTarget::TrimHorizon, true).await, Ok(1));
+        let rewound = table.get(&held).unwrap();
+        assert_eq!(rewound.lease_owner, None);
+        assert_eq!(checkpoint(&table, &held), Some(Checkpoint::TrimHorizon));
+        assert_eq!(
+            table.get(&other_lease_key).unwrap().lease_owner,
+            Some("worker-2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rewinds_to_timestamps_and_sequence_numbers() {
+        let table = FakeLeaseTable::default();
+        for shard_id in ["shardId-000000000001", "shardId-000000000002"] {
+            table.put(&lease(&lease_key(shard_id), sequence_number(LATER), None));
+        }
+        let admin = admin(&table);
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_000);
+        assert_eq!(
+            admin.rewind(&RewindTarget::AtTimestamp(time), false).await,
+            Ok(2)
+        );
+        assert_eq!(
+            checkpoint(&table, &lease_key("shardId-000000000002")),
+            Some(Checkpoint::AtTimestamp(1_600_000_000_000))
+        );
+
+        // Only the listed shards move
+        let target = RewindTarget::SequenceNumbers(HashMap::from([(
+            "shardId-000000000001".to_string(),
+            EARLIER.to_string(),
+        )]));
+        assert_eq!(admin.rewind(&target, false).await, Ok(1));
+        assert_eq!(
+            checkpoint(&table, &lease_key("shardId-000000000001")),
+            Some(sequence_number(EARLIER))
+        );
+        assert_eq!(
+            checkpoint(&table, &lease_key("shardId-000000000002")),
+            Some(Checkpoint::AtTimestamp(1_600_000_000_000))
+        );
+
+        let invalid = RewindTarget::SequenceNumbers(HashMap::from([(
+            "shardId-000000000001".to_string(),
+            "soon".to_string(),
+        )]));
+        assert!(matches!(
+            admin.rewind(&invalid, false).await,
+            Err(CheckpointAdminError::InvalidTarget(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rewinds_checkpoints_in_the_applications_store() {
+        let table = FakeLeaseTable::default();
+        let lease_key = lease_key("shardId-000000000001");
+        table.put(&lease(&lease_key, Checkpoint::TrimHorizon, None));
+        let directory = fake_aws::temp_directory();
+        let store = Arc::new(FileCheckpointStore::new(&directory));
+        store
+            .set_checkpoint(&lease_key, &sequence_number(LATER))
+            .await
+            .unwrap();
+
+        let admin = admin(&table).with_checkpoint_store(store.clone());
+        let target = RewindTarget::SequenceNumbers(HashMap::from([(
+            "shardId-000000000001".to_string(),
+            EARLIER.to_string(),
+        )]));
+        assert_eq!(admin.rewind(&target, false).await, Ok(1));
+        assert_eq!(
+            store.get_checkpoint(&lease_key).await,
+            Ok(Some(sequence_number(EARLIER)))
+        );
+        assert_eq!(
+            checkpoint(&table, &lease_key),
+            Some(sequence_number(EARLIER))
+        );
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn copies_checkpoints_from_another_application() {
+        let source_table = FakeLeaseTable::default();
+        let table = FakeLeaseTable::default();
+        let existing = lease_key("shardId-000000000001");
+        let missing = lease_key("shardId-000000000002");
+        source_table.put(&lease(&existing, Checkpoint::TrimHorizon, Some("worker-1")));
+        source_table.put(&lease(&missing, sequence_number(EARLIER), None));
+        table.put(&lease(&existing, Checkpoint::TrimHorizon, None));
+        // The source keeps its checkpoints in a store of its own
+        let directory = fake_aws::temp_directory();
+        let source_store = Arc::new(FileCheckpointStore::new(&directory));
+        source_store
+            .set_checkpoint(&existing, &sequence_number(LATER))
+            .await
+            .unwrap();
+        let source = CheckpointAdmin::new(source_table.client(), "source-leases", stream())
+            .with_checkpoint_store(source_store);
+
+        assert_eq!(admin(&table).copy_from(&source, false).await, Ok(2));
+        assert_eq!(checkpoint(&table, &existing), Some(sequence_number(LATER)));
+        let created = table.get(&missing).unwrap();
+        assert_eq!(created.lease_owner, None);
+        assert_eq!(checkpoint(&table, &missing), Some(sequence_number(EARLIER)));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn deletes_stale_leases_and_their_stored_checkpoints() {
+        let table = FakeLeaseTable::default();
+        let stale = lease_key("shardId-000000000001");
+        table.put(&lease(&stale, sequence_number(LATER), Some("worker-1")));
+        let directory = fake_aws::temp_directory();
+        let store = Arc::new(FileCheckpointStore::new(&directory));
+        store
+            .set_checkpoint(&stale, &sequence_number(LATER))
+            .await
+            .unwrap();
+        let recreation = StreamRecreation {
+            stream_creation_millis: 1_600_000_000_000,
+            stale_leases: vec![stale.clone()],
+        };
+
+        let admin = admin(&table).with_checkpoint_store(store.clone());
+        assert_eq!(
+            admin.delete_stale_leases(&recreation, false).await,
+            Err(CheckpointAdminError::LeasesHeld(vec![stale.clone()]))
+        );
+        assert!(table.get(&stale).is_some());
+
+        assert_eq!(admin.delete_stale_leases(&recreation, true).await, Ok(1));
+        assert!(table.get(&stale).is_none());
+        assert_eq!(store.get_checkpoint(&stale).await, Ok(None));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
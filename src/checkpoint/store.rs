@@ -0,0 +1,339 @@
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use crate::lease::manager::LeaseManager;
+
+use super::{Checkpoint, CheckpointError};
+
+/// Where a `ShardWorker` loads and saves checkpoints.
+///
+/// Lease ownership is verified before every save, but only the default store writes the checkpoint
+/// conditionally on the lease itself. Stores kept elsewhere should tolerate a rare late write from
+/// a worker that has just lost its lease. `SHARD_END` is written to the lease as well, as child
+/// shards and lease cleanup go by the lease.
+///
+/// Checkpoints are keyed by the shard's lease key, which is its shard ID unless the stream was
+/// given by ARN.
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    async fn get_checkpoint(&self, lease_key: &str) -> Result<Option<Checkpoint>, CheckpointError>;
+    async fn set_checkpoint(
+        &self,
+        lease_key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError>;
+    /// Forgets a shard's checkpoint, for a lease that's deleted before its shard was finished.
+    /// Does nothing by default, which leaves the checkpoint behind for a later shard with the
+    /// same lease key to start from.
+    async fn delete_checkpoint(&self, _lease_key: &str) -> Result<(), CheckpointError> {
+        Ok(())
+    }
+}
+
+/// Loads the checkpoint a shard's processing starts from. Stores other than the lease table have
+/// nothing for shards they haven't seen yet, so those start from the lease's checkpoint, which the
+/// shard syncer set to the application's initial position.
+pub(crate) async fn load_checkpoint(
+    store: &dyn CheckpointStore,
+    lease_manager: &LeaseManager,
+    lease_key: &str,
+) -> Result<Checkpoint, CheckpointError> {
+    match store.get_checkpoint(lease_key).await? {
+        Some(checkpoint) => Ok(checkpoint),
+        None => Ok(lease_manager
+            .get_checkpoint(lease_key)
+            .await
+            .unwrap_or(Checkpoint::TrimHorizon)),
+    }
+}
+
+/// The default store, which keeps checkpoints on the lease items.
+pub(crate) struct LeaseCheckpointStore {
+    lease_manager: Arc<LeaseManager>,
+}
+
+impl LeaseCheckpointStore {
+    pub(crate) fn new(lease_manager: Arc<LeaseManager>) -> Self {
+        Self { lease_manager }
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for LeaseCheckpointStore {
+    async fn get_checkpoint(&self, lease_key: &str) -> Result<Option<Checkpoint>, CheckpointError> {
+        Ok(self.lease_manager.get_checkpoint(lease_key).await)
+    }
+
+    async fn set_checkpoint(
+        &self,
+        lease_key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        self.lease_manager
+            .update_checkpoint(lease_key, checkpoint)
+            .await
+    }
+
+    async fn delete_checkpoint(&self, _lease_key: &str) -> Result<(), CheckpointError> {
+        // The checkpoint goes with the lease
+        Ok(())
+    }
+}
+
+/// Keeps each shard's checkpoint in its own file under a directory.
+pub struct FileCheckpointStore {
+    directory: PathBuf,
+}
+
+impl FileCheckpointStore {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    /// Lease keys of streams given by ARN have slashes and colons in them, so those are escaped.
+    fn checkpoint_path(&self, lease_key: &str) -> PathBuf {
+        let file_name = lease_key
+            .replace('%', "%25")
+            .replace('/', "%2F")
+            .replace(':', "%3A");
+        self.directory.join(format!("{}.checkpoint", file_name))
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for FileCheckpointStore {
+    async fn get_checkpoint(&self, lease_key: &str) -> Result<Option<Checkpoint>, CheckpointError> {
+        match tokio::fs::read_to_string(self.checkpoint_path(lease_key)).await {
+            Ok(contents) => Checkpoint::from_lease_value(contents.trim())
+                .map(Some)
+                .ok_or_else(|| {
+                    CheckpointError::Store(format!(
+                        "Corrupt checkpoint file for lease '{}'",
+                        lease_key
+                    ))
+                }),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(CheckpointError::Store(err.to_string())),
+        }
+    }
+
+    async fn set_checkpoint(
+        &self,
+        lease_key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        // Write then rename so a crash never leaves a half-written checkpoint behind
+        let path = self.checkpoint_path(lease_key);
+        let staging_path = path.with_extension("checkpoint.tmp");
+        tokio::fs::create_dir_all(&self.directory)
+            .await
+            .map_err(|err| CheckpointError::Store(err.to_string()))?;
+        // Synced before the rename, or a crash could leave the renamed file empty
+        let mut staging_file = tokio::fs::File::create(&staging_path)
+            .await
+            .map_err(|err| CheckpointError::Store(err.to_string()))?;
+        staging_file
+            .write_all(checkpoint.to_lease_value().as_bytes())
+            .await
+            .map_err(|err| CheckpointError::Store(err.to_string()))?;
+        staging_file
+            .sync_all()
+            .await
+            .map_err(|err| CheckpointError::Store(err.to_string()))?;
+        tokio::fs::rename(&staging_path, &path)
+            .await
+            .map_err(|err| CheckpointError::Store(err.to_string()))
+    }
+
+    async fn delete_checkpoint(&self, lease_key: &str) -> Result<(), CheckpointError> {
+        match tokio::fs::remove_file(self.checkpoint_path(lease_key)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(CheckpointError::Store(err.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::ShardFilter,
+        kinesis::StreamDescriptor,
+        lease::{broker::LeaseBroker, Lease},
+        util::fake_aws::{self, FakeLeaseTable},
+    };
+
+    use super::*;
+
+    const SEQUENCE_NUMBER: &str = "49590338271490256608559692538361571095921575989136588898";
+
+    async fn lease_manager(
+        table: &FakeLeaseTable,
+        shard_id: &str,
+        checkpoint: Checkpoint,
+    ) -> Arc<LeaseManager> {
+        let lease = Lease {
+            lease_owner: Some("worker-1".to_string()),
+            ..Lease::new(
+                shard_id.to_string(),
+                checkpoint.to_lease_value(),
+                Vec::new(),
+                "0".to_string(),
+                u128::MAX.to_string(),
+            )
+        };
+        table.put(&lease);
+        let lease_manager = Arc::new(LeaseManager::new(
+            LeaseBroker::new(table.client(), "leases".to_string()),
+            "worker-1".to_string(),
+            StreamDescriptor::from_name("test"),
+            ShardFilter::All,
+        ));
+        lease_manager.hold_lease(lease).await;
+        lease_manager
+    }
+
+    #[tokio::test]
+    async fn file_store_round_trips_checkpoints() {
+        let directory = fake_aws::temp_directory();
+        let store = FileCheckpointStore::new(&directory);
+        assert_eq!(store.get_checkpoint("shardId-000000000001").await, Ok(None));
+
+        let checkpoint = Checkpoint::SequenceNumber(SEQUENCE_NUMBER.to_string());
+        store
+            .set_checkpoint("shardId-000000000001", &checkpoint)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_checkpoint("shardId-000000000001").await,
+            Ok(Some(checkpoint))
+        );
+        store
+            .set_checkpoint("shardId-000000000001", &Checkpoint::ShardEnd)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_checkpoint("shardId-000000000001").await,
+            Ok(Some(Checkpoint::ShardEnd))
+        );
+
+        // Only the renamed file is left behind
+        let files: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(files, vec!["shardId-000000000001.checkpoint".to_string()]);
+
+        store
+            .delete_checkpoint("shardId-000000000001")
+            .await
+            .unwrap();
+        assert_eq!(store.get_checkpoint("shardId-000000000001").await, Ok(None));
+        assert_eq!(
+            store.delete_checkpoint("shardId-000000000001").await,
+            Ok(())
+        );
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn file_store_escapes_lease_keys() {
+        let directory = fake_aws::temp_directory();
+        let store = FileCheckpointStore::new(&directory);
+        let arn_key = "arn:aws:kinesis:us-east-1:123456789012:stream/test:shardId-000000000001";
+        store
+            .set_checkpoint(arn_key, &Checkpoint::Latest)
+            .await
+            .unwrap();
+        store
+            .set_checkpoint("shardId-000000000001", &Checkpoint::TrimHorizon)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get_checkpoint(arn_key).await,
+            Ok(Some(Checkpoint::Latest))
+        );
+        assert_eq!(
+            store.get_checkpoint("shardId-000000000001").await,
+            Ok(Some(Checkpoint::TrimHorizon))
+        );
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn file_store_reports_corrupt_checkpoints() {
+        let directory = fake_aws::temp_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("shardId-000000000001.checkpoint"), "garbage").unwrap();
+        let store = FileCheckpointStore::new(&directory);
+        assert!(matches!(
+            store.get_checkpoint("shardId-000000000001").await,
+            Err(CheckpointError::Store(_))
+        ));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn lease_store_keeps_checkpoints_on_held_leases() {
+        let table = FakeLeaseTable::default();
+        let lease_manager =
+            lease_manager(&table, "shardId-000000000001", Checkpoint::TrimHorizon).await;
+        let store = LeaseCheckpointStore::new(lease_manager);
+        assert_eq!(
+            store.get_checkpoint("shardId-000000000001").await,
+            Ok(Some(Checkpoint::TrimHorizon))
+        );
+
+        let checkpoint = Checkpoint::SequenceNumber(SEQUENCE_NUMBER.to_string());
+        store
+            .set_checkpoint("shardId-000000000001", &checkpoint)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_checkpoint("shardId-000000000001").await,
+            Ok(Some(checkpoint.clone()))
+        );
+        assert_eq!(
+            table.get("shardId-000000000001").unwrap().checkpoint,
+            Some(checkpoint.to_lease_value())
+        );
+
+        // Leases we don't hold can't be written
+        assert_eq!(store.get_checkpoint("shardId-000000000002").await, Ok(None));
+        assert_eq!(
+            store
+                .set_checkpoint("shardId-000000000002", &Checkpoint::ShardEnd)
+                .await,
+            Err(CheckpointError::LeaseLost)
+        );
+    }
+
+    #[tokio::test]
+    async fn new_shards_start_from_the_lease_checkpoint() {
+        let table = FakeLeaseTable::default();
+        let lease_manager = lease_manager(&table, "shardId-000000000001", Checkpoint::Latest).await;
+        let directory = fake_aws::temp_directory();
+        let store = FileCheckpointStore::new(&directory);
+        assert_eq!(
+            load_checkpoint(&store, &lease_manager, "shardId-000000000001").await,
+            Ok(Checkpoint::Latest)
+        );
+
+        store
+            .set_checkpoint("shardId-000000000001", &Checkpoint::TrimHorizon)
+            .await
+            .unwrap();
+        assert_eq!(
+            load_checkpoint(&store, &lease_manager, "shardId-000000000001").await,
+            Ok(Checkpoint::TrimHorizon)
+        );
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
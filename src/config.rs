@@ -0,0 +1,370 @@
+use std::{collections::HashSet, fmt, sync::Arc, time::Duration};
+
+use rusoto_core::credential::ProvideAwsCredentials;
+
+use crate::{
+    checkpoint::{Checkpoint, CheckpointMode, CheckpointStore},
+    kinesis::StreamDescriptor,
+    source::RecordSource,
+    status::HashKeyRange,
+};
+
+/// Settings for a `WorkerScheduler`.
+#[derive(Clone)]
+pub struct SchedulerConfig {
+    /// Names the application's lease table and enhanced fan-out consumer, so it must be 3 to 128
+    /// letters, digits, `_`, `-` or `.`.
+    pub application_name: String,
+    /// The stream to consume.
+    pub stream: StreamDescriptor,
+    /// Credentials for the stream's Kinesis client, such as an assumed role in the account that
+    /// owns the stream. Defaults to the usual credentials chain.
+    pub kinesis_credentials: Option<Arc<dyn ProvideAwsCredentials + Send + Sync>>,
+    /// What to do when the stream has been deleted and recreated under the same name since its
+    /// leases were created.
+    pub stream_recreation_policy: StreamRecreationPolicy,
+    /// Which of the stream's shards this application consumes.
+    pub shard_filter: ShardFilter,
+    /// Where consumption starts for shards that have no ancestors left in the stream.
+    pub initial_position: Checkpoint,
+    /// How often the stream's shards are listed to create leases for new ones. Workers create
+    /// leases for child shards as soon as their parents report them, so this is only a safety net.
+    pub shard_sync_interval: Duration,
+    /// How often the leases' hash key ranges are checked for gaps and overlaps.
+    pub hash_range_audit_interval: Duration,
+    /// How often a child shard's worker checks whether its parents have been finished.
+    pub parent_shard_poll_interval: Duration,
+    /// How records are read from the stream's shards.
+    pub retrieval_mode: RetrievalMode,
+    /// Where shards and records come from instead of the stream, such as captured or made up
+    /// records in a test. The stream is then only used to key the leases, and the retrieval mode
+    /// is ignored.
+    pub record_source: Option<Arc<dyn RecordSource>>,
+    /// How far ahead of the record processors records are read.
+    pub prefetch: PrefetchConfig,
+    /// How records are grouped into `process_records` calls.
+    pub batch_shaping: BatchShapingConfig,
+    /// When set, record processors are called with no records once their shard has gone this long
+    /// without any, so they get a chance to flush what they've buffered and checkpoint. Otherwise
+    /// they're called after every fetch, even one that returned no records.
+    pub idle_batch_interval: Option<Duration>,
+    /// Where checkpoints are loaded from and saved to. Defaults to the lease table.
+    pub checkpoint_store: Option<Arc<dyn CheckpointStore>>,
+    /// Whether processors checkpoint for themselves or the worker does it for them.
+    pub checkpoint_mode: CheckpointMode,
+    /// When set, checkpoints made within this long of the last write are coalesced into one.
+    pub checkpoint_coalescing_window: Option<Duration>,
+    /// How leases for finished and trimmed shards are removed from the lease table.
+    pub lease_cleanup: LeaseCleanupConfig,
+}
+
+impl SchedulerConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let name = &self.application_name;
+        let valid_name = (3..=128).contains(&name.len())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid_name {
+            return Err(ConfigError::InvalidApplicationName(name.clone()));
+        }
+        if self.stream.is_dynamodb_stream()
+            && !matches!(self.retrieval_mode, RetrievalMode::Polling(_))
+        {
+            return Err(ConfigError::PollingOnlyStream);
+        }
+        Ok(())
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            application_name: String::new(),
+            stream: StreamDescriptor::from_name(""),
+            kinesis_credentials: None,
+            stream_recreation_policy: StreamRecreationPolicy::default(),
+            shard_filter: ShardFilter::All,
+            initial_position: Checkpoint::TrimHorizon,
+            shard_sync_interval: Duration::from_secs(60),
+            hash_range_audit_interval: Duration::from_secs(5 * 60),
+            parent_shard_poll_interval: Duration::from_secs(10),
+            retrieval_mode: RetrievalMode::default(),
+            record_source: None,
+            prefetch: PrefetchConfig::default(),
+            batch_shaping: BatchShapingConfig::default(),
+            idle_batch_interval: None,
+            checkpoint_store: None,
+            checkpoint_mode: CheckpointMode::default(),
+            checkpoint_coalescing_window: None,
+            lease_cleanup: LeaseCleanupConfig::default(),
+        }
+    }
+}
+
+/// Why a `WorkerScheduler` can't be built from a `SchedulerConfig`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The application name can't name a DynamoDB table and a fan-out consumer.
+    InvalidApplicationName(String),
+    /// DynamoDB streams can only be polled, and the retrieval mode uses enhanced fan-out.
+    PollingOnlyStream,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidApplicationName(name) => write!(
+                f,
+                "'{}' is not a valid application name: it must be 3 to 128 letters, digits, '_', '-' \
+                 or '.'",
+                name
+            ),
+            ConfigError::PollingOnlyStream => write!(
+                f,
+                "DynamoDB streams can only be read with the Polling retrieval mode"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings for removing leases that are no longer needed.
+#[derive(Debug, Clone)]
+pub struct LeaseCleanupConfig {
+    /// How often the lease table is checked for leases to remove.
+    pub interval: Duration,
+    /// How long a lease has to stay at `SHARD_END`, with all of its children started, before it's
+    /// removed.
+    pub completed_lease_delay: Duration,
+    /// How long a shard has to be missing from the stream's listing before its lease is removed.
+    pub trimmed_lease_delay: Duration,
+    /// Only report which leases would be removed, without removing them.
+    pub dry_run: bool,
+}
+
+impl Default for LeaseCleanupConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+            completed_lease_delay: Duration::from_secs(5 * 60),
+            trimmed_lease_delay: Duration::from_secs(30 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+pub type ShardPredicate = Arc<dyn Fn(&str, Option<&HashKeyRange>) -> bool + Send + Sync>;
+
+/// Limits consumption to part of a stream. Shards outside the filter get no leases and are never
+/// taken.
+#[derive(Clone)]
+pub enum ShardFilter {
+    All,
+    /// Shards whose hash key range overlaps this one. Records from those shards that fall outside
+    /// it are dropped before they reach the record processor.
+    HashKeyRange(HashKeyRange),
+    ShardIds(HashSet<String>),
+    /// Called with each shard's ID and, when it's known, its hash key range.
+    Predicate(ShardPredicate),
+}
+
+impl ShardFilter {
+    pub(crate) fn matches_shard(
+        &self,
+        shard_id: &str,
+        hash_key_range: Option<&HashKeyRange>,
+    ) -> bool {
+        match self {
+            ShardFilter::All => true,
+            ShardFilter::HashKeyRange(range) => {
+                matches!(hash_key_range, Some(shard_range) if range.overlaps(shard_range))
+            }
+            ShardFilter::ShardIds(shard_ids) => shard_ids.contains(shard_id),
+            ShardFilter::Predicate(predicate) => predicate(shard_id, hash_key_range),
+        }
+    }
+
+    pub(crate) fn matches_hash_key(&self, hash_key: u128) -> bool {
+        match self {
+            ShardFilter::HashKeyRange(range) => range.contains(hash_key),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamRecreationPolicy {
+    /// Shut the scheduler down.
+    #[default]
+    Fail,
+    /// Delete the old stream's leases, so the new stream is consumed from the initial position.
+    ResetLeases,
+    /// Stop processing until the leases are dealt with, for example with
+    /// `CheckpointAdmin::delete_stale_leases`. Processing resumes after the next shard sync, within
+    /// the shard sync interval.
+    Wait,
+}
+
+#[derive(Debug, Clone)]
+pub enum RetrievalMode {
+    /// Records are pushed over `SubscribeToShard`, with dedicated throughput per consumer.
+    FanOut(FanOutConfig),
+    /// Records are pulled with `GetRecords`, sharing the shard's read throughput with every other
+    /// polling consumer.
+    Polling(PollingConfig),
+    /// Polls while a shard is far behind, where large `GetRecords` pages catch up more cheaply,
+    /// and subscribes once it's close to the tip of the stream.
+    Hybrid(HybridConfig),
+}
+
+impl RetrievalMode {
+    pub(crate) fn fan_out_config(&self) -> Option<&FanOutConfig> {
+        match self {
+            RetrievalMode::FanOut(fan_out_config) => Some(fan_out_config),
+            RetrievalMode::Hybrid(hybrid_config) => Some(&hybrid_config.fan_out),
+            RetrievalMode::Polling(_) => None,
+        }
+    }
+}
+
+impl Default for RetrievalMode {
+    fn default() -> Self {
+        RetrievalMode::FanOut(FanOutConfig::default())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FanOutConfig {
+    /// Deregister the application's stream consumer when the scheduler shuts down. Leave this off
+    /// when other instances of the application are still running.
+    pub deregister_on_shutdown: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PollingConfig {
+    /// The most records a single `GetRecords` call returns, up to 10,000.
+    pub max_records: i64,
+    /// How long to wait between `GetRecords` calls. Each shard allows five calls a second across
+    /// all of its consumers.
+    pub idle_interval: Duration,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 10_000,
+            idle_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HybridConfig {
+    pub fan_out: FanOutConfig,
+    pub polling: PollingConfig,
+    /// Switch from fan-out to polling once a shard is at least this far behind.
+    pub poll_when_behind: Duration,
+    /// Switch from polling to fan-out once a shard has caught up to within this of the tip. Keep
+    /// it well below `poll_when_behind`, so a shard doesn't keep switching back and forth.
+    pub subscribe_when_behind: Duration,
+}
+
+impl Default for HybridConfig {
+    fn default() -> Self {
+        Self {
+            fan_out: FanOutConfig::default(),
+            polling: PollingConfig::default(),
+            poll_when_behind: Duration::from_secs(60 * 60),
+            subscribe_when_behind: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Limits on the records read from a shard that its record processor hasn't finished with yet. A
+/// batch counts until the next one is asked for, so records held back to be merged by batch
+/// shaping stop counting once the batch after them is fetched.
+#[derive(Debug, Clone)]
+pub struct PrefetchConfig {
+    /// The most records buffered for a single shard.
+    pub max_records: usize,
+    /// The most record data, in bytes, buffered for a single shard.
+    pub max_bytes: usize,
+    /// The most record data, in bytes, buffered across all of the scheduler's shards.
+    pub max_total_bytes: usize,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 30_000,
+            max_bytes: 8 * 1024 * 1024,
+            max_total_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Limits on the records handed to a record processor at once. By default each
+/// `process_records` call gets whatever one fetch returned.
+#[derive(Debug, Clone, Default)]
+pub struct BatchShapingConfig {
+    /// The most records in one call. Bigger batches are split.
+    pub max_records: Option<usize>,
+    /// The most record data, in bytes, in one call. A record bigger than this is still handed over,
+    /// on its own.
+    pub max_bytes: Option<usize>,
+    /// When set, batches under the limits are held back and merged with the ones after them, until
+    /// the limits are reached or the oldest held record has waited this long.
+    pub linger: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(application_name: &str) -> SchedulerConfig {
+        SchedulerConfig {
+            application_name: application_name.to_string(),
+            stream: StreamDescriptor::from_name("test"),
+            ..SchedulerConfig::default()
+        }
+    }
+
+    #[test]
+    fn requires_a_valid_application_name() {
+        assert_eq!(named("orders-app_v1.2").validate(), Ok(()));
+        for name in ["", "ab", "orders app", "orders/app", &"a".repeat(129)] {
+            assert_eq!(
+                named(name).validate(),
+                Err(ConfigError::InvalidApplicationName(name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn only_polls_dynamodb_streams() {
+        let stream = StreamDescriptor::from_dynamodb_stream_arn(
+            "arn:aws:dynamodb:us-east-1:123456789012:table/orders/stream/2020-01-01T00:00:00.000",
+        )
+        .unwrap();
+        let config = |retrieval_mode| SchedulerConfig {
+            stream: stream.clone(),
+            retrieval_mode,
+            ..named("orders-app")
+        };
+        assert_eq!(
+            config(RetrievalMode::Polling(PollingConfig::default())).validate(),
+            Ok(())
+        );
+        for retrieval_mode in [
+            RetrievalMode::FanOut(FanOutConfig::default()),
+            RetrievalMode::Hybrid(HybridConfig::default()),
+        ] {
+            assert_eq!(
+                config(retrieval_mode).validate(),
+                Err(ConfigError::PollingOnlyStream)
+            );
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use dynomite::dynamodb::AttributeValue;
+use serde::{Deserialize, Serialize};
+
+pub type Item = HashMap<String, AttributeValue>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChangeEventName {
+    Insert,
+    Modify,
+    Remove,
+}
+
+/// A change to a table item, as read from its DynamoDB stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    #[serde(rename = "eventID")]
+    pub event_id: String,
+    #[serde(rename = "eventName")]
+    pub event_name: ChangeEventName,
+    #[serde(rename = "eventSource", default)]
+    pub event_source: Option<String>,
+    #[serde(rename = "eventVersion", default)]
+    pub event_version: Option<String>,
+    #[serde(rename = "awsRegion", default)]
+    pub aws_region: Option<String>,
+    #[serde(rename = "dynamodb")]
+    pub change: StreamRecord,
+    /// Set for items that were removed by their time to live running out.
+    #[serde(rename = "userIdentity", default)]
+    pub user_identity: Option<Identity>,
+}
+
+impl ChangeRecord {
+    pub fn keys(&self) -> &Item {
+        &self.change.keys
+    }
+
+    /// The item after the change, if the stream's view type includes new images.
+    pub fn new_image(&self) -> Option<&Item> {
+        self.change.new_image.as_ref()
+    }
+
+    /// The item before the change, if the stream's view type includes old images.
+    pub fn old_image(&self) -> Option<&Item> {
+        self.change.old_image.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamRecord {
+    /// Seconds since the epoch, rounded down to the minute.
+    #[serde(rename = "ApproximateCreationDateTime", default)]
+    pub approximate_creation_date_time: Option<f64>,
+    #[serde(rename = "Keys", default)]
+    pub keys: Item,
+    #[serde(rename = "NewImage", default)]
+    pub new_image: Option<Item>,
+    #[serde(rename = "OldImage", default)]
+    pub old_image: Option<Item>,
+    #[serde(rename = "SequenceNumber")]
+    pub sequence_number: String,
+    #[serde(rename = "SizeBytes", default)]
+    pub size_bytes: Option<i64>,
+    /// `KEYS_ONLY`, `NEW_IMAGE`, `OLD_IMAGE` or `NEW_AND_OLD_IMAGES`.
+    #[serde(rename = "StreamViewType", default)]
+    pub stream_view_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(rename = "PrincipalId", default)]
+    pub principal_id: Option<String>,
+    #[serde(rename = "Type", default)]
+    pub type_: Option<String>,
+}
@@ -1,2 +1,3 @@
+pub mod dynamodb;
 pub mod processor;
 pub mod record;
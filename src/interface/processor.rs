@@ -1,18 +1,73 @@
+use std::{sync::Arc, time::Instant};
+
 use async_trait::async_trait;
 use bytes::Bytes;
 use rusoto_kinesis::ChildShard;
 
 use super::record::KinesisClientRecord;
+use crate::checkpoint::{Checkpoint, RecordProcessorCheckpointer};
 
 pub struct InitializationInput {
     pub shard_id: String,
+    pub checkpoint: Checkpoint,
     pub pending_checkpoint_state: Option<Bytes>,
 }
 
+/// Handed to `RecordProcessor::process_records`. `records` is only empty at the end of a shard, or
+/// when `SchedulerConfig::idle_batch_interval` is set and the shard has been quiet for that long.
 pub struct ProcessRecordsInput {
+    pub shard_id: String,
     pub records: Vec<KinesisClientRecord>,
     pub is_at_shard_end: bool,
     pub child_shards: Vec<ChildShard>,
+    pub checkpointer: Arc<RecordProcessorCheckpointer>,
+    /// How far the last record read from the shard is behind the tip of the stream, as of the
+    /// latest response from Kinesis.
+    pub millis_behind_latest: Option<i64>,
+    /// When the records were read from Kinesis. `None` for idle calls.
+    pub fetched_at: Option<Instant>,
+    /// When the records were put in the prefetch buffer. `None` for idle calls.
+    pub cached_at: Option<Instant>,
+}
+
+/// Handed to `RecordProcessor::shard_ended`. Unless checkpointing is automatic, the processor must
+/// call `checkpointer.checkpoint()`, which records `SHARD_END`, before child shards can start. If
+/// it doesn't, its lease is released and the shard's remaining records are delivered again.
+pub struct ShardEndedInput {
+    pub checkpointer: Arc<RecordProcessorCheckpointer>,
+}
+
+/// Why records couldn't be read from a shard. Throttling, transient failures and expired iterators
+/// are retried with backoff, so only the other kinds ever reach a record processor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetrievalError {
+    Throttled(String),
+    /// A network failure or a server-side error.
+    Transient(String),
+    ExpiredIterator(String),
+    /// The stream, shard or fan-out consumer doesn't exist.
+    ResourceNotFound(String),
+    /// The application isn't allowed to read the stream or to decrypt its records.
+    AccessDenied(String),
+    Unrecoverable(String),
+}
+
+impl RetrievalError {
+    pub(crate) fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            RetrievalError::Throttled(_)
+                | RetrievalError::Transient(_)
+                | RetrievalError::ExpiredIterator(_)
+        )
+    }
+}
+
+/// Handed to `RecordProcessor::retrieval_failed` when the shard can't be read any further. The
+/// processor can still checkpoint what it has processed.
+pub struct RetrievalFailedInput {
+    pub error: RetrievalError,
+    pub checkpointer: Arc<RecordProcessorCheckpointer>,
 }
 
 #[async_trait]
@@ -20,6 +75,11 @@ pub trait RecordProcessor: Send + Sync {
     async fn initialize(&self, input: InitializationInput);
     async fn process_records(&self, input: ProcessRecordsInput);
     async fn lease_lost(&self);
-    async fn shard_ended(&self);
+    async fn shard_ended(&self, input: ShardEndedInput);
     async fn shutdown_requested(&self);
+    /// Called instead of `shutdown_requested` when the worker gives up on the shard. Its lease is
+    /// then released, so another worker, or this one, can take it and retry the shard from its
+    /// last checkpoint. A shard that has been trimmed, or whose stream was recreated, keeps its
+    /// lease until lease cleanup or the stream recreation policy deals with it.
+    async fn retrieval_failed(&self, _input: RetrievalFailedInput) {}
 }
@@ -3,6 +3,8 @@
 use bytes::Bytes;
 use rusoto_kinesis::Record;
 
+use super::dynamodb::ChangeRecord;
+
 pub struct KinesisClientRecord {
     pub sequence_number: String,
     // approximate_arrival_timestamp: Instant,
@@ -34,4 +36,22 @@ impl KinesisClientRecord {
             aggregated: false,
         }
     }
+
+    /// Where the record falls in the stream's hash key space: its explicit hash key if it has one,
+    /// otherwise the MD5 of its partition key.
+    pub fn hash_key(&self) -> u128 {
+        if let Some(hash_key) = self
+            .explicit_hash_key
+            .as_ref()
+            .and_then(|hash_key| hash_key.parse().ok())
+        {
+            return hash_key;
+        }
+        u128::from_be_bytes(md5::compute(self.partition_key.as_bytes()).0)
+    }
+
+    /// Reads the change a record from a DynamoDB stream describes, which it carries as JSON.
+    pub fn dynamodb_change(&self) -> Result<ChangeRecord, serde_json::Error> {
+        serde_json::from_slice(&self.data)
+    }
 }
@@ -0,0 +1,267 @@
+use std::{sync::Arc, time::Duration};
+
+use rusoto_core::RusotoError;
+use rusoto_kinesis::{
+    ConsumerDescription, DeregisterStreamConsumerError, DeregisterStreamConsumerInput,
+    DescribeStreamConsumerError, DescribeStreamConsumerInput, Kinesis, KinesisClient,
+    RegisterStreamConsumerError, RegisterStreamConsumerInput,
+};
+use tokio::sync::Mutex;
+
+use crate::util::exception::Exception;
+
+use super::{describe_stream_summary, StreamDescriptor};
+
+static ACTIVE: &str = "ACTIVE";
+
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_STATUS_POLLS: usize = 60;
+
+/// The application's enhanced fan-out consumer, registered on first use and shared by every
+/// `ShardWorker`.
+pub(crate) struct StreamConsumer {
+    kinesis: Arc<KinesisClient>,
+    stream: StreamDescriptor,
+    consumer_name: String,
+    consumer_arn: Mutex<Option<String>>,
+}
+
+impl StreamConsumer {
+    pub(crate) fn new(
+        kinesis: Arc<KinesisClient>,
+        stream: StreamDescriptor,
+        consumer_name: String,
+    ) -> Self {
+        Self {
+            kinesis,
+            stream,
+            consumer_name,
+            consumer_arn: Mutex::new(None),
+        }
+    }
+
+    /// Finds or registers the consumer and waits for it to become active. Only the first caller
+    /// does any of that; the rest wait for it and share the result.
+    pub(crate) async fn consumer_arn(&self) -> Result<String, Exception> {
+        let mut consumer_arn = self.consumer_arn.lock().await;
+        if let Some(consumer_arn) = consumer_arn.as_ref() {
+            return Ok(consumer_arn.clone());
+        }
+
+        let stream_arn = match self.stream.arn() {
+            Some(stream_arn) => stream_arn.to_string(),
+            None => {
+                describe_stream_summary(self.kinesis.as_ref(), &self.stream)
+                    .await?
+                    .stream_arn
+            }
+        };
+        for _ in 0..MAX_STATUS_POLLS {
+            match self.describe(&stream_arn).await? {
+                Some(consumer) if consumer.consumer_status == ACTIVE => {
+                    *consumer_arn = Some(consumer.consumer_arn.clone());
+                    return Ok(consumer.consumer_arn);
+                }
+                // Still being created, or being deleted, in which case the name can't be reused
+                // until it's gone
+                Some(_) => {}
+                None => self.register(&stream_arn).await?,
+            }
+            tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+        }
+
+        Err(Exception::Retryable(format!(
+            "Consumer '{}' did not become active",
+            self.consumer_name
+        )))
+    }
+
+    /// Drops the cached ARN after a subscription found the consumer missing, so the next call
+    /// registers it again. Does nothing if another worker already did that.
+    pub(crate) async fn forget(&self, stale_consumer_arn: &str) {
+        let mut consumer_arn = self.consumer_arn.lock().await;
+        if consumer_arn.as_deref() == Some(stale_consumer_arn) {
+            *consumer_arn = None;
+        }
+    }
+
+    pub(crate) async fn deregister(&self) -> Result<(), Exception> {
+        let consumer_arn = match self.consumer_arn.lock().await.take() {
+            Some(consumer_arn) => consumer_arn,
+            None => return Ok(()),
+        };
+        let input = DeregisterStreamConsumerInput {
+            consumer_arn: Some(consumer_arn),
+            consumer_name: None,
+            stream_arn: None,
+        };
+        match self.kinesis.deregister_stream_consumer(input).await {
+            Ok(_)
+            | Err(RusotoError::Service(DeregisterStreamConsumerError::ResourceNotFound(_))) => {
+                Ok(())
+            }
+            Err(RusotoError::Service(DeregisterStreamConsumerError::LimitExceeded(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
+
+    async fn describe(&self, stream_arn: &str) -> Result<Option<ConsumerDescription>, Exception> {
+        let input = DescribeStreamConsumerInput {
+            consumer_arn: None,
+            consumer_name: Some(self.consumer_name.clone()),
+            stream_arn: Some(stream_arn.to_string()),
+        };
+        match self.kinesis.describe_stream_consumer(input).await {
+            Ok(res) => Ok(Some(res.consumer_description)),
+            Err(RusotoError::Service(DescribeStreamConsumerError::ResourceNotFound(_))) => Ok(None),
+            Err(RusotoError::Service(DescribeStreamConsumerError::LimitExceeded(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
+
+    async fn register(&self, stream_arn: &str) -> Result<(), Exception> {
+        let input = RegisterStreamConsumerInput {
+            consumer_name: self.consumer_name.clone(),
+            stream_arn: stream_arn.to_string(),
+        };
+        match self.kinesis.register_stream_consumer(input).await {
+            // Another application instance got there first, which is just as good
+            Ok(_) | Err(RusotoError::Service(RegisterStreamConsumerError::ResourceInUse(_))) => {
+                Ok(())
+            }
+            Err(RusotoError::Service(RegisterStreamConsumerError::LimitExceeded(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::util::fake_aws::{self, FakeResponse, ScriptedAws};
+
+    const STREAM_ARN: &str = "arn:aws:kinesis:us-east-1:123456789012:stream/orders";
+    const CONSUMER_ARN: &str =
+        "arn:aws:kinesis:us-east-1:123456789012:stream/orders/consumer/app:1600000000";
+
+    fn described(status: &str) -> FakeResponse {
+        (
+            200,
+            json!({
+                "ConsumerDescription": {
+                    "ConsumerARN": CONSUMER_ARN,
+                    "ConsumerCreationTimestamp": 1_600_000_000.0,
+                    "ConsumerName": "app",
+                    "ConsumerStatus": status,
+                    "StreamARN": STREAM_ARN,
+                }
+            }),
+        )
+    }
+
+    fn registered() -> FakeResponse {
+        (
+            200,
+            json!({
+                "Consumer": {
+                    "ConsumerARN": CONSUMER_ARN,
+                    "ConsumerCreationTimestamp": 1_600_000_000.0,
+                    "ConsumerName": "app",
+                    "ConsumerStatus": "CREATING",
+                }
+            }),
+        )
+    }
+
+    fn not_found() -> FakeResponse {
+        fake_aws::error("ResourceNotFoundException", "Consumer not found")
+    }
+
+    fn consumer(script: &ScriptedAws, stream: StreamDescriptor) -> StreamConsumer {
+        StreamConsumer::new(
+            Arc::new(fake_aws::kinesis_client(script.fake())),
+            stream,
+            "app".to_string(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registers_the_consumer_and_waits_for_it_to_become_active() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "DescribeStreamSummary",
+                fake_aws::stream_summary("orders", 1_600_000_000.0),
+            )
+            .respond("DescribeStreamConsumer", not_found())
+            .respond("RegisterStreamConsumer", registered())
+            .respond("DescribeStreamConsumer", described("CREATING"))
+            .respond("DescribeStreamConsumer", described("ACTIVE"));
+        let consumer = consumer(&script, StreamDescriptor::from_name("orders"));
+
+        assert_eq!(consumer.consumer_arn().await.unwrap(), CONSUMER_ARN);
+        // Every worker shares the ARN found the first time
+        assert_eq!(consumer.consumer_arn().await.unwrap(), CONSUMER_ARN);
+
+        let registrations = script.requests("RegisterStreamConsumer");
+        assert_eq!(
+            registrations,
+            vec![json!({ "ConsumerName": "app", "StreamARN": STREAM_ARN })]
+        );
+        assert_eq!(script.requests("DescribeStreamConsumer").len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registers_the_consumer_again_once_forgotten() {
+        let script = ScriptedAws::default();
+        script
+            .respond("DescribeStreamConsumer", described("ACTIVE"))
+            // Deleted, so registered again
+            .respond("DescribeStreamConsumer", not_found())
+            .respond("RegisterStreamConsumer", registered())
+            .respond("DescribeStreamConsumer", described("ACTIVE"));
+        let consumer = consumer(&script, StreamDescriptor::from_arn(STREAM_ARN).unwrap());
+
+        assert_eq!(consumer.consumer_arn().await.unwrap(), CONSUMER_ARN);
+        // Another worker already registered it again, so this ARN is left alone
+        consumer.forget("an older consumer ARN").await;
+        assert_eq!(consumer.consumer_arn().await.unwrap(), CONSUMER_ARN);
+        assert_eq!(script.requests("DescribeStreamConsumer").len(), 1);
+
+        consumer.forget(CONSUMER_ARN).await;
+        assert_eq!(consumer.consumer_arn().await.unwrap(), CONSUMER_ARN);
+        assert_eq!(script.requests("RegisterStreamConsumer").len(), 1);
+        assert_eq!(script.requests("DescribeStreamConsumer").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn deregisters_the_consumer_it_found() {
+        let script = ScriptedAws::default();
+        script
+            .respond("DescribeStreamConsumer", described("ACTIVE"))
+            .respond(
+                "DeregisterStreamConsumer",
+                (200, Value::Object(Default::default())),
+            );
+        let consumer = consumer(&script, StreamDescriptor::from_arn(STREAM_ARN).unwrap());
+
+        // Nothing to do before the consumer was used
+        consumer.deregister().await.unwrap();
+        assert!(script.requests("DeregisterStreamConsumer").is_empty());
+
+        consumer.consumer_arn().await.unwrap();
+        consumer.deregister().await.unwrap();
+        assert_eq!(
+            script.requests("DeregisterStreamConsumer"),
+            vec![json!({ "ConsumerARN": CONSUMER_ARN })]
+        );
+    }
+}
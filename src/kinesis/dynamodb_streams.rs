@@ -0,0 +1,626 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use rusoto_core::{
+    proto, request::BufferedHttpResponse, signature::SignedRequest, Client, Region, RusotoError,
+};
+use rusoto_kinesis::{
+    DescribeStreamSummaryError, DescribeStreamSummaryInput, DescribeStreamSummaryOutput,
+    GetRecordsError, GetRecordsInput, GetRecordsOutput, GetShardIteratorError,
+    GetShardIteratorInput, GetShardIteratorOutput, HashKeyRange, ListShardsError, ListShardsInput,
+    ListShardsOutput, Record, SequenceNumberRange, Shard, StreamDescriptionSummary,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::interface::dynamodb::ChangeRecord;
+
+use super::ShardApi;
+
+/// `GetRecords` on a DynamoDB stream returns at most this many records.
+const MAX_RECORDS: i64 = 1000;
+
+#[derive(Serialize)]
+struct DescribeStreamInput<'a> {
+    #[serde(rename = "StreamArn")]
+    stream_arn: &'a str,
+    #[serde(
+        rename = "ExclusiveStartShardId",
+        skip_serializing_if = "Option::is_none"
+    )]
+    exclusive_start_shard_id: Option<String>,
+    #[serde(rename = "Limit", skip_serializing_if = "Option::is_none")]
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct DescribeStreamOutput {
+    #[serde(rename = "StreamDescription")]
+    stream_description: StreamDescription,
+}
+
+#[derive(Deserialize)]
+struct StreamDescription {
+    #[serde(rename = "CreationRequestDateTime", default)]
+    creation_request_date_time: Option<f64>,
+    #[serde(rename = "LastEvaluatedShardId", default)]
+    last_evaluated_shard_id: Option<String>,
+    #[serde(rename = "Shards", default)]
+    shards: Vec<StreamShard>,
+    #[serde(rename = "StreamArn")]
+    stream_arn: String,
+    #[serde(rename = "StreamStatus")]
+    stream_status: String,
+    #[serde(rename = "TableName", default)]
+    table_name: String,
+}
+
+#[derive(Deserialize)]
+struct StreamShard {
+    #[serde(rename = "ParentShardId", default)]
+    parent_shard_id: Option<String>,
+    #[serde(rename = "SequenceNumberRange", default)]
+    sequence_number_range: Option<StreamSequenceNumberRange>,
+    #[serde(rename = "ShardId")]
+    shard_id: String,
+}
+
+#[derive(Deserialize)]
+struct StreamSequenceNumberRange {
+    #[serde(rename = "StartingSequenceNumber", default)]
+    starting_sequence_number: Option<String>,
+    #[serde(rename = "EndingSequenceNumber", default)]
+    ending_sequence_number: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GetStreamShardIteratorInput<'a> {
+    #[serde(rename = "StreamArn")]
+    stream_arn: &'a str,
+    #[serde(rename = "ShardId")]
+    shard_id: String,
+    #[serde(rename = "ShardIteratorType")]
+    shard_iterator_type: String,
+    #[serde(rename = "SequenceNumber", skip_serializing_if = "Option::is_none")]
+    sequence_number: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GetStreamShardIteratorOutput {
+    #[serde(rename = "ShardIterator", default)]
+    shard_iterator: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GetStreamRecordsInput {
+    #[serde(rename = "ShardIterator")]
+    shard_iterator: String,
+    #[serde(rename = "Limit", skip_serializing_if = "Option::is_none")]
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct GetStreamRecordsOutput {
+    #[serde(rename = "NextShardIterator", default)]
+    next_shard_iterator: Option<String>,
+    #[serde(rename = "Records", default)]
+    records: Vec<ChangeRecord>,
+}
+
+/// Maps the DynamoDB Streams API onto the Kinesis calls the scheduler makes. Shards have no hash
+/// key ranges, and each record's data is its `ChangeRecord` as JSON.
+pub(crate) struct DynamoDbStreamsAdapter {
+    client: Client,
+    region: Region,
+    stream_arn: String,
+}
+
+impl DynamoDbStreamsAdapter {
+    pub(crate) fn new(client: Client, region: Region, stream_arn: String) -> Self {
+        Self {
+            client,
+            region,
+            stream_arn,
+        }
+    }
+
+    /// Makes a call. Errors the service models come back as their type and message, and anything
+    /// else as the raw response.
+    async fn call<I: Serialize, O: DeserializeOwned>(
+        &self,
+        operation: &str,
+        input: &I,
+    ) -> Result<O, RusotoError<(String, String)>> {
+        let mut request = SignedRequest::new("POST", "dynamodb", &self.region, "/");
+        request.set_endpoint_prefix("streams.dynamodb".to_string());
+        request.set_content_type("application/x-amz-json-1.0".to_string());
+        request.add_header(
+            "x-amz-target",
+            &format!("DynamoDBStreams_20120810.{}", operation),
+        );
+        let encoded = serde_json::to_string(input).map_err(|err| {
+            RusotoError::Validation(format!("Failed to encode {}: {}", operation, err))
+        })?;
+        request.set_payload(Some(encoded));
+
+        let mut response = self.client.sign_and_dispatch(request).await?;
+        let response = response.buffer().await.map_err(RusotoError::HttpDispatch)?;
+        if !response.status.is_success() {
+            return Err(service_error(response));
+        }
+        proto::json::ResponsePayload::new(&response).deserialize::<O, _>()
+    }
+
+    async fn describe_stream(
+        &self,
+        exclusive_start_shard_id: Option<String>,
+        limit: Option<i64>,
+    ) -> Result<StreamDescription, RusotoError<(String, String)>> {
+        let input = DescribeStreamInput {
+            stream_arn: &self.stream_arn,
+            exclusive_start_shard_id,
+            limit,
+        };
+        let output: DescribeStreamOutput = self.call("DescribeStream", &input).await?;
+        Ok(output.stream_description)
+    }
+}
+
+/// Errors the API models come back as `Service`, with their type and message. Anything else, such
+/// as a server error or throttling of the account, is left as it was for the caller to classify.
+fn service_error(response: BufferedHttpResponse) -> RusotoError<(String, String)> {
+    match proto::json::Error::parse(&response) {
+        Some(err)
+            if matches!(
+                err.typ.as_str(),
+                "ExpiredIteratorException"
+                    | "LimitExceededException"
+                    | "ResourceNotFoundException"
+                    | "TrimmedDataAccessException"
+            ) =>
+        {
+            RusotoError::Service((err.typ, err.msg))
+        }
+        _ => RusotoError::Unknown(response),
+    }
+}
+
+/// Carries over everything but the service's own errors, which `map_service` translates.
+fn map_error<E>(
+    err: RusotoError<(String, String)>,
+    map_service: impl FnOnce(String, String) -> E,
+) -> RusotoError<E> {
+    match err {
+        RusotoError::Service((typ, msg)) => RusotoError::Service(map_service(typ, msg)),
+        RusotoError::HttpDispatch(err) => RusotoError::HttpDispatch(err),
+        RusotoError::Credentials(err) => RusotoError::Credentials(err),
+        RusotoError::Validation(msg) => RusotoError::Validation(msg),
+        RusotoError::ParseError(msg) => RusotoError::ParseError(msg),
+        RusotoError::Unknown(response) => RusotoError::Unknown(response),
+        RusotoError::Blocking => RusotoError::Blocking,
+    }
+}
+
+fn to_kinesis_shard(shard: StreamShard) -> Shard {
+    let sequence_number_range = shard
+        .sequence_number_range
+        .unwrap_or(StreamSequenceNumberRange {
+            starting_sequence_number: None,
+            ending_sequence_number: None,
+        });
+    Shard {
+        adjacent_parent_shard_id: None,
+        // Leases without a parseable range are left out of the hash range audit
+        hash_key_range: HashKeyRange {
+            starting_hash_key: String::new(),
+            ending_hash_key: String::new(),
+        },
+        parent_shard_id: shard.parent_shard_id,
+        sequence_number_range: SequenceNumberRange {
+            starting_sequence_number: sequence_number_range
+                .starting_sequence_number
+                .unwrap_or_default(),
+            ending_sequence_number: sequence_number_range.ending_sequence_number,
+        },
+        shard_id: shard.shard_id,
+    }
+}
+
+fn to_kinesis_record(record: ChangeRecord) -> Record {
+    Record {
+        approximate_arrival_timestamp: record.change.approximate_creation_date_time,
+        sequence_number: record.change.sequence_number.clone(),
+        data: Bytes::from(
+            serde_json::to_vec(&record).expect("Change records always serialize to JSON"),
+        ),
+        encryption_type: None,
+        partition_key: String::new(),
+    }
+}
+
+#[async_trait]
+impl ShardApi for DynamoDbStreamsAdapter {
+    async fn list_shards(
+        &self,
+        input: ListShardsInput,
+    ) -> Result<ListShardsOutput, RusotoError<ListShardsError>> {
+        // The last shard ID seen stands in for the pagination token
+        let description = self
+            .describe_stream(input.next_token, None)
+            .await
+            .map_err(|err| {
+                map_error(err, |typ, msg| match typ.as_str() {
+                    "LimitExceededException" => ListShardsError::LimitExceeded(msg),
+                    _ => ListShardsError::ResourceNotFound(msg),
+                })
+            })?;
+        Ok(ListShardsOutput {
+            next_token: description.last_evaluated_shard_id,
+            shards: Some(
+                description
+                    .shards
+                    .into_iter()
+                    .map(to_kinesis_shard)
+                    .collect(),
+            ),
+        })
+    }
+
+    async fn describe_stream_summary(
+        &self,
+        _input: DescribeStreamSummaryInput,
+    ) -> Result<DescribeStreamSummaryOutput, RusotoError<DescribeStreamSummaryError>> {
+        let description = self.describe_stream(None, Some(1)).await.map_err(|err| {
+            map_error(err, |typ, msg| match typ.as_str() {
+                "LimitExceededException" => DescribeStreamSummaryError::LimitExceeded(msg),
+                _ => DescribeStreamSummaryError::ResourceNotFound(msg),
+            })
+        })?;
+        Ok(DescribeStreamSummaryOutput {
+            stream_description_summary: StreamDescriptionSummary {
+                consumer_count: None,
+                encryption_type: None,
+                enhanced_monitoring: Vec::new(),
+                key_id: None,
+                open_shard_count: 0,
+                retention_period_hours: 24,
+                stream_arn: description.stream_arn,
+                stream_creation_timestamp: description.creation_request_date_time.unwrap_or(0.0),
+                stream_name: description.table_name,
+                stream_status: description.stream_status,
+            },
+        })
+    }
+
+    async fn get_shard_iterator(
+        &self,
+        input: GetShardIteratorInput,
+    ) -> Result<GetShardIteratorOutput, RusotoError<GetShardIteratorError>> {
+        if input.shard_iterator_type == "AT_TIMESTAMP" {
+            return Err(RusotoError::Service(
+                GetShardIteratorError::InvalidArgument(
+                    "DynamoDB streams can't be read from a timestamp".to_string(),
+                ),
+            ));
+        }
+        let mut input = GetStreamShardIteratorInput {
+            stream_arn: &self.stream_arn,
+            shard_id: input.shard_id,
+            shard_iterator_type: input.shard_iterator_type,
+            sequence_number: input.starting_sequence_number,
+        };
+        let output: GetStreamShardIteratorOutput =
+            match self.call("GetShardIterator", &input).await {
+                // The position has been trimmed away, and the records after it with it, so reading
+                // carries on from the oldest record left
+                Err(RusotoError::Service((typ, msg)))
+                    if typ == "TrimmedDataAccessException" && input.sequence_number.is_some() =>
+                {
+                    log::warn!(
+                        "{} has been trimmed past {:?}; reading it from TRIM_HORIZON: {}",
+                        input.shard_id,
+                        input.sequence_number,
+                        msg
+                    );
+                    input.shard_iterator_type = "TRIM_HORIZON".to_string();
+                    input.sequence_number = None;
+                    self.call("GetShardIterator", &input).await
+                }
+                result => result,
+            }
+            .map_err(|err| {
+                map_error(err, |typ, msg| match typ.as_str() {
+                    "LimitExceededException" => {
+                        GetShardIteratorError::ProvisionedThroughputExceeded(msg)
+                    }
+                    "ResourceNotFoundException" => GetShardIteratorError::ResourceNotFound(msg),
+                    _ => GetShardIteratorError::InvalidArgument(msg),
+                })
+            })?;
+        Ok(GetShardIteratorOutput {
+            shard_iterator: output.shard_iterator,
+        })
+    }
+
+    async fn get_records(
+        &self,
+        input: GetRecordsInput,
+    ) -> Result<GetRecordsOutput, RusotoError<GetRecordsError>> {
+        let input = GetStreamRecordsInput {
+            shard_iterator: input.shard_iterator,
+            limit: input.limit.map(|limit| limit.min(MAX_RECORDS)),
+        };
+        let output: GetStreamRecordsOutput =
+            self.call("GetRecords", &input).await.map_err(|err| {
+                map_error(err, |typ, msg| match typ.as_str() {
+                    "LimitExceededException" => GetRecordsError::ProvisionedThroughputExceeded(msg),
+                    "ResourceNotFoundException" => GetRecordsError::ResourceNotFound(msg),
+                    // The records are gone, so the new iterator this asks for starts from the
+                    // oldest record left instead
+                    _ => GetRecordsError::ExpiredIterator(msg),
+                })
+            })?;
+        Ok(GetRecordsOutput {
+            // The API doesn't report children, so the worker syncs shards once the shard ends
+            child_shards: None,
+            millis_behind_latest: None,
+            next_shard_iterator: output.next_shard_iterator,
+            records: output.records.into_iter().map(to_kinesis_record).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusoto_core::credential::StaticProvider;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        interface::{dynamodb::ChangeEventName, record::KinesisClientRecord},
+        util::fake_aws::{self, ScriptedAws},
+    };
+
+    const STREAM_ARN: &str =
+        "arn:aws:dynamodb:us-east-1:123456789012:table/orders/stream/2020-09-13T12:26:40.000";
+
+    fn adapter(script: &ScriptedAws) -> DynamoDbStreamsAdapter {
+        let client = Client::new_with(
+            StaticProvider::new_minimal("fake".into(), "fake".into()),
+            script.fake(),
+        );
+        DynamoDbStreamsAdapter::new(client, Region::UsEast1, STREAM_ARN.to_string())
+    }
+
+    fn list_shards_input(next_token: Option<&str>) -> ListShardsInput {
+        ListShardsInput {
+            next_token: next_token.map(str::to_string),
+            ..ListShardsInput::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_shards_without_hash_key_ranges() {
+        let script = ScriptedAws::default();
+        script.respond(
+            "DescribeStream",
+            (
+                200,
+                json!({
+                    "StreamDescription": {
+                        "LastEvaluatedShardId": "shardId-2",
+                        "Shards": [
+                            {
+                                "ShardId": "shardId-1",
+                                "SequenceNumberRange": {
+                                    "StartingSequenceNumber": "100",
+                                    "EndingSequenceNumber": "200",
+                                },
+                            },
+                            {
+                                "ShardId": "shardId-2",
+                                "ParentShardId": "shardId-1",
+                                "SequenceNumberRange": { "StartingSequenceNumber": "300" },
+                            },
+                        ],
+                        "StreamArn": STREAM_ARN,
+                        "StreamStatus": "ENABLED",
+                        "TableName": "orders",
+                    }
+                }),
+            ),
+        );
+        let output = adapter(&script)
+            .list_shards(list_shards_input(Some("shardId-0")))
+            .await
+            .unwrap();
+
+        // The last shard described pages on to the rest
+        assert_eq!(output.next_token.as_deref(), Some("shardId-2"));
+        assert_eq!(
+            script.requests("DescribeStream"),
+            vec![json!({ "StreamArn": STREAM_ARN, "ExclusiveStartShardId": "shardId-0" })]
+        );
+        let shards = output.shards.unwrap();
+        assert_eq!(shards[0].shard_id, "shardId-1");
+        assert_eq!(shards[0].parent_shard_id, None);
+        assert_eq!(
+            shards[0]
+                .sequence_number_range
+                .ending_sequence_number
+                .as_deref(),
+            Some("200")
+        );
+        assert_eq!(shards[1].parent_shard_id.as_deref(), Some("shardId-1"));
+        assert_eq!(
+            shards[1].sequence_number_range.starting_sequence_number,
+            "300"
+        );
+        assert_eq!(shards[1].sequence_number_range.ending_sequence_number, None);
+        assert!(shards[1].hash_key_range.starting_hash_key.is_empty());
+    }
+
+    #[tokio::test]
+    async fn carries_change_records_as_json() {
+        let script = ScriptedAws::default();
+        script.respond(
+            "GetRecords",
+            (
+                200,
+                json!({
+                    "NextShardIterator": "it-2",
+                    "Records": [{
+                        "eventID": "1",
+                        "eventName": "MODIFY",
+                        "eventSource": "aws:dynamodb",
+                        "awsRegion": "us-east-1",
+                        "dynamodb": {
+                            "ApproximateCreationDateTime": 1_600_000_000.0,
+                            "Keys": { "id": { "S": "order-1" } },
+                            "NewImage": { "id": { "S": "order-1" }, "total": { "N": "12" } },
+                            "OldImage": { "id": { "S": "order-1" }, "total": { "N": "10" } },
+                            "SequenceNumber": "100",
+                            "StreamViewType": "NEW_AND_OLD_IMAGES",
+                        },
+                    }],
+                }),
+            ),
+        );
+        let output = adapter(&script)
+            .get_records(GetRecordsInput {
+                limit: Some(10_000),
+                shard_iterator: "it-1".to_string(),
+            })
+            .await
+            .unwrap();
+
+        // The service's own limit is lower than Kinesis'
+        assert_eq!(
+            script.requests("GetRecords"),
+            vec![json!({ "ShardIterator": "it-1", "Limit": MAX_RECORDS })]
+        );
+        assert_eq!(output.next_shard_iterator.as_deref(), Some("it-2"));
+        assert_eq!(output.child_shards, None);
+        let record = output.records.into_iter().next().unwrap();
+        assert_eq!(record.sequence_number, "100");
+        assert_eq!(record.approximate_arrival_timestamp, Some(1_600_000_000.0));
+
+        let change = KinesisClientRecord::from_record(record)
+            .dynamodb_change()
+            .unwrap();
+        assert_eq!(change.event_name, ChangeEventName::Modify);
+        assert_eq!(change.keys()["id"].s.as_deref(), Some("order-1"));
+        assert_eq!(
+            change.new_image().unwrap()["total"].n.as_deref(),
+            Some("12")
+        );
+        assert_eq!(
+            change.old_image().unwrap()["total"].n.as_deref(),
+            Some("10")
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_errors_onto_their_kinesis_equivalents() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetRecords",
+                fake_aws::error("ExpiredIteratorException", "old"),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("TrimmedDataAccessException", "trimmed"),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("LimitExceededException", "slow"),
+            )
+            .respond("GetRecords", (500, json!({ "message": "Internal error" })))
+            .respond(
+                "GetShardIterator",
+                fake_aws::error("ResourceNotFoundException", "gone"),
+            );
+        let adapter = adapter(&script);
+        let get_records = || {
+            adapter.get_records(GetRecordsInput {
+                limit: None,
+                shard_iterator: "it-1".to_string(),
+            })
+        };
+
+        for _ in 0..2 {
+            assert!(matches!(
+                get_records().await,
+                Err(RusotoError::Service(GetRecordsError::ExpiredIterator(_)))
+            ));
+        }
+        assert!(matches!(
+            get_records().await,
+            Err(RusotoError::Service(
+                GetRecordsError::ProvisionedThroughputExceeded(_)
+            ))
+        ));
+        assert!(matches!(get_records().await, Err(RusotoError::Unknown(_))));
+
+        let iterator_input = |type_: &str| GetShardIteratorInput {
+            shard_id: "shardId-1".to_string(),
+            shard_iterator_type: type_.to_string(),
+            starting_sequence_number: None,
+            stream_name: "orders".to_string(),
+            timestamp: Some(1_600_000_000.0),
+        };
+        assert!(matches!(
+            adapter
+                .get_shard_iterator(iterator_input("TRIM_HORIZON"))
+                .await,
+            Err(RusotoError::Service(
+                GetShardIteratorError::ResourceNotFound(_)
+            ))
+        ));
+        // Never sent, as the service has no such iterator type
+        assert!(matches!(
+            adapter
+                .get_shard_iterator(iterator_input("AT_TIMESTAMP"))
+                .await,
+            Err(RusotoError::Service(
+                GetShardIteratorError::InvalidArgument(_)
+            ))
+        ));
+        assert_eq!(script.requests("GetShardIterator").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_from_trim_horizon_once_the_position_is_trimmed() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                fake_aws::error("TrimmedDataAccessException", "trimmed"),
+            )
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            );
+        let output = adapter(&script)
+            .get_shard_iterator(GetShardIteratorInput {
+                shard_id: "shardId-1".to_string(),
+                shard_iterator_type: "AFTER_SEQUENCE_NUMBER".to_string(),
+                starting_sequence_number: Some("100".to_string()),
+                stream_name: "orders".to_string(),
+                timestamp: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(output.shard_iterator.as_deref(), Some("it-1"));
+        let requests = script.requests("GetShardIterator");
+        assert_eq!(requests[0]["SequenceNumber"], "100");
+        assert_eq!(
+            requests[1],
+            json!({
+                "StreamArn": STREAM_ARN,
+                "ShardId": "shardId-1",
+                "ShardIteratorType": "TRIM_HORIZON",
+            })
+        );
+    }
+}
@@ -1,4 +1,444 @@
-#[derive(Debug, Clone)]
-pub(crate) struct StreamDescriptor {
-    pub(crate) stream_name: String,
+use std::{fmt, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use rusoto_core::{
+    credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials},
+    HttpClient, Region, RusotoError,
+};
+use rusoto_kinesis::{
+    DescribeStreamSummaryError, DescribeStreamSummaryInput, DescribeStreamSummaryOutput,
+    GetRecordsError, GetRecordsInput, GetRecordsOutput, GetShardIteratorError,
+    GetShardIteratorInput, GetShardIteratorOutput, Kinesis, KinesisClient, ListShardsError,
+    ListShardsInput, ListShardsOutput, Shard, StreamDescriptionSummary,
+};
+
+use crate::util::exception::Exception;
+
+pub(crate) mod consumer;
+mod dynamodb_streams;
+
+use dynamodb_streams::DynamoDbStreamsAdapter;
+
+/// The stream an application consumes, identified either by name, in the worker's own account and
+/// region, or by ARN, which may point at another account or region.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamDescriptor {
+    stream_name: String,
+    arn: Option<String>,
+    region: Option<Region>,
+    account_id: Option<String>,
+    dynamodb: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidStreamArn(pub String);
+
+impl fmt::Display for InvalidStreamArn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid stream ARN: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidStreamArn {}
+
+impl StreamDescriptor {
+    pub fn from_name(stream_name: impl Into<String>) -> Self {
+        Self {
+            stream_name: stream_name.into(),
+            arn: None,
+            region: None,
+            account_id: None,
+            dynamodb: false,
+        }
+    }
+
+    /// Parses an ARN of the form `arn:<partition>:kinesis:<region>:<account>:stream/<name>`.
+    pub fn from_arn(arn: &str) -> Result<Self, InvalidStreamArn> {
+        let parts: Vec<&str> = arn.split(':').collect();
+        let (partition, region, account_id, resource) = match parts.as_slice() {
+            ["arn", partition, "kinesis", region, account_id, resource] => {
+                (*partition, *region, *account_id, *resource)
+            }
+            _ => return Err(InvalidStreamArn(arn.to_string())),
+        };
+        let stream_name = match resource.strip_prefix("stream/") {
+            Some(stream_name) if !stream_name.is_empty() && !stream_name.contains('/') => {
+                stream_name
+            }
+            _ => return Err(InvalidStreamArn(arn.to_string())),
+        };
+        if region.is_empty() || account_id.is_empty() {
+            return Err(InvalidStreamArn(arn.to_string()));
+        }
+
+        // Regions newer than rusoto are reached through their standard endpoint
+        let region = Region::from_str(region).unwrap_or_else(|_| Region::Custom {
+            name: region.to_string(),
+            endpoint: match partition {
+                "aws-cn" => format!("https://kinesis.{}.amazonaws.com.cn", region),
+                _ => format!("https://kinesis.{}.amazonaws.com", region),
+            },
+        });
+
+        Ok(Self {
+            stream_name: stream_name.to_string(),
+            arn: Some(arn.to_string()),
+            region: Some(region),
+            account_id: Some(account_id.to_string()),
+            dynamodb: false,
+        })
+    }
+
+    /// Parses the ARN of a table's DynamoDB stream, of the form
+    /// `arn:<partition>:dynamodb:<region>:<account>:table/<table>/stream/<label>`. Its shards are
+    /// leased, read and checkpointed like a Kinesis stream's, except that records can only be
+    /// polled, so the retrieval mode must be `Polling`, and each carries a `ChangeRecord` as JSON.
+    pub fn from_dynamodb_stream_arn(arn: &str) -> Result<Self, InvalidStreamArn> {
+        // Stream labels are timestamps, with colons of their own
+        let parts: Vec<&str> = arn.splitn(6, ':').collect();
+        let (partition, region, account_id, resource) = match parts.as_slice() {
+            ["arn", partition, "dynamodb", region, account_id, resource] => {
+                (*partition, *region, *account_id, *resource)
+            }
+            _ => return Err(InvalidStreamArn(arn.to_string())),
+        };
+        let table_name = match resource.split('/').collect::<Vec<_>>().as_slice() {
+            ["table", table_name, "stream", label]
+                if !table_name.is_empty() && !label.is_empty() =>
+            {
+                table_name.to_string()
+            }
+            _ => return Err(InvalidStreamArn(arn.to_string())),
+        };
+        if region.is_empty() || account_id.is_empty() {
+            return Err(InvalidStreamArn(arn.to_string()));
+        }
+
+        let region = Region::from_str(region).unwrap_or_else(|_| Region::Custom {
+            name: region.to_string(),
+            endpoint: match partition {
+                "aws-cn" => format!("https://streams.dynamodb.{}.amazonaws.com.cn", region),
+                _ => format!("https://streams.dynamodb.{}.amazonaws.com", region),
+            },
+        });
+
+        Ok(Self {
+            stream_name: table_name,
+            arn: Some(arn.to_string()),
+            region: Some(region),
+            account_id: Some(account_id.to_string()),
+            dynamodb: true,
+        })
+    }
+
+    pub fn stream_name(&self) -> &str {
+        &self.stream_name
+    }
+
+    pub fn arn(&self) -> Option<&str> {
+        self.arn.as_deref()
+    }
+
+    pub fn region(&self) -> Option<&Region> {
+        self.region.as_ref()
+    }
+
+    pub fn account_id(&self) -> Option<&str> {
+        self.account_id.as_deref()
+    }
+
+    pub fn is_dynamodb_stream(&self) -> bool {
+        self.dynamodb
+    }
+
+    /// Streams identified by ARN prefix their lease keys with it, so one lease table can't mix up
+    /// shards of same-named streams in different accounts or regions. Streams identified by name
+    /// keep plain shard IDs, which existing lease tables already use.
+    pub(crate) fn lease_key(&self, shard_id: &str) -> String {
+        match &self.arn {
+            Some(arn) => format!("{}:{}", arn, shard_id),
+            None => shard_id.to_string(),
+        }
+    }
+
+    /// Whether a lease in a table shared with other streams belongs to this one. Shard IDs have no
+    /// colons, so plain shard IDs never clash with the keys of streams identified by ARN.
+    pub(crate) fn owns_lease_key(&self, lease_key: &str) -> bool {
+        match &self.arn {
+            Some(arn) => matches!(
+                lease_key
+                    .strip_prefix(arn.as_str())
+                    .and_then(|rest| rest.strip_prefix(':')),
+                Some(shard_id) if !shard_id.contains(':')
+            ),
+            None => !lease_key.contains(':'),
+        }
+    }
+}
+
+/// Lets a shared credentials provider be handed to a rusoto client, which wants to own one.
+struct SharedCredentials(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+#[async_trait]
+impl ProvideAwsCredentials for SharedCredentials {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
+    }
+}
+
+/// Builds a client in the stream's region, or the default region for streams identified by name,
+/// using the given credentials instead of the default chain when there are any.
+pub(crate) fn kinesis_client(
+    stream: &StreamDescriptor,
+    credentials: Option<Arc<dyn ProvideAwsCredentials + Send + Sync>>,
+) -> KinesisClient {
+    let region = stream.region.clone().unwrap_or_default();
+    match credentials {
+        Some(credentials) => KinesisClient::new_with(
+            HttpClient::new().expect("Failed to create the HTTP client"),
+            SharedCredentials(credentials),
+            region,
+        ),
+        None => KinesisClient::new(region),
+    }
+}
+
+/// The calls used to list and poll a stream's shards, so that streams with the same shard model
+/// behind a different API can stand in for a Kinesis stream. Fan-out has no equivalent and always
+/// goes to Kinesis.
+#[async_trait]
+pub(crate) trait ShardApi: Send + Sync {
+    async fn list_shards(
+        &self,
+        input: ListShardsInput,
+    ) -> Result<ListShardsOutput, RusotoError<ListShardsError>>;
+
+    async fn describe_stream_summary(
+        &self,
+        input: DescribeStreamSummaryInput,
+    ) -> Result<DescribeStreamSummaryOutput, RusotoError<DescribeStreamSummaryError>>;
+
+    async fn get_shard_iterator(
+        &self,
+        input: GetShardIteratorInput,
+    ) -> Result<GetShardIteratorOutput, RusotoError<GetShardIteratorError>>;
+
+    async fn get_records(
+        &self,
+        input: GetRecordsInput,
+    ) -> Result<GetRecordsOutput, RusotoError<GetRecordsError>>;
+}
+
+#[async_trait]
+impl ShardApi for KinesisClient {
+    async fn list_shards(
+        &self,
+        input: ListShardsInput,
+    ) -> Result<ListShardsOutput, RusotoError<ListShardsError>> {
+        Kinesis::list_shards(self, input).await
+    }
+
+    async fn describe_stream_summary(
+        &self,
+        input: DescribeStreamSummaryInput,
+    ) -> Result<DescribeStreamSummaryOutput, RusotoError<DescribeStreamSummaryError>> {
+        Kinesis::describe_stream_summary(self, input).await
+    }
+
+    async fn get_shard_iterator(
+        &self,
+        input: GetShardIteratorInput,
+    ) -> Result<GetShardIteratorOutput, RusotoError<GetShardIteratorError>> {
+        Kinesis::get_shard_iterator(self, input).await
+    }
+
+    async fn get_records(
+        &self,
+        input: GetRecordsInput,
+    ) -> Result<GetRecordsOutput, RusotoError<GetRecordsError>> {
+        Kinesis::get_records(self, input).await
+    }
+}
+
+/// The Kinesis client itself, or an adapter for a DynamoDB stream.
+pub(crate) fn shard_api(
+    stream: &StreamDescriptor,
+    kinesis: Arc<KinesisClient>,
+    credentials: Option<Arc<dyn ProvideAwsCredentials + Send + Sync>>,
+) -> Arc<dyn ShardApi> {
+    if !stream.dynamodb {
+        return kinesis;
+    }
+    let region = stream.region.clone().unwrap_or_default();
+    let stream_arn = stream.arn.clone().unwrap_or_default();
+    let client = match credentials {
+        Some(credentials) => rusoto_core::Client::new_with(
+            SharedCredentials(credentials),
+            HttpClient::new().expect("Failed to create the HTTP client"),
+        ),
+        None => rusoto_core::Client::shared(),
+    };
+    Arc::new(DynamoDbStreamsAdapter::new(client, region, stream_arn))
+}
+
+/// Lists every shard in the stream, following pagination tokens until they run out.
+pub(crate) async fn list_shards(
+    kinesis: &dyn ShardApi,
+    stream: &StreamDescriptor,
+) -> Result<Vec<Shard>, Exception> {
+    let mut all_shards = Vec::new();
+    let mut next_token: Option<String> = None;
+    loop {
+        // The stream name and the token are mutually exclusive
+        let stream_name = match next_token {
+            Some(_) => None,
+            None => Some(stream.stream_name().to_string()),
+        };
+        let input = ListShardsInput {
+            exclusive_start_shard_id: None,
+            max_results: None,
+            next_token: next_token.take(),
+            shard_filter: None,
+            stream_creation_timestamp: None,
+            stream_name,
+        };
+
+        match kinesis.list_shards(input).await {
+            Ok(res) => {
+                if let Some(mut shards) = res.shards {
+                    all_shards.append(&mut shards);
+                }
+                next_token = res.next_token;
+                if next_token.is_none() {
+                    break;
+                }
+            }
+            Err(RusotoError::Service(ListShardsError::LimitExceeded(msg)))
+            | Err(RusotoError::Service(ListShardsError::ExpiredNextToken(msg)))
+            | Err(RusotoError::Service(ListShardsError::ResourceInUse(msg))) => {
+                return Err(Exception::Retryable(msg))
+            }
+            Err(RusotoError::HttpDispatch(err)) => {
+                return Err(Exception::Retryable(err.to_string()))
+            }
+            Err(err) => return Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
+
+    Ok(all_shards)
+}
+
+pub(crate) async fn describe_stream_summary(
+    kinesis: &dyn ShardApi,
+    stream: &StreamDescriptor,
+) -> Result<StreamDescriptionSummary, Exception> {
+    let input = DescribeStreamSummaryInput {
+        stream_name: stream.stream_name().to_string(),
+    };
+    match kinesis.describe_stream_summary(input).await {
+        Ok(res) => Ok(res.stream_description_summary),
+        Err(RusotoError::Service(DescribeStreamSummaryError::LimitExceeded(msg))) => {
+            Err(Exception::Retryable(msg))
+        }
+        Err(RusotoError::HttpDispatch(err)) => Err(Exception::Retryable(err.to_string())),
+        Err(err) => Err(Exception::NonRetryable(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM_ARN: &str = "arn:aws:kinesis:us-east-1:123456789012:stream/orders";
+
+    #[test]
+    fn parses_stream_arns() {
+        let stream = StreamDescriptor::from_arn(STREAM_ARN).unwrap();
+        assert_eq!(stream.stream_name(), "orders");
+        assert_eq!(stream.arn(), Some(STREAM_ARN));
+        assert_eq!(stream.region(), Some(&Region::UsEast1));
+        assert_eq!(stream.account_id(), Some("123456789012"));
+        assert!(!stream.is_dynamodb_stream());
+    }
+
+    #[test]
+    fn parses_dynamodb_stream_arns() {
+        let arn =
+            "arn:aws:dynamodb:us-east-1:123456789012:table/orders/stream/2020-09-13T12:26:40.000";
+        let stream = StreamDescriptor::from_dynamodb_stream_arn(arn).unwrap();
+        assert!(stream.is_dynamodb_stream());
+        assert_eq!(stream.stream_name(), "orders");
+        assert_eq!(stream.region(), Some(&Region::UsEast1));
+        assert_eq!(stream.lease_key("shardId-1"), format!("{}:shardId-1", arn));
+
+        for arn in [
+            STREAM_ARN,
+            "arn:aws:dynamodb:us-east-1:123456789012:table/orders",
+            "arn:aws:dynamodb:us-east-1:123456789012:table//stream/label",
+        ] {
+            assert_eq!(
+                StreamDescriptor::from_dynamodb_stream_arn(arn),
+                Err(InvalidStreamArn(arn.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn reaches_unknown_regions_through_their_standard_endpoint() {
+        let stream =
+            StreamDescriptor::from_arn("arn:aws-cn:kinesis:cn-far-1:123456789012:stream/orders")
+                .unwrap();
+        assert_eq!(
+            stream.region(),
+            Some(&Region::Custom {
+                name: "cn-far-1".to_string(),
+                endpoint: "https://kinesis.cn-far-1.amazonaws.com.cn".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_arns() {
+        for arn in [
+            "orders",
+            "arn:aws:kinesis:us-east-1:123456789012:orders",
+            "arn:aws:kinesis:us-east-1:123456789012:stream/",
+            "arn:aws:kinesis:us-east-1:123456789012:stream/a/b",
+            "arn:aws:kinesis::123456789012:stream/orders",
+            "arn:aws:kinesis:us-east-1::stream/orders",
+            "arn:aws:sqs:us-east-1:123456789012:stream/orders",
+        ] {
+            assert_eq!(
+                StreamDescriptor::from_arn(arn),
+                Err(InvalidStreamArn(arn.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn keys_leases_by_arn() {
+        let stream = StreamDescriptor::from_arn(STREAM_ARN).unwrap();
+        let lease_key = stream.lease_key("shardId-000000000001");
+        assert_eq!(lease_key, format!("{}:shardId-000000000001", STREAM_ARN));
+        assert!(stream.owns_lease_key(&lease_key));
+
+        // Same-named streams in other regions and accounts, and streams given by name
+        let other_region =
+            StreamDescriptor::from_arn("arn:aws:kinesis:eu-west-1:123456789012:stream/orders")
+                .unwrap();
+        assert!(!stream.owns_lease_key(&other_region.lease_key("shardId-000000000001")));
+        assert!(!stream.owns_lease_key("shardId-000000000001"));
+        assert!(!stream.owns_lease_key(&format!("{}-v2:shardId-000000000001", STREAM_ARN)));
+    }
+
+    #[test]
+    fn keys_leases_of_named_streams_by_shard_id() {
+        let stream = StreamDescriptor::from_name("orders");
+        assert_eq!(
+            stream.lease_key("shardId-000000000001"),
+            "shardId-000000000001"
+        );
+        assert!(stream.owns_lease_key("shardId-000000000001"));
+        assert!(!stream.owns_lease_key(&format!("{}:shardId-000000000001", STREAM_ARN)));
+    }
 }
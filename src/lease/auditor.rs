@@ -0,0 +1,334 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures_retry::FutureRetry;
+use tokio::sync::RwLock;
+
+use crate::{
+    checkpoint::Checkpoint,
+    config::ShardFilter,
+    kinesis::StreamDescriptor,
+    status::{HashKeyRange, HashRangeAuditReport},
+    util::{exception::Exception, retry::FixedCountWithDelayStrategy, runnable::PeriodicRunnable},
+};
+
+use super::{broker::LeaseBroker, syncer::ShardSyncer, Lease};
+
+/// Checks that the open leases cover the consumed hash key space exactly once, and resyncs
+/// the stream's shards when part of it isn't covered.
+///
+/// A lease is open when its shard isn't finished and no other lease names it as a parent. Parents
+/// that are still being processed overlap their children, so they're left out.
+pub(crate) struct HashRangeAuditor {
+    stream: StreamDescriptor,
+    lease_broker: Arc<LeaseBroker>,
+    shard_syncer: Arc<ShardSyncer>,
+    shard_filter: ShardFilter,
+    last_report: RwLock<Option<HashRangeAuditReport>>,
+}
+
+impl HashRangeAuditor {
+    pub(crate) fn new(
+        stream: StreamDescriptor,
+        lease_broker: Arc<LeaseBroker>,
+        shard_syncer: Arc<ShardSyncer>,
+        shard_filter: ShardFilter,
+    ) -> Self {
+        Self {
+            stream,
+            lease_broker,
+            shard_syncer,
+            shard_filter,
+            last_report: RwLock::new(None),
+        }
+    }
+
+    pub(crate) async fn last_report(&self) -> Option<HashRangeAuditReport> {
+        self.last_report.read().await.clone()
+    }
+
+    pub(crate) async fn audit(&self) -> Result<HashRangeAuditReport, Exception> {
+        let (shared_leases, _) = FutureRetry::new(
+            move || self.lease_broker.list_all_leases(),
+            FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+        )
+        .await
+        .map_err(|(ex, _)| ex)?;
+        // Other streams sharing the table have hash key spaces of their own
+        let mut leases = Vec::new();
+        for lease in shared_leases {
+            let lease = lease.read().await;
+            if self.stream.owns_lease_key(&lease.lease_key) {
+                leases.push(lease.clone());
+            }
+        }
+
+        let bounds = match &self.shard_filter {
+            ShardFilter::All => Some(HashKeyRange {
+                start: 0,
+                end: u128::MAX,
+            }),
+            ShardFilter::HashKeyRange(range) => Some(*range),
+            ShardFilter::ShardIds(_) | ShardFilter::Predicate(_) => None,
+        };
+        let mut report = check_coverage(&leases, bounds);
+        if !report.gaps.is_empty() {
+            report.resync_created_leases = Some(self.shard_syncer.sync_shards().await?);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Checks the open leases for overlaps and, within `bounds`, for gaps. Without bounds, which part
+/// of the hash key space is consumed isn't known, so gaps can't be told apart from filtered shards.
+fn check_coverage(leases: &[Lease], bounds: Option<HashKeyRange>) -> HashRangeAuditReport {
+    let mut report = HashRangeAuditReport::default();
+    let mut open_ranges = Vec::new();
+    for lease in leases.iter() {
+        let is_finished = matches!(
+            lease
+                .checkpoint
+                .as_deref()
+                .and_then(Checkpoint::from_lease_value),
+            Some(Checkpoint::ShardEnd)
+        );
+        let is_parent = leases.iter().any(|other| {
+            other
+                .parent_shard_ids
+                .iter()
+                .any(|parent| parent == lease.shard_id())
+        });
+        if is_finished || is_parent {
+            continue;
+        }
+        match lease.hash_key_range() {
+            Some(range) => open_ranges.push((range, lease.lease_key.as_str())),
+            None => report.leases_without_range.push(lease.lease_key.clone()),
+        }
+    }
+    open_ranges.sort_by_key(|(range, _)| (range.start, range.end));
+    // A lease without a range could be covering any part of the space, such as every shard of a
+    // DynamoDB stream
+    let bounds = bounds.filter(|_| report.leases_without_range.is_empty());
+    report.gaps_checked = bounds.is_some();
+
+    // The first hash key not yet covered, or `None` once everything up to the last key is
+    let mut next_uncovered = Some(bounds.map_or(0, |bounds| bounds.start));
+    let mut furthest_lease: Option<&str> = None;
+    for (range, lease_key) in open_ranges.iter() {
+        match next_uncovered {
+            Some(next) if range.start > next => {
+                if let Some(bounds) = bounds.filter(|bounds| next <= bounds.end) {
+                    report.gaps.push(HashKeyRange {
+                        start: next,
+                        end: (range.start - 1).min(bounds.end),
+                    });
+                }
+            }
+            Some(next) if range.start == next => {}
+            _ => {
+                if let Some(furthest_lease) = furthest_lease {
+                    report
+                        .overlaps
+                        .push((furthest_lease.to_string(), lease_key.to_string()));
+                }
+            }
+        }
+
+        let extends_coverage = match next_uncovered {
+            Some(next) => range.end >= next,
+            None => false,
+        };
+        if extends_coverage {
+            next_uncovered = range.end.checked_add(1);
+            furthest_lease = Some(lease_key);
+        }
+    }
+    if let (Some(next), Some(bounds)) = (next_uncovered, bounds) {
+        if next <= bounds.end {
+            report.gaps.push(HashKeyRange {
+                start: next,
+                end: bounds.end,
+            });
+        }
+    }
+
+    report
+}
+
+#[async_trait]
+impl PeriodicRunnable for HashRangeAuditor {
+    async fn run_once(&self) {
+        // A failed audit is picked up again on the next run
+        if let Ok(report) = self.audit().await {
+            if !report.is_consistent() {
+                log::warn!(
+                    "The open leases of {} have {} hash key range gaps and {} overlaps",
+                    self.stream.stream_name(),
+                    report.gaps.len(),
+                    report.overlaps.len()
+                );
+            }
+            *self.last_report.write().await = Some(report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{
+        config::StreamRecreationPolicy,
+        kinesis::StreamDescriptor,
+        util::fake_aws::{self, FakeLeaseTable, ScriptedAws},
+    };
+
+    use super::*;
+
+    fn lease(shard_id: &str, start: u128, end: u128) -> Lease {
+        Lease::new(
+            shard_id.to_string(),
+            Checkpoint::TrimHorizon.to_lease_value(),
+            Vec::new(),
+            start.to_string(),
+            end.to_string(),
+        )
+    }
+
+    fn range(start: u128, end: u128) -> HashKeyRange {
+        HashKeyRange { start, end }
+    }
+
+    const WHOLE_SPACE: Option<HashKeyRange> = Some(HashKeyRange {
+        start: 0,
+        end: u128::MAX,
+    });
+
+    #[test]
+    fn accepts_leases_covering_the_whole_space() {
+        let leases = vec![lease("shard-1", 0, 99), lease("shard-2", 100, u128::MAX)];
+        let report = check_coverage(&leases, WHOLE_SPACE);
+        assert!(report.is_consistent());
+        assert!(report.gaps_checked);
+        assert!(report.leases_without_range.is_empty());
+    }
+
+    #[test]
+    fn reports_gaps() {
+        let leases = vec![lease("shard-1", 10, 99), lease("shard-2", 200, 299)];
+        let report = check_coverage(&leases, WHOLE_SPACE);
+        assert_eq!(
+            report.gaps,
+            vec![range(0, 9), range(100, 199), range(300, u128::MAX)]
+        );
+        assert!(report.overlaps.is_empty());
+    }
+
+    #[test]
+    fn reports_overlaps() {
+        let leases = vec![lease("shard-1", 0, 150), lease("shard-2", 100, u128::MAX)];
+        let report = check_coverage(&leases, WHOLE_SPACE);
+        assert!(report.gaps.is_empty());
+        assert_eq!(
+            report.overlaps,
+            vec![("shard-1".to_string(), "shard-2".to_string())]
+        );
+    }
+
+    #[test]
+    fn leaves_out_finished_shards_and_parents() {
+        let finished = Lease {
+            checkpoint: Some(Checkpoint::ShardEnd.to_lease_value()),
+            ..lease("shard-0", 0, u128::MAX)
+        };
+        let parent = lease("shard-1", 0, u128::MAX);
+        let children = vec![
+            Lease {
+                parent_shard_ids: vec!["shard-1".to_string()],
+                ..lease("shard-2", 0, 99)
+            },
+            Lease {
+                parent_shard_ids: vec!["shard-1".to_string()],
+                ..lease("shard-3", 100, u128::MAX)
+            },
+        ];
+        let mut leases = vec![finished, parent];
+        leases.extend(children);
+        assert!(check_coverage(&leases, WHOLE_SPACE).is_consistent());
+    }
+
+    #[test]
+    fn only_reports_gaps_within_bounds() {
+        let leases = vec![lease("shard-1", 100, 199)];
+        let report = check_coverage(&leases, Some(range(50, 299)));
+        assert_eq!(report.gaps, vec![range(50, 99), range(200, 299)]);
+
+        // Without bounds, which keys are consumed isn't known
+        let report = check_coverage(&leases, None);
+        assert!(report.gaps.is_empty());
+        assert!(!report.gaps_checked);
+    }
+
+    #[test]
+    fn lists_leases_without_a_range() {
+        let unranged = Lease {
+            starting_hash_key: None,
+            ending_hash_key: None,
+            ..lease("shard-2", 0, 0)
+        };
+        let leases = vec![lease("shard-1", 0, u128::MAX), unranged];
+        let report = check_coverage(&leases, WHOLE_SPACE);
+        assert!(report.is_consistent());
+        assert!(!report.gaps_checked);
+        assert_eq!(report.leases_without_range, vec!["shard-2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn resyncs_shards_when_there_are_gaps() {
+        let table = FakeLeaseTable::default();
+        table.put(&lease("shard-1", 0, 99));
+        let script = ScriptedAws::default();
+        script.respond(
+            "DescribeStreamSummary",
+            fake_aws::stream_summary("test", 1_600_000_000.0),
+        );
+        script.respond(
+            "ListShards",
+            (
+                200,
+                json!({
+                    "Shards": [{
+                        "ShardId": "shard-2",
+                        "HashKeyRange": {
+                            "StartingHashKey": "100",
+                            "EndingHashKey": u128::MAX.to_string(),
+                        },
+                        "SequenceNumberRange": { "StartingSequenceNumber": "0" },
+                    }],
+                }),
+            ),
+        );
+        let lease_broker = Arc::new(LeaseBroker::new(table.client(), "leases".to_string()));
+        let shard_syncer = Arc::new(ShardSyncer::new(
+            StreamDescriptor::from_name("test"),
+            fake_aws::polled_stream(&script, StreamDescriptor::from_name("test")),
+            lease_broker.clone(),
+            Checkpoint::TrimHorizon,
+            ShardFilter::All,
+            StreamRecreationPolicy::default(),
+        ));
+        let auditor = HashRangeAuditor::new(
+            StreamDescriptor::from_name("test"),
+            lease_broker,
+            shard_syncer,
+            ShardFilter::All,
+        );
+
+        let report = auditor.audit().await.unwrap();
+        assert_eq!(report.gaps, vec![range(100, u128::MAX)]);
+        assert_eq!(report.resync_created_leases, Some(1));
+        assert_eq!(table.lease_keys(), vec!["shard-1", "shard-2"]);
+    }
+}
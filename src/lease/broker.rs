@@ -1,30 +1,73 @@
 use dynomite::AttributeError::{self, MissingField};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use dynomite::{
-    dynamodb::{DynamoDb, DynamoDbClient, ScanInput},
+    attr_map,
+    dynamodb::{
+        AttributeValue, DeleteItemError, DeleteItemInput, DynamoDb, DynamoDbClient, GetItemError,
+        GetItemInput, PutItemError, PutItemInput, ScanError, ScanInput, UpdateItemError,
+        UpdateItemInput,
+    },
     FromAttributes,
 };
+use rusoto_core_dynamodb::RusotoError;
 use tokio::sync::RwLock;
 
 use crate::{lease::Lease, util::exception::Exception};
 
 use super::SharedLease;
 
-static LEASE_TABLE: &str = "lease_table";
+fn parse_lease(attr_item: HashMap<String, AttributeValue>) -> Result<Lease, Exception> {
+    Lease::from_attrs(attr_item).map_err(|err| match err {
+        AttributeError::InvalidFormat => {
+            Exception::NonRetryable("Attribute contains an invalid format".to_string())
+        }
+        AttributeError::InvalidType => {
+            Exception::NonRetryable("Attribute contains invalid type".to_string())
+        }
+        MissingField { name } => {
+            Exception::NonRetryable(format!("Attribute '{}' was missing", name))
+        }
+    })
+}
 
 pub(crate) struct LeaseBroker {
     dynamo_client: DynamoDbClient,
+    table_name: String,
 }
 
 impl LeaseBroker {
+    pub(crate) fn new(dynamo_client: DynamoDbClient, table_name: String) -> Self {
+        Self {
+            dynamo_client,
+            table_name,
+        }
+    }
+
     pub(crate) async fn list_all_leases(&self) -> Result<Vec<SharedLease>, Exception> {
-        // TODO: Paging
+        let mut all_leases = Vec::new();
+        let mut exclusive_start_key = None;
+        loop {
+            let (mut page, last_evaluated_key) = self.list_leases_page(exclusive_start_key).await?;
+            all_leases.append(&mut page);
+            if last_evaluated_key.is_none() {
+                break;
+            }
+            exclusive_start_key = last_evaluated_key;
+        }
+
+        Ok(all_leases)
+    }
+
+    async fn list_leases_page(
+        &self,
+        exclusive_start_key: Option<HashMap<String, AttributeValue>>,
+    ) -> Result<(Vec<SharedLease>, Option<HashMap<String, AttributeValue>>), Exception> {
         let input = ScanInput {
             attributes_to_get: None,
             conditional_operator: None,
             consistent_read: Some(true),
-            exclusive_start_key: None,
+            exclusive_start_key,
             expression_attribute_names: None,
             expression_attribute_values: None,
             filter_expression: None,
@@ -35,47 +78,54 @@ impl LeaseBroker {
             scan_filter: None,
             segment: None,
             select: None,
-            table_name: LEASE_TABLE.to_string(),
+            table_name: self.table_name.clone(),
             total_segments: None,
         };
 
-        let mut all_leases = Vec::new();
+        let mut leases = Vec::new();
         match self.dynamo_client.scan(input).await {
             Ok(res) => {
                 if let Some(items) = res.items {
                     for attr_item in items {
-                        match Lease::from_attrs(attr_item) {
-                            Ok(lease) => {
-                                all_leases.push(Arc::new(RwLock::new(lease)));
-                            }
-                            Err(err) => match err {
-                                AttributeError::InvalidFormat => {
-                                    return Err(Exception::NonRetryable(
-                                        "Attribute contains an invalid format".to_string(),
-                                    ))
-                                }
-                                AttributeError::InvalidType => {
-                                    return Err(Exception::NonRetryable(
-                                        "Attribute contains invalid type".to_string(),
-                                    ))
-                                }
-                                MissingField { name } => {
-                                    return Err(Exception::NonRetryable(format!(
-                                        "Attribute '{}' was missing",
-                                        name
-                                    )))
-                                }
-                            },
-                        }
+                        leases.push(Arc::new(RwLock::new(parse_lease(attr_item)?)));
                     }
                 }
+                Ok((leases, res.last_evaluated_key))
             }
-            Err(_err) => {
-                todo!("Handle dynamo errors")
+            Err(RusotoError::Service(ScanError::ProvisionedThroughputExceeded(msg)))
+            | Err(RusotoError::Service(ScanError::RequestLimitExceeded(msg)))
+            | Err(RusotoError::Service(ScanError::InternalServerError(msg))) => {
+                Err(Exception::Retryable(msg))
             }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
         }
+    }
 
-        Ok(all_leases)
+    pub(crate) async fn get_lease(&self, lease_key: &str) -> Result<Option<Lease>, Exception> {
+        let input = GetItemInput {
+            attributes_to_get: None,
+            consistent_read: Some(true),
+            expression_attribute_names: None,
+            key: attr_map! {
+                "lease_key" => lease_key.to_string(),
+            },
+            projection_expression: None,
+            return_consumed_capacity: None,
+            table_name: self.table_name.clone(),
+        };
+
+        match self.dynamo_client.get_item(input).await {
+            Ok(res) => match res.item {
+                Some(attr_item) => Ok(Some(parse_lease(attr_item)?)),
+                None => Ok(None),
+            },
+            Err(RusotoError::Service(GetItemError::ProvisionedThroughputExceeded(msg)))
+            | Err(RusotoError::Service(GetItemError::RequestLimitExceeded(msg)))
+            | Err(RusotoError::Service(GetItemError::InternalServerError(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
     }
 
     pub(crate) async fn take_lease(&self, _lease: SharedLease, _worker: &str) -> bool {
@@ -85,4 +135,221 @@ impl LeaseBroker {
     pub(crate) async fn renew_lease(&self, _lease: SharedLease) -> bool {
         todo!()
     }
+
+    /// Writes a checkpoint to the lease, returning `false` if we no longer own it.
+    pub(crate) async fn update_checkpoint(
+        &self,
+        lease: SharedLease,
+        checkpoint: &str,
+    ) -> Result<bool, Exception> {
+        let mut lease_guard = lease.write().await;
+        let owner = match &lease_guard.lease_owner {
+            Some(owner) => owner.clone(),
+            None => return Ok(false),
+        };
+        let updated = self
+            .conditional_update(
+                &lease_guard,
+                "SET checkpoint = :checkpoint, lease_counter = lease_counter + :one",
+                "lease_owner = :owner AND lease_counter = :counter",
+                attr_map! {
+                    ":owner" => owner,
+                    ":checkpoint" => checkpoint.to_string(),
+                },
+            )
+            .await?;
+        if updated {
+            lease_guard.lease_counter += 1;
+            lease_guard.checkpoint = Some(checkpoint.to_string());
+        }
+        Ok(updated)
+    }
+
+    /// Overwrites the checkpoint of a lease nobody owns, returning `false` if the lease changed
+    /// since it was read.
+    pub(crate) async fn reset_checkpoint(
+        &self,
+        lease: SharedLease,
+        checkpoint: &str,
+    ) -> Result<bool, Exception> {
+        let mut lease_guard = lease.write().await;
+        if lease_guard.lease_owner.is_some() {
+            return Ok(false);
+        }
+        let updated = self
+            .conditional_update(
+                &lease_guard,
+                "SET checkpoint = :checkpoint, lease_counter = lease_counter + :one",
+                "lease_counter = :counter",
+                attr_map! {
+                    ":checkpoint" => checkpoint.to_string(),
+                },
+            )
+            .await?;
+        if updated {
+            lease_guard.lease_counter += 1;
+            lease_guard.checkpoint = Some(checkpoint.to_string());
+        }
+        Ok(updated)
+    }
+
+    /// Clears the owner of a lease so that its holder's next renewal fails.
+    pub(crate) async fn evict_lease(&self, lease: SharedLease) -> Result<bool, Exception> {
+        let mut lease_guard = lease.write().await;
+        let updated = self
+            .conditional_update(
+                &lease_guard,
+                "SET lease_owner = :no_owner, lease_counter = lease_counter + :one",
+                "lease_counter = :counter",
+                attr_map! {
+                    ":no_owner" => Option::<String>::None,
+                },
+            )
+            .await?;
+        if updated {
+            lease_guard.lease_counter += 1;
+            lease_guard.lease_owner = None;
+        }
+        Ok(updated)
+    }
+
+    /// Records which stream a lease from before creation times were kept belongs to, returning
+    /// `false` if the lease changed since it was read. The lease counter is left alone, so the
+    /// lease's holder isn't turned away at its next checkpoint.
+    pub(crate) async fn set_stream_creation_millis(
+        &self,
+        lease: SharedLease,
+        stream_creation_millis: u64,
+    ) -> Result<bool, Exception> {
+        let mut lease_guard = lease.write().await;
+        let updated = self
+            .conditional_update(
+                &lease_guard,
+                "SET stream_creation_millis = :created",
+                "lease_counter = :counter",
+                attr_map! {
+                    ":created" => stream_creation_millis,
+                },
+            )
+            .await?;
+        if updated {
+            lease_guard.stream_creation_millis = Some(stream_creation_millis);
+        }
+        Ok(updated)
+    }
+
+    /// Creates the lease unless one already exists with the same key.
+    pub(crate) async fn create_lease_if_not_exists(
+        &self,
+        lease: &Lease,
+    ) -> Result<bool, Exception> {
+        let input = PutItemInput {
+            condition_expression: Some("attribute_not_exists(lease_key)".to_string()),
+            conditional_operator: None,
+            expected: None,
+            expression_attribute_names: None,
+            expression_attribute_values: None,
+            item: lease.clone().into(),
+            return_consumed_capacity: None,
+            return_item_collection_metrics: None,
+            return_values: None,
+            table_name: self.table_name.clone(),
+        };
+
+        match self.dynamo_client.put_item(input).await {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(RusotoError::Service(PutItemError::ProvisionedThroughputExceeded(msg)))
+            | Err(RusotoError::Service(PutItemError::RequestLimitExceeded(msg)))
+            | Err(RusotoError::Service(PutItemError::InternalServerError(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
+
+    /// Deletes the lease, optionally only if its checkpoint hasn't moved. Returns `false` if the
+    /// condition didn't hold.
+    pub(crate) async fn delete_lease(
+        &self,
+        lease_key: &str,
+        expected_checkpoint: Option<&str>,
+    ) -> Result<bool, Exception> {
+        let (condition_expression, expression_attribute_values) = match expected_checkpoint {
+            Some(checkpoint) => (
+                Some("checkpoint = :checkpoint".to_string()),
+                Some(attr_map! {
+                    ":checkpoint" => checkpoint.to_string(),
+                }),
+            ),
+            None => (None, None),
+        };
+        let input = DeleteItemInput {
+            condition_expression,
+            conditional_operator: None,
+            expected: None,
+            expression_attribute_names: None,
+            expression_attribute_values,
+            key: attr_map! {
+                "lease_key" => lease_key.to_string(),
+            },
+            return_consumed_capacity: None,
+            return_item_collection_metrics: None,
+            return_values: None,
+            table_name: self.table_name.clone(),
+        };
+
+        match self.dynamo_client.delete_item(input).await {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(DeleteItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(RusotoError::Service(DeleteItemError::ProvisionedThroughputExceeded(msg)))
+            | Err(RusotoError::Service(DeleteItemError::RequestLimitExceeded(msg)))
+            | Err(RusotoError::Service(DeleteItemError::InternalServerError(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
+
+    /// Applies an update to the lease as we last saw it. `:counter` and `:one` are provided for
+    /// the expressions to use.
+    async fn conditional_update(
+        &self,
+        lease: &Lease,
+        update_expression: &str,
+        condition_expression: &str,
+        mut values: HashMap<String, AttributeValue>,
+    ) -> Result<bool, Exception> {
+        values.extend(attr_map! {
+            ":counter" => lease.lease_counter,
+            ":one" => 1_u64,
+        });
+        let input = UpdateItemInput {
+            attribute_updates: None,
+            condition_expression: Some(condition_expression.to_string()),
+            conditional_operator: None,
+            expected: None,
+            expression_attribute_names: None,
+            expression_attribute_values: Some(values),
+            key: attr_map! {
+                "lease_key" => lease.lease_key.clone(),
+            },
+            return_consumed_capacity: None,
+            return_item_collection_metrics: None,
+            return_values: None,
+            table_name: self.table_name.clone(),
+            update_expression: Some(update_expression.to_string()),
+        };
+
+        match self.dynamo_client.update_item(input).await {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(RusotoError::Service(UpdateItemError::ProvisionedThroughputExceeded(msg)))
+            | Err(RusotoError::Service(UpdateItemError::RequestLimitExceeded(msg)))
+            | Err(RusotoError::Service(UpdateItemError::InternalServerError(msg))) => {
+                Err(Exception::Retryable(msg))
+            }
+            Err(err) => Err(Exception::NonRetryable(err.to_string())),
+        }
+    }
 }
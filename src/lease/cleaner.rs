@@ -0,0 +1,388 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_retry::FutureRetry;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    checkpoint::Checkpoint,
+    config::LeaseCleanupConfig,
+    kinesis::StreamDescriptor,
+    source::RecordSource,
+    status::LeaseCleanupReport,
+    util::{exception::Exception, retry::FixedCountWithDelayStrategy, runnable::PeriodicRunnable},
+};
+
+use super::{broker::LeaseBroker, Lease};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CleanupReason {
+    Completed,
+    Trimmed,
+}
+
+/// Removes leases for shards that were finished and whose children have all started, and for
+/// shards that have aged out of the stream.
+pub(crate) struct LeaseCleaner {
+    stream: StreamDescriptor,
+    source: Arc<dyn RecordSource>,
+    lease_broker: Arc<LeaseBroker>,
+    config: LeaseCleanupConfig,
+    eligible_since: Mutex<HashMap<String, (CleanupReason, Instant)>>,
+    last_report: RwLock<Option<LeaseCleanupReport>>,
+}
+
+impl LeaseCleaner {
+    pub(crate) fn new(
+        stream: StreamDescriptor,
+        source: Arc<dyn RecordSource>,
+        lease_broker: Arc<LeaseBroker>,
+        config: LeaseCleanupConfig,
+    ) -> Self {
+        Self {
+            stream,
+            source,
+            lease_broker,
+            config,
+            eligible_since: Mutex::new(HashMap::new()),
+            last_report: RwLock::new(None),
+        }
+    }
+
+    pub(crate) async fn last_report(&self) -> Option<LeaseCleanupReport> {
+        self.last_report.read().await.clone()
+    }
+
+    pub(crate) async fn clean_up(&self) -> Result<LeaseCleanupReport, Exception> {
+        let (shards, _) = FutureRetry::new(
+            move || async move { Ok(self.source.list_shards().await?) },
+            FixedCountWithDelayStrategy::new(3, Duration::from_secs(1)),
+        )
+        .await
+        .map_err(|(ex, _)| ex)?;
+        let (shared_leases, _) = FutureRetry::new(
+            move || self.lease_broker.list_all_leases(),
+            FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+        )
+        .await
+        .map_err(|(ex, _)| ex)?;
+
+        // The table may be shared with other streams, whose leases aren't ours to judge
+        let mut leases = Vec::new();
+        for lease in shared_leases {
+            let lease = lease.read().await;
+            if self.stream.owns_lease_key(&lease.lease_key) {
+                leases.push(lease.clone());
+            }
+        }
+        let listed_shards: HashSet<&str> =
+            shards.iter().map(|shard| shard.shard_id.as_str()).collect();
+        let mut children: HashMap<&str, Vec<&Lease>> = HashMap::new();
+        for lease in leases.iter() {
+            for parent in lease.parent_shard_ids.iter() {
+                children.entry(parent.as_str()).or_default().push(lease);
+            }
+        }
+
+        let now = Instant::now();
+        let mut report = LeaseCleanupReport {
+            dry_run: self.config.dry_run,
+            ..LeaseCleanupReport::default()
+        };
+        let mut eligible_since = self.eligible_since.lock().await;
+        let mut still_eligible = HashMap::new();
+        for lease in leases.iter() {
+            let reason = if is_completed(lease, children.get(lease.shard_id())) {
+                CleanupReason::Completed
+            } else if !listed_shards.is_empty() && !listed_shards.contains(lease.shard_id()) {
+                // An empty listing is more likely a bad response than a stream with no shards
+                CleanupReason::Trimmed
+            } else {
+                continue;
+            };
+
+            let since = match eligible_since.get(&lease.lease_key) {
+                Some(&(previous_reason, since)) if previous_reason == reason => since,
+                _ => now,
+            };
+            let delay = match reason {
+                CleanupReason::Completed => self.config.completed_lease_delay,
+                CleanupReason::Trimmed => self.config.trimmed_lease_delay,
+            };
+            if now.duration_since(since) < delay {
+                still_eligible.insert(lease.lease_key.clone(), (reason, since));
+                continue;
+            }
+
+            if !self.config.dry_run {
+                // If the checkpoint moved since we listed the lease, it's still in use
+                let lease_key = lease.lease_key.as_str();
+                let expected_checkpoint = lease.checkpoint.as_deref();
+                let (deleted, _) = FutureRetry::new(
+                    move || {
+                        self.lease_broker
+                            .delete_lease(lease_key, expected_checkpoint)
+                    },
+                    FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+                )
+                .await
+                .map_err(|(ex, _)| ex)?;
+                if !deleted {
+                    continue;
+                }
+            } else {
+                still_eligible.insert(lease.lease_key.clone(), (reason, since));
+            }
+
+            match reason {
+                CleanupReason::Completed => report.completed.push(lease.lease_key.clone()),
+                CleanupReason::Trimmed => report.trimmed.push(lease.lease_key.clone()),
+            }
+        }
+        *eligible_since = still_eligible;
+
+        Ok(report)
+    }
+}
+
+/// A finished shard's lease is only needed until every one of its children has made progress,
+/// since that's what lets the children's workers start.
+fn is_completed(lease: &Lease, children: Option<&Vec<&Lease>>) -> bool {
+    let is_shard_end = matches!(
+        lease
+            .checkpoint
+            .as_deref()
+            .and_then(Checkpoint::from_lease_value),
+        Some(Checkpoint::ShardEnd)
+    );
+    let children = match children {
+        Some(children) if is_shard_end => children,
+        _ => return false,
+    };
+
+    children.iter().all(|child| {
+        matches!(
+            child
+                .checkpoint
+                .as_deref()
+                .and_then(Checkpoint::from_lease_value),
+            Some(Checkpoint::SequenceNumber(_)) | Some(Checkpoint::ShardEnd)
+        )
+    })
+}
+
+#[async_trait]
+impl PeriodicRunnable for LeaseCleaner {
+    async fn run_once(&self) {
+        // A failed pass is picked up again on the next run
+        if let Ok(report) = self.clean_up().await {
+            *self.last_report.write().await = Some(report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::util::fake_aws::{self, FakeLeaseTable, ScriptedAws};
+
+    use super::*;
+
+    const SEQUENCE_NUMBER: &str = "49590338271490256608559692538361571095921575989136588898";
+
+    fn lease(lease_key: &str, checkpoint: Checkpoint, parent_shard_ids: &[&str]) -> Lease {
+        Lease::new(
+            lease_key.to_string(),
+            checkpoint.to_lease_value(),
+            parent_shard_ids
+                .iter()
+                .map(|parent| parent.to_string())
+                .collect(),
+            "0".to_string(),
+            "99".to_string(),
+        )
+    }
+
+    fn sequence_number() -> Checkpoint {
+        Checkpoint::SequenceNumber(SEQUENCE_NUMBER.to_string())
+    }
+
+    fn cleaner(
+        table: &FakeLeaseTable,
+        script: &ScriptedAws,
+        config: LeaseCleanupConfig,
+    ) -> LeaseCleaner {
+        LeaseCleaner::new(
+            StreamDescriptor::from_name("test"),
+            fake_aws::polled_stream(script, StreamDescriptor::from_name("test")),
+            Arc::new(LeaseBroker::new(table.client(), "leases".to_string())),
+            config,
+        )
+    }
+
+    fn immediate() -> LeaseCleanupConfig {
+        LeaseCleanupConfig {
+            completed_lease_delay: Duration::ZERO,
+            trimmed_lease_delay: Duration::ZERO,
+            ..LeaseCleanupConfig::default()
+        }
+    }
+
+    /// Answers the next `ListShards` with the given shards.
+    fn list(script: &ScriptedAws, shard_ids: &[&str]) {
+        let shards: Vec<_> = shard_ids
+            .iter()
+            .map(|shard_id| {
+                json!({
+                    "ShardId": shard_id,
+                    "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                    "SequenceNumberRange": { "StartingSequenceNumber": "0" },
+                })
+            })
+            .collect();
+        script.respond("ListShards", (200, json!({ "Shards": shards })));
+    }
+
+    #[tokio::test]
+    async fn removes_finished_leases_once_every_child_has_started() {
+        let table = FakeLeaseTable::default();
+        table.put(&lease("shard-1", Checkpoint::ShardEnd, &[]));
+        table.put(&lease("shard-2", sequence_number(), &["shard-1"]));
+        table.put(&lease("shard-3", Checkpoint::TrimHorizon, &["shard-1"]));
+        let script = ScriptedAws::default();
+        let cleaner = cleaner(&table, &script, immediate());
+
+        list(&script, &["shard-1", "shard-2", "shard-3"]);
+        assert_eq!(
+            cleaner.clean_up().await.unwrap(),
+            LeaseCleanupReport::default()
+        );
+        assert_eq!(table.lease_keys(), vec!["shard-1", "shard-2", "shard-3"]);
+
+        table.put(&lease("shard-3", Checkpoint::ShardEnd, &["shard-1"]));
+        list(&script, &["shard-1", "shard-2", "shard-3"]);
+        let report = cleaner.clean_up().await.unwrap();
+        assert_eq!(report.completed, vec!["shard-1".to_string()]);
+        assert_eq!(table.lease_keys(), vec!["shard-2", "shard-3"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_finished_leases_without_children() {
+        let table = FakeLeaseTable::default();
+        table.put(&lease("shard-1", Checkpoint::ShardEnd, &[]));
+        let script = ScriptedAws::default();
+        let cleaner = cleaner(&table, &script, immediate());
+
+        list(&script, &["shard-1"]);
+        assert_eq!(
+            cleaner.clean_up().await.unwrap(),
+            LeaseCleanupReport::default()
+        );
+        assert_eq!(table.lease_keys(), vec!["shard-1"]);
+    }
+
+    #[tokio::test]
+    async fn removes_leases_of_trimmed_shards() {
+        let table = FakeLeaseTable::default();
+        table.put(&lease("shard-1", sequence_number(), &[]));
+        table.put(&lease("shard-2", sequence_number(), &[]));
+        let script = ScriptedAws::default();
+        let cleaner = cleaner(&table, &script, immediate());
+
+        list(&script, &["shard-1", "shard-2"]);
+        assert_eq!(
+            cleaner.clean_up().await.unwrap(),
+            LeaseCleanupReport::default()
+        );
+
+        list(&script, &["shard-2"]);
+        let report = cleaner.clean_up().await.unwrap();
+        assert_eq!(report.trimmed, vec!["shard-1".to_string()]);
+        assert_eq!(table.lease_keys(), vec!["shard-2"]);
+
+        // An empty listing is taken to be a bad response
+        list(&script, &[]);
+        assert_eq!(
+            cleaner.clean_up().await.unwrap(),
+            LeaseCleanupReport::default()
+        );
+        assert_eq!(table.lease_keys(), vec!["shard-2"]);
+    }
+
+    #[tokio::test]
+    async fn waits_before_removing_leases() {
+        let table = FakeLeaseTable::default();
+        table.put(&lease("shard-1", sequence_number(), &[]));
+        let script = ScriptedAws::default();
+        let cleaner = cleaner(
+            &table,
+            &script,
+            LeaseCleanupConfig {
+                trimmed_lease_delay: Duration::from_millis(50),
+                ..immediate()
+            },
+        );
+
+        list(&script, &["shard-2"]);
+        assert_eq!(
+            cleaner.clean_up().await.unwrap(),
+            LeaseCleanupReport::default()
+        );
+        assert_eq!(table.lease_keys(), vec!["shard-1"]);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        list(&script, &["shard-2"]);
+        let report = cleaner.clean_up().await.unwrap();
+        assert_eq!(report.trimmed, vec!["shard-1".to_string()]);
+        assert!(table.lease_keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_reports_leases_in_a_dry_run() {
+        let table = FakeLeaseTable::default();
+        table.put(&lease("shard-1", sequence_number(), &[]));
+        let script = ScriptedAws::default();
+        let cleaner = cleaner(
+            &table,
+            &script,
+            LeaseCleanupConfig {
+                dry_run: true,
+                ..immediate()
+            },
+        );
+
+        for _ in 0..2 {
+            list(&script, &["shard-2"]);
+            assert_eq!(
+                cleaner.clean_up().await.unwrap(),
+                LeaseCleanupReport {
+                    completed: Vec::new(),
+                    trimmed: vec!["shard-1".to_string()],
+                    dry_run: true,
+                }
+            );
+        }
+        assert_eq!(table.lease_keys(), vec!["shard-1"]);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_streams_leases_alone() {
+        let table = FakeLeaseTable::default();
+        let other_lease_key = "arn:aws:kinesis:us-east-1:123456789012:stream/other:shard-1";
+        table.put(&lease(other_lease_key, sequence_number(), &[]));
+        let script = ScriptedAws::default();
+        let cleaner = cleaner(&table, &script, immediate());
+
+        list(&script, &["shard-2"]);
+        assert_eq!(
+            cleaner.clean_up().await.unwrap(),
+            LeaseCleanupReport::default()
+        );
+        assert_eq!(table.lease_keys(), vec![other_lease_key]);
+    }
+}
@@ -1,3 +1,4 @@
+use futures_retry::FutureRetry;
 use std::{collections::HashSet, sync::Arc};
 use std::{
     sync::atomic::{AtomicBool, Ordering},
@@ -5,19 +6,53 @@ use std::{
 };
 use tokio::sync::Notify;
 
-use super::{renewer::LeaseRenewer, taker::LeaseTaker, ShardInfo};
-use crate::util::runnable::{run_at_fixed_interval, run_with_fixed_delay};
+use super::{broker::LeaseBroker, renewer::LeaseRenewer, taker::LeaseTaker, ShardInfo};
+use crate::{
+    checkpoint::{Checkpoint, CheckpointError},
+    config::ShardFilter,
+    kinesis::StreamDescriptor,
+    util::{
+        exception::Exception,
+        retry::FixedCountWithDelayStrategy,
+        runnable::{run_at_fixed_interval, run_with_fixed_delay},
+    },
+};
 
 pub(crate) struct LeaseManager {
     initialized: AtomicBool,
+    lease_broker: Arc<LeaseBroker>,
     lease_taker: Arc<LeaseTaker>,
     lease_renewer: Arc<LeaseRenewer>,
     shutdown: Arc<Notify>,
 }
 
 impl LeaseManager {
-    pub(crate) fn new() -> Self {
-        todo!()
+    pub(crate) fn new(
+        lease_broker: LeaseBroker,
+        worker_identifier: String,
+        stream: StreamDescriptor,
+        shard_filter: ShardFilter,
+    ) -> Self {
+        let lease_broker = Arc::new(lease_broker);
+        let lease_renewer = Arc::new(LeaseRenewer::new(lease_broker.clone()));
+        let lease_taker = Arc::new(LeaseTaker::new(
+            lease_broker.clone(),
+            lease_renewer.clone(),
+            worker_identifier,
+            stream,
+            shard_filter,
+        ));
+        Self {
+            initialized: AtomicBool::new(false),
+            lease_broker,
+            lease_taker,
+            lease_renewer,
+            shutdown: Arc::new(Notify::new()),
+        }
+    }
+
+    pub(crate) fn lease_broker(&self) -> Arc<LeaseBroker> {
+        self.lease_broker.clone()
     }
 
     pub(crate) fn initialize(&self) {
@@ -43,7 +78,75 @@ impl LeaseManager {
     }
 
     pub(crate) async fn get_owned_leases(&self) -> HashSet<ShardInfo> {
-        todo!()
+        let mut owned_leases = HashSet::new();
+        for lease in self.lease_renewer.get_leases().await {
+            let lease_guard = lease.read().await;
+            owned_leases.insert(ShardInfo {
+                shard_id: lease_guard.shard_id().to_string(),
+                lease_key: lease_guard.lease_key.clone(),
+                parent_shard_ids: lease_guard.parent_shard_ids.clone(),
+            });
+        }
+        owned_leases
+    }
+
+    pub(crate) async fn holds_lease(&self, lease_key: &str) -> bool {
+        self.lease_renewer.get_lease(lease_key).await.is_some()
+    }
+
+    pub(crate) async fn get_checkpoint(&self, lease_key: &str) -> Option<Checkpoint> {
+        let lease = self.lease_renewer.get_lease(lease_key).await?;
+        let lease_guard = lease.read().await;
+        lease_guard
+            .checkpoint
+            .as_ref()
+            .and_then(|value| Checkpoint::from_lease_value(value))
+    }
+
+    pub(crate) async fn update_checkpoint(
+        &self,
+        lease_key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        let lease = self
+            .lease_renewer
+            .get_lease(lease_key)
+            .await
+            .ok_or(CheckpointError::LeaseLost)?;
+        let value = &checkpoint.to_lease_value();
+        match FutureRetry::new(
+            move || self.lease_broker.update_checkpoint(lease.clone(), value),
+            FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+        )
+        .await
+        {
+            Ok((true, _)) => Ok(()),
+            Ok((false, _)) => Err(CheckpointError::LeaseLost),
+            Err((Exception::Retryable(msg), _)) | Err((Exception::NonRetryable(msg), _)) => {
+                Err(CheckpointError::Store(msg))
+            }
+        }
+    }
+
+    /// Stops renewing the lease and clears its owner, so any worker can take it on its next scan.
+    /// If clearing the owner fails, the lease is taken once it expires instead.
+    pub(crate) async fn release_lease(&self, lease_key: &str) {
+        if let Some(lease) = self.lease_renewer.remove_lease(lease_key).await {
+            if let Err((ex, _)) = FutureRetry::new(
+                move || self.lease_broker.evict_lease(lease.clone()),
+                FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+            )
+            .await
+            {
+                log::warn!("Failed to release the lease {}: {:?}", lease_key, ex);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn hold_lease(&self, lease: super::Lease) {
+        let lease = Arc::new(tokio::sync::RwLock::new(lease));
+        self.lease_renewer.add_leases(vec![lease]).await;
     }
 
     pub(crate) async fn shutdown(&self) {
@@ -3,9 +3,14 @@ use std::sync::Arc;
 use dynomite::Item;
 use tokio::sync::RwLock;
 
-mod broker;
+use crate::status::HashKeyRange;
+
+pub(crate) mod auditor;
+pub(crate) mod broker;
+pub(crate) mod cleaner;
 pub(crate) mod manager;
 mod renewer;
+pub(crate) mod syncer;
 mod taker;
 
 pub(crate) type SharedLease = Arc<RwLock<Lease>>;
@@ -17,9 +22,53 @@ pub(crate) struct Lease {
     pub(crate) lease_owner: Option<String>,
     pub(crate) last_renewal_nanos: u64,
     pub(crate) lease_counter: u64,
+    #[dynomite(default)]
+    pub(crate) checkpoint: Option<String>,
+    #[dynomite(default)]
+    pub(crate) parent_shard_ids: Vec<String>,
+    #[dynomite(default)]
+    pub(crate) starting_hash_key: Option<String>,
+    #[dynomite(default)]
+    pub(crate) ending_hash_key: Option<String>,
+    /// When the stream the shard belongs to was created, to tell a recreated stream's shards
+    /// apart from the old ones of the same name.
+    #[dynomite(default)]
+    pub(crate) stream_creation_millis: Option<u64>,
 }
 
 impl Lease {
+    pub(crate) fn new(
+        lease_key: String,
+        checkpoint: String,
+        parent_shard_ids: Vec<String>,
+        starting_hash_key: String,
+        ending_hash_key: String,
+    ) -> Self {
+        Self {
+            lease_key,
+            lease_owner: None,
+            last_renewal_nanos: 0,
+            lease_counter: 0,
+            checkpoint: Some(checkpoint),
+            parent_shard_ids,
+            starting_hash_key: Some(starting_hash_key),
+            ending_hash_key: Some(ending_hash_key),
+            stream_creation_millis: None,
+        }
+    }
+
+    /// Lease keys for streams identified by ARN carry the stream's identity ahead of the shard ID.
+    pub(crate) fn shard_id(&self) -> &str {
+        self.lease_key.rsplit(':').next().unwrap_or(&self.lease_key)
+    }
+
+    /// Leases created before hash key ranges were recorded don't have one.
+    pub(crate) fn hash_key_range(&self) -> Option<HashKeyRange> {
+        let start = self.starting_hash_key.as_ref()?.parse().ok()?;
+        let end = self.ending_hash_key.as_ref()?.parse().ok()?;
+        Some(HashKeyRange { start, end })
+    }
+
     pub(crate) fn is_expired(&self) -> bool {
         todo!()
     }
@@ -28,4 +77,6 @@ impl Lease {
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub(crate) struct ShardInfo {
     pub(crate) shard_id: String,
+    pub(crate) lease_key: String,
+    pub(crate) parent_shard_ids: Vec<String>,
 }
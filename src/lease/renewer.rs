@@ -13,6 +13,13 @@ pub(crate) struct LeaseRenewer {
 }
 
 impl LeaseRenewer {
+    pub(crate) fn new(lease_broker: Arc<LeaseBroker>) -> Self {
+        Self {
+            leases: RwLock::new(HashMap::new()),
+            lease_broker,
+        }
+    }
+
     pub(crate) async fn add_leases(&self, leases: Vec<SharedLease>) {
         let mut leases_guard = self.leases.write().await;
         for lease in leases {
@@ -21,6 +28,18 @@ impl LeaseRenewer {
         }
     }
 
+    pub(crate) async fn get_leases(&self) -> Vec<SharedLease> {
+        self.leases.read().await.values().cloned().collect()
+    }
+
+    pub(crate) async fn get_lease(&self, lease_key: &str) -> Option<SharedLease> {
+        self.leases.read().await.get(lease_key).cloned()
+    }
+
+    pub(crate) async fn remove_lease(&self, lease_key: &str) -> Option<SharedLease> {
+        self.leases.write().await.remove(lease_key)
+    }
+
     async fn renew_lease(&self, lease: SharedLease) -> bool {
         let mut renewed_lease = false;
         let lease_guard = lease.read().await;
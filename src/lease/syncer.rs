@@ -0,0 +1,731 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures_retry::FutureRetry;
+use rusoto_kinesis::{ChildShard, HashKeyRange as ShardHashKeyRange, Shard};
+use tokio::sync::RwLock;
+
+use crate::{
+    checkpoint::Checkpoint,
+    config::{ShardFilter, StreamRecreationPolicy},
+    kinesis::StreamDescriptor,
+    source::RecordSource,
+    status::{HashKeyRange, StreamRecreation},
+    util::{exception::Exception, retry::FixedCountWithDelayStrategy, runnable::PeriodicRunnable},
+};
+
+use super::{broker::LeaseBroker, Lease};
+
+/// Creates leases for any of the stream's shards that don't have one yet.
+pub(crate) struct ShardSyncer {
+    stream: StreamDescriptor,
+    source: Arc<dyn RecordSource>,
+    lease_broker: Arc<LeaseBroker>,
+    initial_position: Checkpoint,
+    shard_filter: ShardFilter,
+    recreation_policy: StreamRecreationPolicy,
+    last_listed_shards: RwLock<Option<HashSet<String>>>,
+    stream_creation_millis: RwLock<Option<u64>>,
+    stream_recreation: RwLock<Option<StreamRecreation>>,
+}
+
+impl ShardSyncer {
+    pub(crate) fn new(
+        stream: StreamDescriptor,
+        source: Arc<dyn RecordSource>,
+        lease_broker: Arc<LeaseBroker>,
+        initial_position: Checkpoint,
+        shard_filter: ShardFilter,
+        recreation_policy: StreamRecreationPolicy,
+    ) -> Self {
+        Self {
+            stream,
+            source,
+            lease_broker,
+            initial_position,
+            shard_filter,
+            recreation_policy,
+            last_listed_shards: RwLock::new(None),
+            stream_creation_millis: RwLock::new(None),
+            stream_recreation: RwLock::new(None),
+        }
+    }
+
+    /// Whether the last listing of the stream no longer included the shard.
+    pub(crate) async fn is_trimmed(&self, shard_id: &str) -> bool {
+        match self.last_listed_shards.read().await.as_ref() {
+            Some(listed_shards) => !listed_shards.contains(shard_id),
+            None => false,
+        }
+    }
+
+    /// Set while the lease table holds leases of an earlier stream with the same name, unless the
+    /// policy is to reset them.
+    pub(crate) async fn stream_recreation(&self) -> Option<StreamRecreation> {
+        self.stream_recreation.read().await.clone()
+    }
+
+    /// Returns how many leases were created.
+    pub(crate) async fn sync_shards(&self) -> Result<usize, Exception> {
+        let (stream_creation_millis, _) = FutureRetry::new(
+            move || async move { Ok(self.source.stream_creation_millis().await?) },
+            FixedCountWithDelayStrategy::new(3, Duration::from_secs(1)),
+        )
+        .await
+        .map_err(|(ex, _)| ex)?;
+        *self.stream_creation_millis.write().await = Some(stream_creation_millis);
+
+        let (shards, _) = FutureRetry::new(
+            move || async move { Ok(self.source.list_shards().await?) },
+            FixedCountWithDelayStrategy::new(3, Duration::from_secs(1)),
+        )
+        .await
+        .map_err(|(ex, _)| ex)?;
+        let (existing_leases, _) = FutureRetry::new(
+            move || self.lease_broker.list_all_leases(),
+            FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+        )
+        .await
+        .map_err(|(ex, _)| ex)?;
+
+        let mut leased_shards = HashSet::new();
+        let mut stale_leases = Vec::new();
+        let mut unstamped_leases = Vec::new();
+        for lease in existing_leases {
+            let lease_guard = lease.read().await;
+            // Other streams sharing the table have their own shards, created at their own times
+            if !self.stream.owns_lease_key(&lease_guard.lease_key) {
+                continue;
+            }
+            match lease_guard.stream_creation_millis {
+                Some(created) if created != stream_creation_millis => {
+                    stale_leases.push(lease_guard.lease_key.clone())
+                }
+                Some(_) => {
+                    leased_shards.insert(lease_guard.shard_id().to_string());
+                }
+                // Leases from before creation times were recorded are assumed to be current
+                None => {
+                    leased_shards.insert(lease_guard.shard_id().to_string());
+                    unstamped_leases.push(lease.clone());
+                }
+            }
+        }
+        if !stale_leases.is_empty() {
+            if self.recreation_policy != StreamRecreationPolicy::ResetLeases {
+                *self.stream_recreation.write().await = Some(StreamRecreation {
+                    stream_creation_millis,
+                    stale_leases,
+                });
+                return Err(Exception::NonRetryable(
+                    "The stream was recreated since its leases were created".to_string(),
+                ));
+            }
+            // Their holders find out at their next renewal; the shards are gone either way
+            for lease_key in stale_leases.iter() {
+                FutureRetry::new(
+                    move || self.lease_broker.delete_lease(lease_key, None),
+                    FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+                )
+                .await
+                .map_err(|(ex, _)| ex)?;
+            }
+        }
+        *self.stream_recreation.write().await = None;
+        let listed_shards: HashSet<&str> =
+            shards.iter().map(|shard| shard.shard_id.as_str()).collect();
+        *self.last_listed_shards.write().await = Some(
+            listed_shards
+                .iter()
+                .map(|shard_id| shard_id.to_string())
+                .collect(),
+        );
+
+        let mut created = 0;
+        for shard in shards.iter() {
+            if leased_shards.contains(&shard.shard_id)
+                || !self.matches_filter(&shard.shard_id, &shard.hash_key_range)
+            {
+                continue;
+            }
+            let lease = &Lease {
+                stream_creation_millis: Some(stream_creation_millis),
+                ..self.new_lease(shard, &listed_shards)
+            };
+            let (was_created, _) = FutureRetry::new(
+                move || self.lease_broker.create_lease_if_not_exists(lease),
+                FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+            )
+            .await
+            .map_err(|(ex, _)| ex)?;
+            if was_created {
+                created += 1;
+            }
+        }
+
+        // Stamped with the stream they were found in, so a later recreation is noticed. Shards
+        // the stream doesn't list any more may well be from an earlier stream, so they're left as
+        // they are for lease cleanup.
+        for lease in unstamped_leases.iter() {
+            if !listed_shards.contains(lease.read().await.shard_id()) {
+                continue;
+            }
+            FutureRetry::new(
+                move || {
+                    self.lease_broker
+                        .set_stream_creation_millis(lease.clone(), stream_creation_millis)
+                },
+                FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+            )
+            .await
+            .map_err(|(ex, _)| ex)?;
+        }
+
+        Ok(created)
+    }
+
+    /// Creates leases for the children a shard reported, without waiting for the next sync. The
+    /// writes are conditional, so workers racing to create the same children is harmless. Returns
+    /// how many leases were created.
+    pub(crate) async fn create_child_leases(
+        &self,
+        child_shards: &[ChildShard],
+    ) -> Result<usize, Exception> {
+        let stream_creation_millis = *self.stream_creation_millis.read().await;
+        let mut created = 0;
+        for child_shard in child_shards {
+            if !self.matches_filter(&child_shard.shard_id, &child_shard.hash_key_range) {
+                continue;
+            }
+            let lease = &Lease {
+                stream_creation_millis,
+                ..Lease::new(
+                    self.stream.lease_key(&child_shard.shard_id),
+                    Checkpoint::TrimHorizon.to_lease_value(),
+                    child_shard.parent_shards.clone(),
+                    child_shard.hash_key_range.starting_hash_key.clone(),
+                    child_shard.hash_key_range.ending_hash_key.clone(),
+                )
+            };
+            let (was_created, _) = FutureRetry::new(
+                move || self.lease_broker.create_lease_if_not_exists(lease),
+                FixedCountWithDelayStrategy::new(3, Duration::from_millis(100)),
+            )
+            .await
+            .map_err(|(ex, _)| ex)?;
+            if was_created {
+                created += 1;
+            }
+        }
+
+        Ok(created)
+    }
+
+    fn matches_filter(&self, shard_id: &str, hash_key_range: &ShardHashKeyRange) -> bool {
+        let range = match (
+            hash_key_range.starting_hash_key.parse(),
+            hash_key_range.ending_hash_key.parse(),
+        ) {
+            (Ok(start), Ok(end)) => Some(HashKeyRange { start, end }),
+            _ => None,
+        };
+        self.shard_filter.matches_shard(shard_id, range.as_ref())
+    }
+
+    fn new_lease(&self, shard: &Shard, listed_shards: &HashSet<&str>) -> Lease {
+        let parent_shard_ids: Vec<String> = shard
+            .parent_shard_id
+            .iter()
+            .chain(shard.adjacent_parent_shard_id.iter())
+            .cloned()
+            .collect();
+
+        // A child of a shard still in retention has to pick up where its parents left off
+        let has_listed_parent = parent_shard_ids
+            .iter()
+            .any(|parent| listed_shards.contains(parent.as_str()));
+        let checkpoint = if has_listed_parent {
+            Checkpoint::TrimHorizon
+        } else {
+            self.initial_position.clone()
+        };
+
+        Lease::new(
+            self.stream.lease_key(&shard.shard_id),
+            checkpoint.to_lease_value(),
+            parent_shard_ids,
+            shard.hash_key_range.starting_hash_key.clone(),
+            shard.hash_key_range.ending_hash_key.clone(),
+        )
+    }
+}
+
+#[async_trait]
+impl PeriodicRunnable for ShardSyncer {
+    async fn run_once(&self) {
+        // A failed sync is picked up again on the next run
+        if let Err(err) = self.sync_shards().await {
+            log::warn!(
+                "Failed to sync the shards of {}: {:?}",
+                self.stream.stream_name(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::util::fake_aws::{self, FakeLeaseTable, ScriptedAws};
+
+    use super::*;
+
+    const SEQUENCE_NUMBER: &str = "49590338271490256608559692538361571095921575989136588898";
+    const CREATED_MILLIS: u64 = 1_600_000_000_000;
+
+    /// A shard as `ListShards` describes it, with up to two parents.
+    fn shard(shard_id: &str, parent_shard_ids: &[&str], hash_keys: (u128, u128)) -> Value {
+        json!({
+            "ShardId": shard_id,
+            "ParentShardId": parent_shard_ids.first(),
+            "AdjacentParentShardId": parent_shard_ids.get(1),
+            "HashKeyRange": {
+                "StartingHashKey": hash_keys.0.to_string(),
+                "EndingHashKey": hash_keys.1.to_string(),
+            },
+            "SequenceNumberRange": { "StartingSequenceNumber": "0" },
+        })
+    }
+
+    fn child_shard(
+        shard_id: &str,
+        parent_shard_ids: &[&str],
+        hash_keys: (u128, u128),
+    ) -> ChildShard {
+        ChildShard {
+            shard_id: shard_id.to_string(),
+            parent_shards: parent_shard_ids
+                .iter()
+                .map(|parent| parent.to_string())
+                .collect(),
+            hash_key_range: ShardHashKeyRange {
+                starting_hash_key: hash_keys.0.to_string(),
+                ending_hash_key: hash_keys.1.to_string(),
+            },
+        }
+    }
+
+    fn syncer(
+        table: &FakeLeaseTable,
+        script: &ScriptedAws,
+        initial_position: Checkpoint,
+        shard_filter: ShardFilter,
+    ) -> ShardSyncer {
+        ShardSyncer::new(
+            StreamDescriptor::from_name("test"),
+            fake_aws::polled_stream(script, StreamDescriptor::from_name("test")),
+            Arc::new(LeaseBroker::new(table.client(), "leases".to_string())),
+            initial_position,
+            shard_filter,
+            StreamRecreationPolicy::default(),
+        )
+    }
+
+    /// Scripts the stream summary each sync starts with.
+    fn describe_stream(script: &ScriptedAws, syncs: usize) {
+        for _ in 0..syncs {
+            script.respond(
+                "DescribeStreamSummary",
+                fake_aws::stream_summary("test", CREATED_MILLIS as f64 / 1000.0),
+            );
+        }
+    }
+
+    fn checkpoint(table: &FakeLeaseTable, lease_key: &str) -> Option<Checkpoint> {
+        table
+            .get(lease_key)
+            .and_then(|lease| lease.checkpoint)
+            .and_then(|value| Checkpoint::from_lease_value(&value))
+    }
+
+    #[tokio::test]
+    async fn creates_leases_for_new_shards() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        describe_stream(&script, 2);
+        let shards = json!([
+            shard("shard-1", &[], (0, 99)),
+            shard("shard-2", &["shard-1"], (0, 49)),
+            shard("shard-3", &[], (100, 199)),
+        ]);
+        script
+            .respond("ListShards", (200, json!({ "Shards": shards })))
+            .respond("ListShards", (200, json!({ "Shards": shards })));
+        let syncer = syncer(&table, &script, Checkpoint::Latest, ShardFilter::All);
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 3);
+        assert_eq!(table.lease_keys(), vec!["shard-1", "shard-2", "shard-3"]);
+        assert_eq!(checkpoint(&table, "shard-1"), Some(Checkpoint::Latest));
+        assert_eq!(checkpoint(&table, "shard-3"), Some(Checkpoint::Latest));
+        // Children of shards still in retention pick up where their parents leave off
+        assert_eq!(checkpoint(&table, "shard-2"), Some(Checkpoint::TrimHorizon));
+        let child = table.get("shard-2").unwrap();
+        assert_eq!(child.parent_shard_ids, vec!["shard-1".to_string()]);
+        assert_eq!(child.starting_hash_key, Some("0".to_string()));
+        assert_eq!(child.ending_hash_key, Some("49".to_string()));
+        assert_eq!(child.stream_creation_millis, Some(CREATED_MILLIS));
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn leaves_existing_leases_alone() {
+        let table = FakeLeaseTable::default();
+        table.put(&Lease::new(
+            "shard-1".to_string(),
+            SEQUENCE_NUMBER.to_string(),
+            Vec::new(),
+            "0".to_string(),
+            "99".to_string(),
+        ));
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        script.respond(
+            "ListShards",
+            (
+                200,
+                json!({
+                    "Shards": [shard("shard-1", &[], (0, 99)), shard("shard-2", &[], (100, 199))],
+                }),
+            ),
+        );
+        let syncer = syncer(&table, &script, Checkpoint::TrimHorizon, ShardFilter::All);
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 1);
+        assert_eq!(
+            checkpoint(&table, "shard-1"),
+            Some(Checkpoint::SequenceNumber(SEQUENCE_NUMBER.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn notices_trimmed_shards() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        describe_stream(&script, 2);
+        script
+            .respond(
+                "ListShards",
+                (
+                    200,
+                    json!({
+                        "Shards": [
+                            shard("shard-1", &[], (0, 99)),
+                            shard("shard-2", &["shard-1"], (0, 99)),
+                        ],
+                    }),
+                ),
+            )
+            .respond(
+                "ListShards",
+                (
+                    200,
+                    json!({ "Shards": [shard("shard-2", &["shard-1"], (0, 99))] }),
+                ),
+            );
+        let syncer = syncer(&table, &script, Checkpoint::Latest, ShardFilter::All);
+        // Nothing is known to be trimmed before the stream has been listed
+        assert!(!syncer.is_trimmed("shard-1").await);
+
+        syncer.sync_shards().await.unwrap();
+        assert!(!syncer.is_trimmed("shard-1").await);
+
+        syncer.sync_shards().await.unwrap();
+        assert!(syncer.is_trimmed("shard-1").await);
+        assert!(!syncer.is_trimmed("shard-2").await);
+    }
+
+    #[tokio::test]
+    async fn starts_children_of_trimmed_shards_at_the_initial_position() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        script.respond(
+            "ListShards",
+            (
+                200,
+                json!({ "Shards": [shard("shard-2", &["shard-1"], (0, 99))] }),
+            ),
+        );
+        let syncer = syncer(&table, &script, Checkpoint::Latest, ShardFilter::All);
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 1);
+        assert_eq!(checkpoint(&table, "shard-2"), Some(Checkpoint::Latest));
+    }
+
+    #[tokio::test]
+    async fn follows_list_shards_pagination() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        script
+            .respond(
+                "ListShards",
+                (
+                    200,
+                    json!({
+                        "Shards": [shard("shard-1", &[], (0, 99))],
+                        "NextToken": "page-2",
+                    }),
+                ),
+            )
+            .respond(
+                "ListShards",
+                (
+                    200,
+                    json!({ "Shards": [shard("shard-2", &[], (100, 199))] }),
+                ),
+            );
+        let syncer = syncer(&table, &script, Checkpoint::TrimHorizon, ShardFilter::All);
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 2);
+        let requests = script.requests("ListShards");
+        assert_eq!(requests[0]["StreamName"], "test");
+        // The stream name can't be sent along with a token
+        assert_eq!(requests[1]["NextToken"], "page-2");
+        assert_eq!(requests[1].get("StreamName"), None);
+    }
+
+    #[tokio::test]
+    async fn creates_leases_for_reported_child_shards() {
+        let table = FakeLeaseTable::default();
+        let syncer = syncer(
+            &table,
+            &ScriptedAws::default(),
+            Checkpoint::Latest,
+            ShardFilter::All,
+        );
+        let children = vec![
+            child_shard("shard-2", &["shard-1"], (0, 49)),
+            child_shard("shard-3", &["shard-1"], (50, 99)),
+        ];
+
+        assert_eq!(syncer.create_child_leases(&children).await.unwrap(), 2);
+        // Children always start from the beginning, whatever the initial position
+        assert_eq!(checkpoint(&table, "shard-2"), Some(Checkpoint::TrimHorizon));
+        let child = table.get("shard-3").unwrap();
+        assert_eq!(child.parent_shard_ids, vec!["shard-1".to_string()]);
+        assert_eq!(child.starting_hash_key, Some("50".to_string()));
+        assert_eq!(child.ending_hash_key, Some("99".to_string()));
+
+        // Other workers, or the sync, may have created them already
+        assert_eq!(syncer.create_child_leases(&children).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn only_creates_leases_for_shards_in_the_filter() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        script.respond(
+            "ListShards",
+            (
+                200,
+                json!({
+                    "Shards": [
+                        shard("shard-1", &[], (0, 99)),
+                        shard("shard-2", &[], (100, 199)),
+                    ],
+                }),
+            ),
+        );
+        let syncer = syncer(
+            &table,
+            &script,
+            Checkpoint::TrimHorizon,
+            ShardFilter::HashKeyRange(HashKeyRange { start: 50, end: 60 }),
+        );
+        assert_eq!(syncer.sync_shards().await.unwrap(), 1);
+        assert_eq!(table.lease_keys(), vec!["shard-1"]);
+
+        let children = vec![
+            child_shard("shard-3", &["shard-1"], (0, 49)),
+            child_shard("shard-4", &["shard-1"], (50, 99)),
+        ];
+        assert_eq!(syncer.create_child_leases(&children).await.unwrap(), 1);
+        assert_eq!(table.lease_keys(), vec!["shard-1", "shard-4"]);
+    }
+
+    #[tokio::test]
+    async fn keys_leases_of_streams_given_by_arn() {
+        let table = FakeLeaseTable::default();
+        let stream =
+            StreamDescriptor::from_arn("arn:aws:kinesis:us-east-1:123456789012:stream/test")
+                .unwrap();
+        // A same-named stream elsewhere shares the table
+        let other_lease_key = "arn:aws:kinesis:eu-west-1:123456789012:stream/test:shard-1";
+        table.put(&Lease::new(
+            other_lease_key.to_string(),
+            SEQUENCE_NUMBER.to_string(),
+            Vec::new(),
+            "0".to_string(),
+            "99".to_string(),
+        ));
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        script.respond(
+            "ListShards",
+            (200, json!({ "Shards": [shard("shard-1", &[], (0, 99))] })),
+        );
+        let syncer = ShardSyncer::new(
+            stream.clone(),
+            fake_aws::polled_stream(&script, stream.clone()),
+            Arc::new(LeaseBroker::new(table.client(), "leases".to_string())),
+            Checkpoint::TrimHorizon,
+            ShardFilter::All,
+            StreamRecreationPolicy::default(),
+        );
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 1);
+        assert_eq!(
+            checkpoint(&table, &stream.lease_key("shard-1")),
+            Some(Checkpoint::TrimHorizon)
+        );
+        assert_eq!(
+            checkpoint(&table, other_lease_key),
+            Some(Checkpoint::SequenceNumber(SEQUENCE_NUMBER.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_leases_of_an_earlier_stream_until_they_are_deleted() {
+        let table = FakeLeaseTable::default();
+        table.put(&Lease {
+            stream_creation_millis: Some(CREATED_MILLIS - 1),
+            ..Lease::new(
+                "shard-1".to_string(),
+                SEQUENCE_NUMBER.to_string(),
+                Vec::new(),
+                "0".to_string(),
+                "99".to_string(),
+            )
+        });
+        let script = ScriptedAws::default();
+        describe_stream(&script, 2);
+        script.respond(
+            "ListShards",
+            (200, json!({ "Shards": [shard("shard-1", &[], (0, 99))] })),
+        );
+        script.respond(
+            "ListShards",
+            (200, json!({ "Shards": [shard("shard-1", &[], (0, 99))] })),
+        );
+        let syncer = syncer(&table, &script, Checkpoint::TrimHorizon, ShardFilter::All);
+
+        assert!(syncer.sync_shards().await.is_err());
+        assert_eq!(
+            syncer.stream_recreation().await,
+            Some(StreamRecreation {
+                stream_creation_millis: CREATED_MILLIS,
+                stale_leases: vec!["shard-1".to_string()],
+            })
+        );
+        assert_eq!(
+            checkpoint(&table, "shard-1"),
+            Some(Checkpoint::SequenceNumber(SEQUENCE_NUMBER.to_string()))
+        );
+
+        let lease_broker = LeaseBroker::new(table.client(), "leases".to_string());
+        lease_broker.delete_lease("shard-1", None).await.unwrap();
+        assert_eq!(syncer.sync_shards().await.unwrap(), 1);
+        assert_eq!(syncer.stream_recreation().await, None);
+        assert_eq!(checkpoint(&table, "shard-1"), Some(Checkpoint::TrimHorizon));
+    }
+
+    #[tokio::test]
+    async fn stamps_leases_from_before_creation_times_were_recorded() {
+        let table = FakeLeaseTable::default();
+        let legacy = |shard_id: &str| {
+            Lease::new(
+                shard_id.to_string(),
+                SEQUENCE_NUMBER.to_string(),
+                Vec::new(),
+                "0".to_string(),
+                "99".to_string(),
+            )
+        };
+        table.put(&legacy("shard-1"));
+        table.put(&legacy("shard-0"));
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        // The stream is recreated before the second sync
+        script.respond(
+            "DescribeStreamSummary",
+            fake_aws::stream_summary("test", (CREATED_MILLIS + 1000) as f64 / 1000.0),
+        );
+        for _ in 0..2 {
+            script.respond(
+                "ListShards",
+                (200, json!({ "Shards": [shard("shard-1", &[], (0, 99))] })),
+            );
+        }
+        let syncer = syncer(&table, &script, Checkpoint::TrimHorizon, ShardFilter::All);
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 0);
+        let stamped = table.get("shard-1").unwrap();
+        assert_eq!(stamped.stream_creation_millis, Some(CREATED_MILLIS));
+        // Its holder's next checkpoint isn't turned away
+        assert_eq!(stamped.lease_counter, 0);
+        // Not listed any more, so maybe not from this stream at all
+        assert_eq!(table.get("shard-0").unwrap().stream_creation_millis, None);
+
+        // A recreation is noticed from then on
+        assert!(syncer.sync_shards().await.is_err());
+        assert_eq!(
+            syncer
+                .stream_recreation()
+                .await
+                .map(|recreation| recreation.stale_leases),
+            Some(vec!["shard-1".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_leases_of_an_earlier_stream_when_told_to() {
+        let table = FakeLeaseTable::default();
+        table.put(&Lease {
+            stream_creation_millis: Some(CREATED_MILLIS - 1),
+            ..Lease::new(
+                "shard-1".to_string(),
+                SEQUENCE_NUMBER.to_string(),
+                Vec::new(),
+                "0".to_string(),
+                "99".to_string(),
+            )
+        });
+        let script = ScriptedAws::default();
+        describe_stream(&script, 1);
+        script.respond(
+            "ListShards",
+            (200, json!({ "Shards": [shard("shard-1", &[], (0, 99))] })),
+        );
+        let syncer = ShardSyncer::new(
+            StreamDescriptor::from_name("test"),
+            fake_aws::polled_stream(&script, StreamDescriptor::from_name("test")),
+            Arc::new(LeaseBroker::new(table.client(), "leases".to_string())),
+            Checkpoint::Latest,
+            ShardFilter::All,
+            StreamRecreationPolicy::ResetLeases,
+        );
+
+        assert_eq!(syncer.sync_shards().await.unwrap(), 1);
+        assert_eq!(syncer.stream_recreation().await, None);
+        assert_eq!(checkpoint(&table, "shard-1"), Some(Checkpoint::Latest));
+        assert_eq!(
+            table.get("shard-1").unwrap().stream_creation_millis,
+            Some(CREATED_MILLIS)
+        );
+    }
+}
@@ -13,8 +13,10 @@ use futures_retry::FutureRetry;
 use rand::{seq::SliceRandom, thread_rng};
 use tokio::sync::RwLock;
 
-use crate::util::{
-    exception::Exception, retry::FixedCountWithDelayStrategy, runnable::PeriodicRunnable,
+use crate::{
+    config::ShardFilter,
+    kinesis::StreamDescriptor,
+    util::{exception::Exception, retry::FixedCountWithDelayStrategy, runnable::PeriodicRunnable},
 };
 
 use super::{broker::LeaseBroker, renewer::LeaseRenewer, SharedLease};
@@ -27,6 +29,8 @@ pub(crate) struct LeaseTaker {
     worker_identifier: String,
     max_allowed_leases: usize,
     max_steals_per_run: usize,
+    stream: StreamDescriptor,
+    shard_filter: ShardFilter,
 }
 
 fn current_nano_time() -> u64 {
@@ -39,6 +43,26 @@ fn current_nano_time() -> u64 {
 }
 
 impl LeaseTaker {
+    pub(crate) fn new(
+        lease_broker: Arc<LeaseBroker>,
+        lease_renewer: Arc<LeaseRenewer>,
+        worker_identifier: String,
+        stream: StreamDescriptor,
+        shard_filter: ShardFilter,
+    ) -> Self {
+        Self {
+            lease_broker,
+            all_leases: RwLock::new(HashMap::new()),
+            lease_renewer,
+            last_scan_time: AtomicU64::new(0),
+            worker_identifier,
+            max_allowed_leases: usize::MAX,
+            max_steals_per_run: 1,
+            stream,
+            shard_filter,
+        }
+    }
+
     async fn take_leases(&self) -> Vec<SharedLease> {
         if let Err((_ex, _)) = self.update_leases_from_source().await {
             todo!("Print an error somewhere")
@@ -94,6 +118,16 @@ impl LeaseTaker {
         let mut all_leases = self.all_leases.write().await;
         for shared_source_lease in source_leases {
             let mut source_lease = shared_source_lease.write().await;
+            // Leases of other streams sharing the table, or outside the filter, are left out
+            // entirely, so they're never taken or counted
+            if !self.stream.owns_lease_key(&source_lease.lease_key)
+                || !self.shard_filter.matches_shard(
+                    source_lease.shard_id(),
+                    source_lease.hash_key_range().as_ref(),
+                )
+            {
+                continue;
+            }
             let existing_lease =
                 all_leases.insert(source_lease.lease_key.clone(), shared_source_lease.clone());
             not_updated.remove(&source_lease.lease_key);
@@ -122,7 +156,7 @@ impl LeaseTaker {
     }
 
     async fn find_leases_to_take(&self, expired_leases: &mut Vec<SharedLease>) -> Vec<SharedLease> {
-        if self.all_leases.read().await.len() == 0 {
+        if self.all_leases.read().await.is_empty() {
             return Vec::new();
         }
 
@@ -154,10 +188,10 @@ impl LeaseTaker {
         }
 
         let mut result = Vec::new();
-        if expired_leases.len() > 0 {
+        if !expired_leases.is_empty() {
             // Try taking some of the expired leases at random
             expired_leases.shuffle(&mut thread_rng());
-            while available_slots > 0 && expired_leases.len() > 0 {
+            while available_slots > 0 && !expired_leases.is_empty() {
                 result.push(expired_leases.pop().expect("Awkward").clone());
                 available_slots -= 1;
             }
@@ -198,7 +232,7 @@ impl LeaseTaker {
             }
         }
 
-        if lease_counts.get(&self.worker_identifier).is_none() {
+        if !lease_counts.contains_key(&self.worker_identifier) {
             lease_counts.insert(self.worker_identifier.clone(), 0);
         }
         lease_counts
@@ -210,7 +244,7 @@ impl LeaseTaker {
         needed_leases: usize,
         target: usize,
     ) -> Vec<SharedLease> {
-        assert!(lease_counts.len() > 0);
+        assert!(!lease_counts.is_empty());
         let (busiest_worker, &busiest_count) = lease_counts
             .iter()
             .max_by_key(|&(_, &v)| v)
@@ -234,7 +268,7 @@ impl LeaseTaker {
             }
         }
 
-        if leases_to_steal <= 0 {
+        if leases_to_steal == 0 {
             return Vec::new();
         }
 
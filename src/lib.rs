@@ -1,5 +1,4 @@
 use async_trait::async_trait;
-use rusoto_kinesis::KinesisClient;
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
@@ -8,37 +7,150 @@ use std::{
 use tokio::sync::Mutex;
 use util::runnable::{run_at_fixed_interval, PeriodicRunnable};
 
+use checkpoint::{CheckpointStore, LeaseCheckpointStore};
+use config::{ConfigError, SchedulerConfig, StreamRecreationPolicy};
+use dynomite::dynamodb::DynamoDbClient;
 use interface::processor::RecordProcessor;
-use lease::{manager::LeaseManager, ShardInfo};
-use rusoto_core::region::Region;
+use kinesis::{consumer::StreamConsumer, StreamDescriptor};
+use lease::{
+    auditor::HashRangeAuditor, broker::LeaseBroker, cleaner::LeaseCleaner, manager::LeaseManager,
+    syncer::ShardSyncer, ShardInfo,
+};
+use status::{HashRangeAuditReport, LeaseCleanupReport, ShardWorkerState, StreamRecreation};
 use tokio::sync::Notify;
-use worker::ShardWorker;
+use worker::{ShardWorker, WorkerContext};
 
+pub mod checkpoint;
+pub mod config;
 pub mod interface;
-mod kinesis;
+pub mod kinesis;
 mod lease;
+pub mod source;
+pub mod status;
 pub mod util;
 mod worker;
 
 /// TODO
 pub struct WorkerScheduler {
     processor_factory: fn() -> Box<dyn RecordProcessor>,
+    config: Arc<SchedulerConfig>,
     lease_manager: Arc<LeaseManager>,
     consumers: Mutex<HashMap<ShardInfo, Arc<ShardWorker>>>,
-    kinesis: Arc<KinesisClient>,
+    worker_context: Arc<WorkerContext>,
+    /// Set when records are retrieved with enhanced fan-out.
+    stream_consumer: Option<Arc<StreamConsumer>>,
+    shard_syncer: Arc<ShardSyncer>,
+    shard_sync_shutdown: Arc<Notify>,
+    lease_cleaner: Arc<LeaseCleaner>,
+    lease_cleanup_shutdown: Arc<Notify>,
+    hash_range_auditor: Arc<HashRangeAuditor>,
+    hash_range_audit_shutdown: Arc<Notify>,
     shutdown: Arc<Notify>,
 }
 
 impl WorkerScheduler {
-    /// TODO
-    pub fn new(processor_factory: fn() -> Box<dyn RecordProcessor>) -> Self {
-        Self {
+    /// Consumes `stream` with the default settings.
+    pub fn new(
+        application_name: impl Into<String>,
+        stream: StreamDescriptor,
+        processor_factory: fn() -> Box<dyn RecordProcessor>,
+    ) -> Result<Self, ConfigError> {
+        Self::with_config(
+            processor_factory,
+            SchedulerConfig {
+                application_name: application_name.into(),
+                stream,
+                ..SchedulerConfig::default()
+            },
+        )
+    }
+
+    /// Fails if the configuration can't work, rather than once the scheduler runs.
+    pub fn with_config(
+        processor_factory: fn() -> Box<dyn RecordProcessor>,
+        config: SchedulerConfig,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        // Each application has a lease table of its own, named after it
+        let lease_broker = LeaseBroker::new(
+            DynamoDbClient::new(Default::default()),
+            config.application_name.clone(),
+        );
+        let lease_manager = Arc::new(LeaseManager::new(
+            lease_broker,
+            format!("{:016x}", rand::random::<u64>()),
+            config.stream.clone(),
+            config.shard_filter.clone(),
+        ));
+        let checkpoint_store: Arc<dyn CheckpointStore> = config
+            .checkpoint_store
+            .clone()
+            .unwrap_or_else(|| Arc::new(LeaseCheckpointStore::new(lease_manager.clone())));
+        let stream = config.stream.clone();
+        let kinesis = Arc::new(kinesis::kinesis_client(
+            &stream,
+            config.kinesis_credentials.clone(),
+        ));
+        let stream_consumer = config
+            .retrieval_mode
+            .fan_out_config()
+            .filter(|_| config.record_source.is_none())
+            .map(|_| {
+                Arc::new(StreamConsumer::new(
+                    kinesis.clone(),
+                    config.stream.clone(),
+                    config.application_name.clone(),
+                ))
+            });
+        let record_source = config.record_source.clone().unwrap_or_else(|| {
+            let shard_api =
+                kinesis::shard_api(&stream, kinesis.clone(), config.kinesis_credentials.clone());
+            source::kinesis::stream_source(&config, kinesis, shard_api, stream_consumer.clone())
+        });
+        let shard_syncer = Arc::new(ShardSyncer::new(
+            stream,
+            record_source.clone(),
+            lease_manager.lease_broker(),
+            config.initial_position.clone(),
+            config.shard_filter.clone(),
+            config.stream_recreation_policy,
+        ));
+        let lease_cleaner = Arc::new(LeaseCleaner::new(
+            config.stream.clone(),
+            record_source.clone(),
+            lease_manager.lease_broker(),
+            config.lease_cleanup.clone(),
+        ));
+        let hash_range_auditor = Arc::new(HashRangeAuditor::new(
+            config.stream.clone(),
+            lease_manager.lease_broker(),
+            shard_syncer.clone(),
+            config.shard_filter.clone(),
+        ));
+        let prefetch_budget = worker::prefetch_budget(&config.prefetch);
+        let config = Arc::new(config);
+        Ok(Self {
             processor_factory,
-            lease_manager: Arc::new(LeaseManager::new()),
+            config: config.clone(),
+            lease_manager: lease_manager.clone(),
             consumers: Mutex::new(HashMap::new()),
-            kinesis: Arc::new(KinesisClient::new(Region::UsEast1)),
+            worker_context: Arc::new(WorkerContext {
+                config,
+                record_source,
+                lease_manager,
+                checkpoint_store,
+                shard_syncer: shard_syncer.clone(),
+                prefetch_budget,
+            }),
+            stream_consumer,
+            shard_syncer,
+            shard_sync_shutdown: Arc::new(Notify::new()),
+            lease_cleaner,
+            lease_cleanup_shutdown: Arc::new(Notify::new()),
+            hash_range_auditor,
+            hash_range_audit_shutdown: Arc::new(Notify::new()),
             shutdown: Arc::new(Notify::new()),
-        }
+        })
     }
 
     /// TODO
@@ -48,6 +160,32 @@ impl WorkerScheduler {
 
     /// TODO
     pub async fn run(self: Arc<Self>) {
+        // Make sure a brand new application has leases before we go looking for them
+        self.shard_syncer.run_once().await;
+        if let Some(stream_consumer) = &self.stream_consumer {
+            // Saves every worker from waiting on the registration; failures are retried by the
+            // first worker to subscribe
+            if let Err(ex) = stream_consumer.consumer_arn().await {
+                log::warn!("Failed to register the stream consumer: {:?}", ex);
+            }
+        }
+        tokio::spawn(run_at_fixed_interval(
+            self.shard_syncer.clone(),
+            self.config.shard_sync_interval,
+            self.shard_sync_shutdown.clone(),
+        ));
+        // Every worker cleans up until there's a leader to do it; deletes are conditional, so
+        // workers racing each other is harmless
+        tokio::spawn(run_at_fixed_interval(
+            self.lease_cleaner.clone(),
+            self.config.lease_cleanup.interval,
+            self.lease_cleanup_shutdown.clone(),
+        ));
+        tokio::spawn(run_at_fixed_interval(
+            self.hash_range_auditor.clone(),
+            self.config.hash_range_audit_interval,
+            self.hash_range_audit_shutdown.clone(),
+        ));
         self.lease_manager.start();
         run_at_fixed_interval(self.clone(), Duration::from_secs(10), self.shutdown.clone()).await;
     }
@@ -58,6 +196,32 @@ impl WorkerScheduler {
         self.shutdown.notified().await;
     }
 
+    /// What the most recent pass of lease cleanup removed, or would have removed in a dry run.
+    pub async fn last_lease_cleanup(&self) -> Option<LeaseCleanupReport> {
+        self.lease_cleaner.last_report().await
+    }
+
+    /// Whether the open leases covered the stream's whole hash key space at the last check.
+    pub async fn last_hash_range_audit(&self) -> Option<HashRangeAuditReport> {
+        self.hash_range_auditor.last_report().await
+    }
+
+    /// What the worker for each shard this scheduler holds a lease on is doing, keyed by shard ID.
+    pub async fn shard_worker_states(&self) -> HashMap<String, ShardWorkerState> {
+        self.consumers
+            .lock()
+            .await
+            .iter()
+            .map(|(shard, consumer)| (shard.shard_id.clone(), consumer.state()))
+            .collect()
+    }
+
+    /// Set while the lease table holds leases of an earlier stream with the same name. Depending
+    /// on the configured policy, the scheduler has either shut down or paused processing.
+    pub async fn stream_recreation(&self) -> Option<StreamRecreation> {
+        self.shard_syncer.stream_recreation().await
+    }
+
     async fn shutdown_all_consumers(&self) {
         let mut consumers = self.consumers.lock().await;
         let mut handles = Vec::new();
@@ -73,19 +237,42 @@ impl WorkerScheduler {
 #[async_trait]
 impl PeriodicRunnable for WorkerScheduler {
     async fn run_once(&self) {
+        // Step 0: Stop processing if the leases belong to an earlier stream of the same name
+        if self.shard_syncer.stream_recreation().await.is_some() {
+            if self.config.stream_recreation_policy == StreamRecreationPolicy::Fail {
+                // The permit is kept if the loop isn't waiting on it yet
+                self.shutdown.notify_one();
+                return;
+            }
+            // Processing resumes once the periodic sync finds the stale leases dealt with
+            self.shutdown_all_consumers().await;
+            return;
+        }
+
         // Step 1: Launch consumers if we need to
         let mut assigned_shards = HashSet::<ShardInfo>::new();
         {
             let mut consumers_guard = self.consumers.lock().await;
+            // Shards whose workers gave up are handed back, to be retried by whoever takes them
+            // next, at the earliest on the taker's next run
+            let given_up_shards: Vec<ShardInfo> = consumers_guard
+                .iter()
+                .filter(|(_, consumer)| consumer.has_given_up())
+                .map(|(shard, _)| shard.clone())
+                .collect();
+            for shard in given_up_shards {
+                consumers_guard.remove(&shard);
+                self.lease_manager.release_lease(&shard.lease_key).await;
+            }
             for shard in self.lease_manager.get_owned_leases().await {
-                // TODO: don't launch children until parents are done
+                // Workers for child shards wait on their parents before they start processing
                 if !consumers_guard.contains_key(&shard) {
                     consumers_guard.insert(
                         shard.clone(),
                         Arc::new(ShardWorker::new(
                             shard.clone(),
-                            self.kinesis.clone(),
-                            self.processor_factory,
+                            self.worker_context.clone(),
+                            (self.processor_factory)(),
                         )),
                     );
                     consumers_guard
@@ -109,7 +296,7 @@ impl PeriodicRunnable for WorkerScheduler {
                 .filter(|&(shard, _)| !assigned_shards.contains(shard))
             {
                 if !consumer.is_shutdown() {
-                    expiring_consumers.push(consumer.await_shutdown());
+                    expiring_consumers.push(consumer.await_lease_lost());
                 }
                 expired_shards.push(shard.clone());
             }
@@ -126,7 +313,22 @@ impl PeriodicRunnable for WorkerScheduler {
     }
 
     async fn before_shutdown_complete(&self) {
+        self.shard_sync_shutdown.notify_waiters();
+        self.shard_sync_shutdown.notified().await;
+        self.lease_cleanup_shutdown.notify_waiters();
+        self.lease_cleanup_shutdown.notified().await;
+        self.hash_range_audit_shutdown.notify_waiters();
+        self.hash_range_audit_shutdown.notified().await;
         self.shutdown_all_consumers().await;
+        if let Some(fan_out_config) = self.config.retrieval_mode.fan_out_config() {
+            if fan_out_config.deregister_on_shutdown {
+                if let Some(stream_consumer) = &self.stream_consumer {
+                    if let Err(ex) = stream_consumer.deregister().await {
+                        log::warn!("Failed to deregister the stream consumer: {:?}", ex);
+                    }
+                }
+            }
+        }
         self.lease_manager.shutdown().await;
     }
 }
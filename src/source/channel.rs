@@ -0,0 +1,262 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use rusoto_kinesis::{ChildShard, Record, SequenceNumberRange, Shard};
+use tokio::sync::Notify;
+
+use crate::{checkpoint::Checkpoint, interface::processor::RetrievalError};
+
+use super::{is_read_from, unranged_hash_key_range, RecordBatch, RecordSource, ShardRetriever};
+
+/// The most records handed out at once, as with `GetRecords`.
+const MAX_BATCH_RECORDS: usize = 10_000;
+
+/// A shard's records, kept for as long as the source is around so the shard can be read again
+/// from any checkpoint.
+struct ChannelShard {
+    parent_shard_ids: Vec<String>,
+    log: Mutex<ShardLog>,
+    /// Woken whenever a record is added or the shard is closed.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct ShardLog {
+    records: Vec<Record>,
+    closed: bool,
+    children: Vec<ChildShard>,
+}
+
+impl ChannelShard {
+    fn log(&self) -> std::sync::MutexGuard<'_, ShardLog> {
+        self.log.lock().expect("Shard log lock poisoned")
+    }
+}
+
+/// Shards held in memory and fed through a `ShardSender` each, for running processors and leasing
+/// against records a test makes up as it goes.
+pub struct ChannelSource {
+    shards: Mutex<BTreeMap<String, Arc<ChannelShard>>>,
+    next_sequence_number: Arc<AtomicU64>,
+    created_millis: u64,
+}
+
+impl Default for ChannelSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelSource {
+    pub fn new() -> Self {
+        Self {
+            shards: Mutex::new(BTreeMap::new()),
+            next_sequence_number: Arc::new(AtomicU64::new(1)),
+            created_millis: 0,
+        }
+    }
+
+    /// Sets the creation time the source reports for its stream, which is otherwise the epoch.
+    /// Leases left by a source with another creation time are taken to belong to an earlier
+    /// stream, so the stream recreation policy can be exercised by changing it between runs.
+    pub fn with_stream_creation_millis(mut self, created_millis: u64) -> Self {
+        self.created_millis = created_millis;
+        self
+    }
+
+    /// Adds a shard, returning the sender its records are added with. The shard is closed once
+    /// the sender is dropped. Children have to be added before their parents are closed for the
+    /// parents' workers to report them; otherwise they're found by the next shard sync.
+    ///
+    /// # Panics
+    ///
+    /// If a shard with the same ID has already been added.
+    pub fn add_shard(
+        &self,
+        shard_id: impl Into<String>,
+        parent_shard_ids: Vec<String>,
+    ) -> ShardSender {
+        let shard_id = shard_id.into();
+        let mut shards = self.shards.lock().expect("Shard map lock poisoned");
+        assert!(
+            !shards.contains_key(&shard_id),
+            "Shard {} has already been added",
+            shard_id
+        );
+        for parent_shard_id in parent_shard_ids.iter() {
+            if let Some(parent) = shards.get(parent_shard_id) {
+                parent.log().children.push(ChildShard {
+                    hash_key_range: unranged_hash_key_range(),
+                    parent_shards: parent_shard_ids.clone(),
+                    shard_id: shard_id.clone(),
+                });
+            }
+        }
+
+        let shard = Arc::new(ChannelShard {
+            parent_shard_ids,
+            log: Mutex::new(ShardLog::default()),
+            changed: Notify::new(),
+        });
+        shards.insert(shard_id, shard.clone());
+        ShardSender {
+            shard,
+            next_sequence_number: self.next_sequence_number.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl RecordSource for ChannelSource {
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError> {
+        let shards = self.shards.lock().expect("Shard map lock poisoned");
+        Ok(shards
+            .iter()
+            .map(|(shard_id, shard)| {
+                let log = shard.log();
+                Shard {
+                    adjacent_parent_shard_id: shard.parent_shard_ids.get(1).cloned(),
+                    hash_key_range: unranged_hash_key_range(),
+                    parent_shard_id: shard.parent_shard_ids.first().cloned(),
+                    sequence_number_range: SequenceNumberRange {
+                        starting_sequence_number: log
+                            .records
+                            .first()
+                            .map(|record| record.sequence_number.clone())
+                            .unwrap_or_default(),
+                        ending_sequence_number: log
+                            .records
+                            .last()
+                            .filter(|_| log.closed)
+                            .map(|record| record.sequence_number.clone()),
+                    },
+                    shard_id: shard_id.clone(),
+                }
+            })
+            .collect())
+    }
+
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError> {
+        Ok(self.created_millis)
+    }
+
+    fn open_shard(&self, shard_id: &str, position: &Checkpoint) -> Box<dyn ShardRetriever> {
+        let shards = self.shards.lock().expect("Shard map lock poisoned");
+        let shard = shards.get(shard_id).cloned();
+        // Later records are always read, so the position only has to be found among what's
+        // there now
+        let next_index = shard.as_ref().map_or(0, |shard| {
+            let log = shard.log();
+            log.records
+                .iter()
+                .position(|record| is_read_from(record, position))
+                .unwrap_or_else(|| log.records.len())
+        });
+        Box::new(ChannelRetriever {
+            shard_id: shard_id.to_string(),
+            shard,
+            next_index,
+            shard_ended: false,
+        })
+    }
+}
+
+/// Adds records to one of a `ChannelSource`'s shards.
+pub struct ShardSender {
+    shard: Arc<ChannelShard>,
+    next_sequence_number: Arc<AtomicU64>,
+}
+
+impl ShardSender {
+    /// Adds a record to the end of the shard, returning its sequence number.
+    pub fn send(&self, partition_key: impl Into<String>, data: impl Into<Bytes>) -> String {
+        let mut log = self.shard.log();
+        // Taken under the lock, so the shard's records stay in order. Padded so they sort the same
+        // as strings and as numbers.
+        let sequence_number = format!(
+            "{:020}",
+            self.next_sequence_number.fetch_add(1, Ordering::SeqCst)
+        );
+        let arrival = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since_epoch| since_epoch.as_secs_f64());
+        log.records.push(Record {
+            approximate_arrival_timestamp: Some(arrival),
+            data: data.into(),
+            encryption_type: None,
+            partition_key: partition_key.into(),
+            sequence_number: sequence_number.clone(),
+        });
+        drop(log);
+        self.shard.changed.notify_waiters();
+        sequence_number
+    }
+}
+
+impl Drop for ShardSender {
+    fn drop(&mut self) {
+        self.shard.log().closed = true;
+        self.shard.changed.notify_waiters();
+    }
+}
+
+struct ChannelRetriever {
+    shard_id: String,
+    shard: Option<Arc<ChannelShard>>,
+    next_index: usize,
+    shard_ended: bool,
+}
+
+#[async_trait]
+impl ShardRetriever for ChannelRetriever {
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+        if self.shard_ended {
+            return None;
+        }
+        let shard = match self.shard.clone() {
+            Some(shard) => shard,
+            None => {
+                self.shard_ended = true;
+                return Some(Err(RetrievalError::ResourceNotFound(format!(
+                    "No shard {} has been added",
+                    self.shard_id
+                ))));
+            }
+        };
+
+        loop {
+            // Registered before looking, so a record added in between still wakes us up
+            let changed = shard.changed.notified();
+            {
+                let log = shard.log();
+                if self.next_index < log.records.len() || log.closed {
+                    let end_index = log.records.len().min(self.next_index + MAX_BATCH_RECORDS);
+                    let records = log.records[self.next_index..end_index].to_vec();
+                    self.next_index = end_index;
+                    self.shard_ended = log.closed && end_index == log.records.len();
+                    return Some(Ok(RecordBatch {
+                        records,
+                        child_shards: if self.shard_ended {
+                            log.children.clone()
+                        } else {
+                            Vec::new()
+                        },
+                        shard_end: self.shard_ended,
+                        millis_behind_latest: None,
+                        fetched_at: Instant::now(),
+                        cached_at: None,
+                    }));
+                }
+            }
+            changed.await;
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::{collections::VecDeque, io::ErrorKind, path::PathBuf, time::Instant};
+
+use async_trait::async_trait;
+use rusoto_kinesis::{Record, SequenceNumberRange, Shard};
+
+use crate::{checkpoint::Checkpoint, interface::processor::RetrievalError};
+
+use super::{is_read_from, unranged_hash_key_range, RecordBatch, RecordSource, ShardRetriever};
+
+/// The most records handed out at once, as with `GetRecords`.
+const MAX_BATCH_RECORDS: usize = 10_000;
+
+/// Replays records captured to files under a directory. Each `<shard ID>.jsonl` file is a shard,
+/// holding one record per line in the JSON `GetRecords` returns them in, with the data base64
+/// encoded. Shards have no parents and end once their file has been read, so replaying the same
+/// directory again after checkpointing picks up where the last run left off.
+pub struct FileReplaySource {
+    directory: PathBuf,
+}
+
+impl FileReplaySource {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    fn shard_path(&self, shard_id: &str) -> PathBuf {
+        self.directory.join(format!("{}.jsonl", shard_id))
+    }
+}
+
+#[async_trait]
+impl RecordSource for FileReplaySource {
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError> {
+        let mut entries = tokio::fs::read_dir(&self.directory)
+            .await
+            .map_err(|err| RetrievalError::Unrecoverable(err.to_string()))?;
+        let mut shard_ids = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| RetrievalError::Unrecoverable(err.to_string()))?
+        {
+            let path = entry.path();
+            if path.extension() == Some("jsonl".as_ref()) {
+                if let Some(shard_id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    shard_ids.push(shard_id.to_string());
+                }
+            }
+        }
+        shard_ids.sort();
+
+        Ok(shard_ids
+            .into_iter()
+            .map(|shard_id| Shard {
+                adjacent_parent_shard_id: None,
+                hash_key_range: unranged_hash_key_range(),
+                parent_shard_id: None,
+                sequence_number_range: SequenceNumberRange {
+                    starting_sequence_number: String::new(),
+                    ending_sequence_number: None,
+                },
+                shard_id,
+            })
+            .collect())
+    }
+
+    /// Every replay is of the same stream.
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError> {
+        Ok(0)
+    }
+
+    fn open_shard(&self, shard_id: &str, position: &Checkpoint) -> Box<dyn ShardRetriever> {
+        Box::new(FileReplayRetriever {
+            path: self.shard_path(shard_id),
+            position: position.clone(),
+            records: None,
+        })
+    }
+}
+
+struct FileReplayRetriever {
+    path: PathBuf,
+    position: Checkpoint,
+    /// What's left to hand out, once the file has been read.
+    records: Option<VecDeque<Record>>,
+}
+
+impl FileReplayRetriever {
+    async fn read_records(&self) -> Result<VecDeque<Record>, RetrievalError> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(RetrievalError::ResourceNotFound(format!(
+                    "{}: {}",
+                    self.path.display(),
+                    err
+                )))
+            }
+            Err(err) => return Err(RetrievalError::Transient(err.to_string())),
+        };
+
+        let mut records = VecDeque::new();
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(line).map_err(|err| {
+                RetrievalError::Unrecoverable(format!(
+                    "{}:{}: {}",
+                    self.path.display(),
+                    index + 1,
+                    err
+                ))
+            })?;
+            if is_read_from(&record, &self.position) {
+                records.push_back(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[async_trait]
+impl ShardRetriever for FileReplayRetriever {
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+        let remaining = match self.records.as_mut() {
+            // The last batch went out with the end of the shard
+            Some(remaining) if remaining.is_empty() => return None,
+            Some(remaining) => remaining,
+            None => match self.read_records().await {
+                Ok(records) => self.records.insert(records),
+                Err(err) => return Some(Err(err)),
+            },
+        };
+
+        let batch_size = remaining.len().min(MAX_BATCH_RECORDS);
+        let records: Vec<Record> = remaining.drain(..batch_size).collect();
+        Some(Ok(RecordBatch {
+            records,
+            child_shards: Vec::new(),
+            shard_end: remaining.is_empty(),
+            millis_behind_latest: None,
+            fetched_at: Instant::now(),
+            cached_at: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::util::fake_aws;
+
+    fn record_line(sequence_number: &str) -> String {
+        json!({
+            "SequenceNumber": sequence_number,
+            "Data": "ZGF0YQ==",
+            "PartitionKey": "key",
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn replays_shard_files_from_a_checkpoint() {
+        let directory = fake_aws::temp_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        let lines = [
+            record_line("1"),
+            String::new(),
+            record_line("2"),
+            record_line("3"),
+        ];
+        std::fs::write(directory.join("shard-1.jsonl"), lines.join("\n")).unwrap();
+        std::fs::write(directory.join("shard-2.jsonl"), "not a record\n").unwrap();
+        std::fs::write(directory.join("notes.txt"), "").unwrap();
+        let source = FileReplaySource::new(&directory);
+
+        let shard_ids: Vec<String> = source
+            .list_shards()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|shard| shard.shard_id)
+            .collect();
+        assert_eq!(shard_ids, vec!["shard-1", "shard-2"]);
+
+        let mut retriever =
+            source.open_shard("shard-1", &Checkpoint::SequenceNumber("1".to_string()));
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        let sequence_numbers: Vec<&str> = batch
+            .records
+            .iter()
+            .map(|record| record.sequence_number.as_str())
+            .collect();
+        assert_eq!(sequence_numbers, vec!["2", "3"]);
+        assert!(batch.shard_end);
+        assert!(retriever.next_batch().await.is_none());
+
+        assert!(matches!(
+            source
+                .open_shard("shard-2", &Checkpoint::TrimHorizon)
+                .next_batch()
+                .await,
+            Some(Err(RetrievalError::Unrecoverable(_)))
+        ));
+        assert!(matches!(
+            source
+                .open_shard("shard-3", &Checkpoint::TrimHorizon)
+                .next_batch()
+                .await,
+            Some(Err(RetrievalError::ResourceNotFound(_)))
+        ));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
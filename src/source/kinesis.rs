@@ -0,0 +1,1542 @@
+use std::{
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use rusoto_core::{
+    credential::ProvideAwsCredentials, event_stream::EventStream, proto, RusotoError,
+};
+use rusoto_kinesis::{
+    GetRecordsError, GetRecordsInput, GetShardIteratorError, GetShardIteratorInput, Kinesis,
+    KinesisClient, Shard, StartingPosition, SubscribeToShardError, SubscribeToShardEvent,
+    SubscribeToShardEventStreamItem, SubscribeToShardInput,
+};
+
+use crate::{
+    checkpoint::Checkpoint,
+    config::{HybridConfig, PollingConfig, RetrievalMode, SchedulerConfig},
+    interface::processor::RetrievalError,
+    kinesis::{
+        consumer::StreamConsumer, describe_stream_summary, kinesis_client, list_shards, shard_api,
+        ShardApi, StreamDescriptor,
+    },
+    util::{exception::Exception, retry::ExponentialBackoff},
+};
+
+use super::{RecordBatch, RecordSource, ShardRetriever};
+
+const RESUBSCRIBE_BASE_DELAY: Duration = Duration::from_millis(200);
+const RESUBSCRIBE_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Sorts out the failures any Kinesis call can have, leaving the operation's own errors to
+/// `classify_service`.
+fn classify<E: Error + 'static>(
+    err: RusotoError<E>,
+    classify_service: impl FnOnce(E) -> RetrievalError,
+) -> RetrievalError {
+    match err {
+        RusotoError::Service(err) => classify_service(err),
+        RusotoError::HttpDispatch(err) => RetrievalError::Transient(err.to_string()),
+        // Retrying won't fix missing or invalid credentials
+        RusotoError::Credentials(err) => RetrievalError::Unrecoverable(err.to_string()),
+        // Errors common to every operation, such as throttling of the account's control plane
+        // calls or missing IAM permissions, aren't modelled by rusoto
+        RusotoError::Unknown(res) => {
+            let body = String::from_utf8_lossy(&res.body).to_string();
+            let typ = proto::json::Error::parse(&res).map(|err| err.typ);
+            if res.status.is_server_error() {
+                RetrievalError::Transient(body)
+            } else if res.status.as_u16() == 429 {
+                RetrievalError::Throttled(body)
+            } else {
+                match typ.as_deref() {
+                    Some("AccessDeniedException") => RetrievalError::AccessDenied(body),
+                    Some("ThrottlingException")
+                    | Some("LimitExceededException")
+                    | Some("ProvisionedThroughputExceededException") => {
+                        RetrievalError::Throttled(body)
+                    }
+                    _ => RetrievalError::Unrecoverable(body),
+                }
+            }
+        }
+        err => RetrievalError::Unrecoverable(err.to_string()),
+    }
+}
+
+fn from_exception(ex: Exception) -> RetrievalError {
+    match ex {
+        Exception::Retryable(msg) => RetrievalError::Transient(msg),
+        Exception::NonRetryable(msg) => RetrievalError::Unrecoverable(msg),
+    }
+}
+
+/// The final event for a shard carries no continuation sequence number, only its children.
+fn is_shard_end(event: &SubscribeToShardEvent) -> bool {
+    event.continuation_sequence_number.is_empty()
+        && matches!(&event.child_shards, Some(child_shards) if !child_shards.is_empty())
+}
+
+/// Has records pushed to it over enhanced fan-out. A subscription only lasts about five minutes,
+/// so a new one is opened from the last continuation sequence number whenever one ends. One that
+/// fails in a way that can be retried is opened again too, after a backoff, so only the other
+/// failures are returned.
+pub(crate) struct FanOutRetriever {
+    kinesis: Arc<KinesisClient>,
+    consumer: Arc<StreamConsumer>,
+    shard_id: String,
+    starting_position: StartingPosition,
+    continuation_sequence_number: Option<String>,
+    event_stream: Option<EventStream<SubscribeToShardEventStreamItem>>,
+    subscribed_consumer_arn: Option<String>,
+    reregistered: bool,
+    backoff: ExponentialBackoff,
+    shard_ended: bool,
+}
+
+impl FanOutRetriever {
+    pub(crate) fn new(
+        kinesis: Arc<KinesisClient>,
+        consumer: Arc<StreamConsumer>,
+        shard_id: String,
+        starting_position: StartingPosition,
+    ) -> Self {
+        Self {
+            kinesis,
+            consumer,
+            shard_id,
+            starting_position,
+            continuation_sequence_number: None,
+            event_stream: None,
+            subscribed_consumer_arn: None,
+            reregistered: false,
+            backoff: ExponentialBackoff::new(RESUBSCRIBE_BASE_DELAY, RESUBSCRIBE_MAX_DELAY),
+            shard_ended: false,
+        }
+    }
+
+    /// Where the next subscription starts: right after the last event we saw, or where we were
+    /// told to start if there hasn't been one yet.
+    fn next_starting_position(&self) -> StartingPosition {
+        match &self.continuation_sequence_number {
+            Some(sequence_number) => StartingPosition {
+                sequence_number: Some(sequence_number.clone()),
+                timestamp: None,
+                type_: "AFTER_SEQUENCE_NUMBER".to_string(),
+            },
+            None => self.starting_position.clone(),
+        }
+    }
+
+    async fn subscribe(&mut self) -> Result<(), RetrievalError> {
+        let mut reregistered = false;
+        loop {
+            let consumer_arn = self.consumer.consumer_arn().await.map_err(from_exception)?;
+            let input = SubscribeToShardInput {
+                consumer_arn: consumer_arn.clone(),
+                shard_id: self.shard_id.clone(),
+                starting_position: self.next_starting_position(),
+            };
+            match self.kinesis.subscribe_to_shard(input).await {
+                Ok(res) => {
+                    self.event_stream = Some(res.event_stream);
+                    self.subscribed_consumer_arn = Some(consumer_arn);
+                    return Ok(());
+                }
+                // The consumer may have been deleted from under us, so register it again once
+                Err(RusotoError::Service(SubscribeToShardError::ResourceNotFound(_)))
+                    if !reregistered =>
+                {
+                    self.consumer.forget(&consumer_arn).await;
+                    reregistered = true;
+                }
+                Err(err) => {
+                    return Err(classify(err, |err| match err {
+                        SubscribeToShardError::LimitExceeded(msg) => RetrievalError::Throttled(msg),
+                        // The subscription we're replacing, or one held by the lease's previous
+                        // owner, hasn't been released yet
+                        SubscribeToShardError::ResourceInUse(msg) => RetrievalError::Transient(msg),
+                        SubscribeToShardError::ResourceNotFound(msg) => {
+                            RetrievalError::ResourceNotFound(msg)
+                        }
+                        SubscribeToShardError::InvalidArgument(msg) => {
+                            RetrievalError::Unrecoverable(msg)
+                        }
+                    }));
+                }
+            }
+        }
+    }
+
+    /// The next event from the current subscription, subscribing first if there isn't one.
+    async fn next_event(&mut self) -> Option<Result<SubscribeToShardEvent, RetrievalError>> {
+        loop {
+            if self.shard_ended {
+                return None;
+            }
+            let event_stream = match self.event_stream.as_mut() {
+                Some(event_stream) => event_stream,
+                None => {
+                    if let Err(ex) = self.subscribe().await {
+                        return Some(Err(ex));
+                    }
+                    continue;
+                }
+            };
+
+            let item = match event_stream.next().await {
+                Some(Ok(item)) => item,
+                Some(Err(err)) => {
+                    self.event_stream = None;
+                    return Some(Err(RetrievalError::Transient(format!("{:?}", err))));
+                }
+                // The subscription expired, so carry on with a new one
+                None => {
+                    self.event_stream = None;
+                    continue;
+                }
+            };
+            let event = match item {
+                SubscribeToShardEventStreamItem::SubscribeToShardEvent(event) => event,
+                // The consumer was deleted during the subscription, so register it again once,
+                // just as when subscribing
+                SubscribeToShardEventStreamItem::ResourceNotFoundException(_)
+                    if !self.reregistered =>
+                {
+                    self.event_stream = None;
+                    if let Some(consumer_arn) = self.subscribed_consumer_arn.take() {
+                        self.consumer.forget(&consumer_arn).await;
+                    }
+                    self.reregistered = true;
+                    continue;
+                }
+                failure => {
+                    // Any failure ends the subscription
+                    self.event_stream = None;
+                    return Some(Err(classify_event_failure(failure)));
+                }
+            };
+            self.reregistered = false;
+            self.shard_ended = is_shard_end(&event);
+            if !event.continuation_sequence_number.is_empty() {
+                self.continuation_sequence_number =
+                    Some(event.continuation_sequence_number.clone());
+            }
+            return Some(Ok(event));
+        }
+    }
+}
+
+#[async_trait]
+impl ShardRetriever for FanOutRetriever {
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+        loop {
+            let event = match self.next_event().await? {
+                Ok(event) => event,
+                Err(err) if err.is_retryable() => {
+                    log::warn!(
+                        "Subscription to {} failed, subscribing again: {:?}",
+                        self.shard_id,
+                        err
+                    );
+                    tokio::time::sleep(self.backoff.next_delay()).await;
+                    continue;
+                }
+                Err(err) => return Some(Err(err)),
+            };
+            self.backoff.reset();
+            return Some(Ok(RecordBatch {
+                records: event.records,
+                child_shards: event.child_shards.unwrap_or_default(),
+                shard_end: self.shard_ended,
+                millis_behind_latest: Some(event.millis_behind_latest),
+                fetched_at: Instant::now(),
+                cached_at: None,
+            }));
+        }
+    }
+}
+
+fn classify_event_failure(item: SubscribeToShardEventStreamItem) -> RetrievalError {
+    let msg = format!("Subscription failed: {:?}", item);
+    match item {
+        SubscribeToShardEventStreamItem::KMSThrottlingException(_) => {
+            RetrievalError::Throttled(msg)
+        }
+        SubscribeToShardEventStreamItem::InternalFailureException(_)
+        | SubscribeToShardEventStreamItem::ResourceInUseException(_) => {
+            RetrievalError::Transient(msg)
+        }
+        SubscribeToShardEventStreamItem::ResourceNotFoundException(_) => {
+            RetrievalError::ResourceNotFound(msg)
+        }
+        SubscribeToShardEventStreamItem::KMSAccessDeniedException(_)
+        | SubscribeToShardEventStreamItem::KMSDisabledException(_)
+        | SubscribeToShardEventStreamItem::KMSInvalidStateException(_)
+        | SubscribeToShardEventStreamItem::KMSNotFoundException(_)
+        | SubscribeToShardEventStreamItem::KMSOptInRequired(_) => RetrievalError::AccessDenied(msg),
+        SubscribeToShardEventStreamItem::SubscribeToShardEvent(_) => {
+            RetrievalError::Unrecoverable(msg)
+        }
+    }
+}
+
+/// Pulls records with `GetRecords`, waiting the idle interval between calls.
+pub(crate) struct PollingRetriever {
+    shard_api: Arc<dyn ShardApi>,
+    stream_name: String,
+    shard_id: String,
+    config: PollingConfig,
+    starting_position: StartingPosition,
+    shard_iterator: Option<String>,
+    last_sequence_number: Option<String>,
+    last_call_time: Option<tokio::time::Instant>,
+    shard_ended: bool,
+}
+
+impl PollingRetriever {
+    pub(crate) fn new(
+        shard_api: Arc<dyn ShardApi>,
+        stream_name: String,
+        shard_id: String,
+        config: PollingConfig,
+        starting_position: StartingPosition,
+    ) -> Self {
+        Self {
+            shard_api,
+            stream_name,
+            shard_id,
+            config,
+            starting_position,
+            shard_iterator: None,
+            last_sequence_number: None,
+            last_call_time: None,
+            shard_ended: false,
+        }
+    }
+
+    async fn wait_for_next_call(&mut self) {
+        if let Some(last_call_time) = self.last_call_time {
+            let next_call_time = last_call_time + self.config.idle_interval;
+            let now = tokio::time::Instant::now();
+            if next_call_time > now {
+                tokio::time::sleep(next_call_time - now).await;
+            }
+        }
+        self.last_call_time = Some(tokio::time::Instant::now());
+    }
+
+    /// Picks up after the last record we read, so an expired iterator doesn't skip or repeat any.
+    async fn get_shard_iterator(&self) -> Result<String, RetrievalError> {
+        let input = match &self.last_sequence_number {
+            Some(sequence_number) => GetShardIteratorInput {
+                shard_id: self.shard_id.clone(),
+                shard_iterator_type: "AFTER_SEQUENCE_NUMBER".to_string(),
+                starting_sequence_number: Some(sequence_number.clone()),
+                stream_name: self.stream_name.clone(),
+                timestamp: None,
+            },
+            None => GetShardIteratorInput {
+                shard_id: self.shard_id.clone(),
+                shard_iterator_type: self.starting_position.type_.clone(),
+                starting_sequence_number: self.starting_position.sequence_number.clone(),
+                stream_name: self.stream_name.clone(),
+                timestamp: self.starting_position.timestamp,
+            },
+        };
+
+        match self.shard_api.get_shard_iterator(input).await {
+            Ok(res) => res.shard_iterator.ok_or_else(|| {
+                RetrievalError::Unrecoverable("No shard iterator was returned".to_string())
+            }),
+            Err(err) => Err(classify(err, |err| match err {
+                GetShardIteratorError::ProvisionedThroughputExceeded(msg) => {
+                    RetrievalError::Throttled(msg)
+                }
+                GetShardIteratorError::ResourceNotFound(msg) => {
+                    RetrievalError::ResourceNotFound(msg)
+                }
+                GetShardIteratorError::InvalidArgument(msg) => RetrievalError::Unrecoverable(msg),
+            })),
+        }
+    }
+}
+
+#[async_trait]
+impl ShardRetriever for PollingRetriever {
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+        if self.shard_ended {
+            return None;
+        }
+        self.wait_for_next_call().await;
+        let shard_iterator = match self.shard_iterator.take() {
+            Some(shard_iterator) => shard_iterator,
+            None => match self.get_shard_iterator().await {
+                Ok(shard_iterator) => shard_iterator,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+
+        let input = GetRecordsInput {
+            limit: Some(self.config.max_records),
+            shard_iterator: shard_iterator.clone(),
+        };
+        match self.shard_api.get_records(input).await {
+            Ok(res) => {
+                self.shard_ended = res.next_shard_iterator.is_none();
+                self.shard_iterator = res.next_shard_iterator;
+                if let Some(last_record) = res.records.last() {
+                    self.last_sequence_number = Some(last_record.sequence_number.clone());
+                }
+                // Empty batches are passed on too, as they tell us how far behind we are
+                Some(Ok(RecordBatch {
+                    records: res.records,
+                    child_shards: res.child_shards.unwrap_or_default(),
+                    shard_end: self.shard_ended,
+                    millis_behind_latest: res.millis_behind_latest,
+                    fetched_at: Instant::now(),
+                    cached_at: None,
+                }))
+            }
+            Err(err) => {
+                let err = classify(err, |err| match err {
+                    GetRecordsError::ExpiredIterator(msg) => RetrievalError::ExpiredIterator(msg),
+                    GetRecordsError::ProvisionedThroughputExceeded(msg)
+                    | GetRecordsError::KMSThrottling(msg) => RetrievalError::Throttled(msg),
+                    GetRecordsError::ResourceNotFound(msg) => RetrievalError::ResourceNotFound(msg),
+                    GetRecordsError::KMSAccessDenied(msg)
+                    | GetRecordsError::KMSDisabled(msg)
+                    | GetRecordsError::KMSInvalidState(msg)
+                    | GetRecordsError::KMSNotFound(msg)
+                    | GetRecordsError::KMSOptInRequired(msg) => RetrievalError::AccessDenied(msg),
+                    GetRecordsError::InvalidArgument(msg) => RetrievalError::Unrecoverable(msg),
+                });
+                // A new iterator is fetched after an expired one, otherwise the same one is
+                // tried again
+                if !matches!(err, RetrievalError::ExpiredIterator(_)) {
+                    self.shard_iterator = Some(shard_iterator);
+                }
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Switches between polling and fan-out as a shard falls behind and catches up. Each switch picks
+/// up right after the last record returned, so nothing is skipped or read twice.
+pub(crate) struct HybridRetriever {
+    kinesis: Arc<KinesisClient>,
+    shard_api: Arc<dyn ShardApi>,
+    consumer: Arc<StreamConsumer>,
+    stream_name: String,
+    shard_id: String,
+    config: HybridConfig,
+    starting_position: StartingPosition,
+    last_sequence_number: Option<String>,
+    active: Box<dyn ShardRetriever>,
+    fanning_out: bool,
+}
+
+impl HybridRetriever {
+    /// Starts out polling, as a shard that's just been taken is more likely to be behind.
+    ///
+    /// A LATEST start is pinned to the time the shard was opened, so a switch made before any
+    /// record arrives doesn't skip the records written since.
+    pub(crate) fn new(
+        kinesis: Arc<KinesisClient>,
+        shard_api: Arc<dyn ShardApi>,
+        consumer: Arc<StreamConsumer>,
+        stream_name: String,
+        shard_id: String,
+        config: HybridConfig,
+        starting_position: StartingPosition,
+    ) -> Self {
+        let starting_position = pin_latest(starting_position);
+        let active = Box::new(PollingRetriever::new(
+            shard_api.clone(),
+            stream_name.clone(),
+            shard_id.clone(),
+            config.polling.clone(),
+            starting_position.clone(),
+        ));
+        Self {
+            kinesis,
+            shard_api,
+            consumer,
+            stream_name,
+            shard_id,
+            config,
+            starting_position,
+            last_sequence_number: None,
+            active,
+            fanning_out: false,
+        }
+    }
+
+    fn resume_position(&self) -> StartingPosition {
+        match &self.last_sequence_number {
+            Some(sequence_number) => StartingPosition {
+                sequence_number: Some(sequence_number.clone()),
+                timestamp: None,
+                type_: "AFTER_SEQUENCE_NUMBER".to_string(),
+            },
+            None => self.starting_position.clone(),
+        }
+    }
+
+    fn switch(&mut self, fan_out: bool) {
+        let starting_position = self.resume_position();
+        self.active = if fan_out {
+            Box::new(FanOutRetriever::new(
+                self.kinesis.clone(),
+                self.consumer.clone(),
+                self.shard_id.clone(),
+                starting_position,
+            ))
+        } else {
+            Box::new(PollingRetriever::new(
+                self.shard_api.clone(),
+                self.stream_name.clone(),
+                self.shard_id.clone(),
+                self.config.polling.clone(),
+                starting_position,
+            ))
+        };
+        self.fanning_out = fan_out;
+    }
+}
+
+#[async_trait]
+impl ShardRetriever for HybridRetriever {
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+        let result = self.active.next_batch().await?;
+        if let Ok(batch) = &result {
+            if let Some(last_record) = batch.records.last() {
+                self.last_sequence_number = Some(last_record.sequence_number.clone());
+            }
+            // Nothing to switch to at the end of the shard
+            let millis_behind_latest = match batch.millis_behind_latest {
+                Some(millis_behind_latest) if !batch.shard_end => {
+                    millis_behind_latest.max(0) as u128
+                }
+                _ => return Some(result),
+            };
+            if self.fanning_out && millis_behind_latest >= self.config.poll_when_behind.as_millis()
+            {
+                self.switch(false);
+            } else if !self.fanning_out
+                && millis_behind_latest <= self.config.subscribe_when_behind.as_millis()
+            {
+                self.switch(true);
+            }
+        }
+        Some(result)
+    }
+}
+
+/// Swaps a LATEST start for the current time, so a start that's repeated later, after an expired
+/// iterator or a switch, still picks up the records written in between.
+fn pin_latest(starting_position: StartingPosition) -> StartingPosition {
+    if starting_position.type_ != "LATEST" {
+        return starting_position;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    StartingPosition {
+        sequence_number: None,
+        timestamp: Some(now.as_secs_f64()),
+        type_: "AT_TIMESTAMP".to_string(),
+    }
+}
+
+fn starting_position(checkpoint: &Checkpoint) -> StartingPosition {
+    let (type_, sequence_number, timestamp) = match checkpoint {
+        Checkpoint::TrimHorizon => ("TRIM_HORIZON", None, None),
+        Checkpoint::Latest => ("LATEST", None, None),
+        Checkpoint::AtTimestamp(millis) => ("AT_TIMESTAMP", None, Some(*millis as f64 / 1000.0)),
+        Checkpoint::SequenceNumber(sequence_number) => {
+            ("AFTER_SEQUENCE_NUMBER", Some(sequence_number.clone()), None)
+        }
+        Checkpoint::ShardEnd => unreachable!("Shards checkpointed at their end aren't read"),
+    };
+    StartingPosition {
+        sequence_number,
+        timestamp,
+        type_: type_.to_string(),
+    }
+}
+
+/// Lists the stream's shards, whichever way their records are read.
+struct StreamShards {
+    shard_api: Arc<dyn ShardApi>,
+    stream: StreamDescriptor,
+}
+
+impl StreamShards {
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError> {
+        list_shards(self.shard_api.as_ref(), &self.stream)
+            .await
+            .map_err(from_exception)
+    }
+
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError> {
+        let summary = describe_stream_summary(self.shard_api.as_ref(), &self.stream)
+            .await
+            .map_err(from_exception)?;
+        Ok((summary.stream_creation_timestamp * 1000.0).round() as u64)
+    }
+}
+
+/// Reads the stream with `GetRecords`.
+pub struct PollingSource {
+    shards: StreamShards,
+    config: PollingConfig,
+}
+
+impl PollingSource {
+    /// Polls a Kinesis stream or a DynamoDB stream, with `credentials` or else the usual
+    /// credentials chain.
+    pub fn new(
+        stream: StreamDescriptor,
+        config: PollingConfig,
+        credentials: Option<Arc<dyn ProvideAwsCredentials + Send + Sync>>,
+    ) -> Self {
+        let kinesis = Arc::new(kinesis_client(&stream, credentials.clone()));
+        Self {
+            shards: StreamShards {
+                shard_api: shard_api(&stream, kinesis, credentials),
+                stream,
+            },
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl RecordSource for PollingSource {
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError> {
+        self.shards.list_shards().await
+    }
+
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError> {
+        self.shards.stream_creation_millis().await
+    }
+
+    /// A LATEST start is pinned to the time the shard was opened, so an iterator that expires
+    /// before any record arrives doesn't skip the records written since. DynamoDB streams can't
+    /// be read from a timestamp, so theirs is left as it is.
+    fn open_shard(&self, shard_id: &str, position: &Checkpoint) -> Box<dyn ShardRetriever> {
+        let starting_position = if self.shards.stream.is_dynamodb_stream() {
+            starting_position(position)
+        } else {
+            pin_latest(starting_position(position))
+        };
+        Box::new(PollingRetriever::new(
+            self.shards.shard_api.clone(),
+            self.shards.stream.stream_name().to_string(),
+            shard_id.to_string(),
+            self.config.clone(),
+            starting_position,
+        ))
+    }
+}
+
+/// Reads the stream over enhanced fan-out. Its shards are still listed with `ListShards`.
+pub struct FanOutSource {
+    shards: StreamShards,
+    kinesis: Arc<KinesisClient>,
+    consumer: Arc<StreamConsumer>,
+}
+
+impl FanOutSource {
+    /// Subscribes to a Kinesis stream's shards as the consumer `consumer_name`, which is
+    /// registered on the first subscription if it doesn't exist yet. It's left registered when
+    /// the source is dropped.
+    pub fn new(
+        stream: StreamDescriptor,
+        consumer_name: impl Into<String>,
+        credentials: Option<Arc<dyn ProvideAwsCredentials + Send + Sync>>,
+    ) -> Self {
+        let kinesis = Arc::new(kinesis_client(&stream, credentials));
+        Self {
+            consumer: Arc::new(StreamConsumer::new(
+                kinesis.clone(),
+                stream.clone(),
+                consumer_name.into(),
+            )),
+            shards: StreamShards {
+                shard_api: kinesis.clone(),
+                stream,
+            },
+            kinesis,
+        }
+    }
+}
+
+#[async_trait]
+impl RecordSource for FanOutSource {
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError> {
+        self.shards.list_shards().await
+    }
+
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError> {
+        self.shards.stream_creation_millis().await
+    }
+
+    fn open_shard(&self, shard_id: &str, position: &Checkpoint) -> Box<dyn ShardRetriever> {
+        Box::new(FanOutRetriever::new(
+            self.kinesis.clone(),
+            self.consumer.clone(),
+            shard_id.to_string(),
+            starting_position(position),
+        ))
+    }
+}
+
+/// Reads the stream by polling or over enhanced fan-out, depending on how far behind each shard
+/// is.
+pub struct HybridSource {
+    shards: StreamShards,
+    kinesis: Arc<KinesisClient>,
+    consumer: Arc<StreamConsumer>,
+    config: HybridConfig,
+}
+
+impl HybridSource {
+    /// Reads a Kinesis stream, subscribing as the consumer `consumer_name` as with
+    /// `FanOutSource::new`.
+    pub fn new(
+        stream: StreamDescriptor,
+        consumer_name: impl Into<String>,
+        config: HybridConfig,
+        credentials: Option<Arc<dyn ProvideAwsCredentials + Send + Sync>>,
+    ) -> Self {
+        let FanOutSource {
+            shards,
+            kinesis,
+            consumer,
+        } = FanOutSource::new(stream, consumer_name, credentials);
+        Self {
+            shards,
+            kinesis,
+            consumer,
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl RecordSource for HybridSource {
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError> {
+        self.shards.list_shards().await
+    }
+
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError> {
+        self.shards.stream_creation_millis().await
+    }
+
+    fn open_shard(&self, shard_id: &str, position: &Checkpoint) -> Box<dyn ShardRetriever> {
+        Box::new(HybridRetriever::new(
+            self.kinesis.clone(),
+            self.shards.shard_api.clone(),
+            self.consumer.clone(),
+            self.shards.stream.stream_name().to_string(),
+            shard_id.to_string(),
+            self.config.clone(),
+            starting_position(position),
+        ))
+    }
+}
+
+/// The configured stream, read with the configured retrieval mode. `consumer` is set whenever
+/// the mode uses enhanced fan-out.
+pub(crate) fn stream_source(
+    config: &SchedulerConfig,
+    kinesis: Arc<KinesisClient>,
+    shard_api: Arc<dyn ShardApi>,
+    consumer: Option<Arc<StreamConsumer>>,
+) -> Arc<dyn RecordSource> {
+    let shards = StreamShards {
+        shard_api,
+        stream: config.stream.clone(),
+    };
+    let consumer = || consumer.clone().expect("Fan-out without a stream consumer");
+    match &config.retrieval_mode {
+        RetrievalMode::FanOut(_) => Arc::new(FanOutSource {
+            shards,
+            kinesis,
+            consumer: consumer(),
+        }),
+        RetrievalMode::Polling(polling_config) => Arc::new(PollingSource {
+            shards,
+            config: polling_config.clone(),
+        }),
+        RetrievalMode::Hybrid(hybrid_config) => Arc::new(HybridSource {
+            shards,
+            kinesis,
+            consumer: consumer(),
+            config: hybrid_config.clone(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use http::{HeaderMap, StatusCode};
+    use rusoto_core::{credential::CredentialsError, request::BufferedHttpResponse};
+    use rusoto_kinesis::{ChildShard, HashKeyRange};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        kinesis::StreamDescriptor,
+        util::fake_aws::{self, dispatch_error, event_stream, FakeResponse, ScriptedAws},
+    };
+
+    const SEQUENCE_NUMBER: &str = "49590338271490256608559692538361571095921575989136588898";
+    const STREAM_ARN: &str = "arn:aws:kinesis:us-east-1:123456789012:stream/orders";
+    const CONSUMER_ARN: &str =
+        "arn:aws:kinesis:us-east-1:123456789012:stream/orders/consumer/app:1600000000";
+
+    fn records(sequence_numbers: &[&str]) -> Value {
+        Value::Array(
+            sequence_numbers
+                .iter()
+                .map(|sequence_number| {
+                    json!({
+                        "SequenceNumber": sequence_number,
+                        "Data": "ZGF0YQ==",
+                        "PartitionKey": "key",
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    fn active_consumer(consumer_arn: &str) -> FakeResponse {
+        (
+            200,
+            json!({
+                "ConsumerDescription": {
+                    "ConsumerARN": consumer_arn,
+                    "ConsumerCreationTimestamp": 1_600_000_000.0,
+                    "ConsumerName": "app",
+                    "ConsumerStatus": "ACTIVE",
+                    "StreamARN": STREAM_ARN,
+                }
+            }),
+        )
+    }
+
+    fn subscribe_event(
+        sequence_numbers: &[&str],
+        continuation_sequence_number: &str,
+        millis_behind_latest: i64,
+    ) -> (&'static str, Value) {
+        let mut event = json!({
+            "Records": records(sequence_numbers),
+            "ContinuationSequenceNumber": continuation_sequence_number,
+            "MillisBehindLatest": millis_behind_latest,
+        });
+        if continuation_sequence_number.is_empty() {
+            event["ChildShards"] = json!([{
+                "ShardId": "shard-2",
+                "ParentShards": ["shard-1"],
+                "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "1" },
+            }]);
+        }
+        ("SubscribeToShardEvent", event)
+    }
+
+    fn fan_out(script: &ScriptedAws) -> FanOutRetriever {
+        let kinesis = Arc::new(fake_aws::kinesis_client(script.fake()));
+        let consumer = Arc::new(StreamConsumer::new(
+            kinesis.clone(),
+            StreamDescriptor::from_arn(STREAM_ARN).unwrap(),
+            "app".to_string(),
+        ));
+        FanOutRetriever::new(
+            kinesis,
+            consumer,
+            "shard-1".to_string(),
+            StartingPosition {
+                sequence_number: None,
+                timestamp: None,
+                type_: "TRIM_HORIZON".to_string(),
+            },
+        )
+    }
+
+    fn hybrid(script: &ScriptedAws, type_: &str) -> HybridRetriever {
+        let kinesis = Arc::new(fake_aws::kinesis_client(script.fake()));
+        let consumer = Arc::new(StreamConsumer::new(
+            kinesis.clone(),
+            StreamDescriptor::from_arn(STREAM_ARN).unwrap(),
+            "app".to_string(),
+        ));
+        let mut config = HybridConfig::default();
+        config.polling.idle_interval = Duration::from_millis(0);
+        HybridRetriever::new(
+            kinesis.clone(),
+            kinesis,
+            consumer,
+            "orders".to_string(),
+            "shard-1".to_string(),
+            config,
+            StartingPosition {
+                sequence_number: None,
+                timestamp: None,
+                type_: type_.to_string(),
+            },
+        )
+    }
+
+    fn consumer_not_found() -> (&'static str, Value) {
+        (
+            "ResourceNotFoundException",
+            json!({ "message": "Consumer not found" }),
+        )
+    }
+
+    fn polling(script: &ScriptedAws, type_: &str) -> PollingRetriever {
+        PollingRetriever::new(
+            Arc::new(fake_aws::kinesis_client(script.fake())),
+            "orders".to_string(),
+            "shard-1".to_string(),
+            PollingConfig {
+                idle_interval: Duration::from_millis(0),
+                ..PollingConfig::default()
+            },
+            StartingPosition {
+                sequence_number: None,
+                timestamp: None,
+                type_: type_.to_string(),
+            },
+        )
+    }
+
+    fn sequence_numbers(batch: &RecordBatch) -> Vec<&str> {
+        batch
+            .records
+            .iter()
+            .map(|record| record.sequence_number.as_str())
+            .collect()
+    }
+
+    fn unknown_response(status: u16, body: &str) -> RusotoError<GetRecordsError> {
+        RusotoError::Unknown(BufferedHttpResponse {
+            status: StatusCode::from_u16(status).unwrap(),
+            body: Bytes::from(body.to_string()),
+            headers: HeaderMap::<String>::default(),
+        })
+    }
+
+    #[test]
+    fn classifies_failures_every_call_can_have() {
+        let classified = |err| classify(err, |_: GetRecordsError| unreachable!());
+        assert!(matches!(
+            classified(dispatch_error("connection reset")),
+            RetrievalError::Transient(_)
+        ));
+        assert!(matches!(
+            classified(RusotoError::Credentials(CredentialsError::new(
+                "no credentials"
+            ))),
+            RetrievalError::Unrecoverable(_)
+        ));
+        assert!(matches!(
+            classified(unknown_response(503, "Service unavailable")),
+            RetrievalError::Transient(_)
+        ));
+        assert!(matches!(
+            classified(unknown_response(
+                400,
+                r#"{"__type":"AccessDeniedException"}"#
+            )),
+            RetrievalError::AccessDenied(_)
+        ));
+        for throttled in &["ThrottlingException", "LimitExceededException"] {
+            assert!(matches!(
+                classified(unknown_response(
+                    400,
+                    &format!(r#"{{"__type":"{}"}}"#, throttled)
+                )),
+                RetrievalError::Throttled(_)
+            ));
+        }
+        assert!(matches!(
+            classified(unknown_response(400, r#"{"__type":"ValidationException"}"#)),
+            RetrievalError::Unrecoverable(_)
+        ));
+        // Only the error's type counts, not what its message happens to mention
+        assert!(matches!(
+            classified(unknown_response(
+                400,
+                r#"{"__type":"ValidationException","message":"Throttling is not a valid value"}"#
+            )),
+            RetrievalError::Unrecoverable(_)
+        ));
+        assert!(matches!(
+            classified(unknown_response(
+                400,
+                r#"{"__type":"com.amazonaws.kinesis#AccessDeniedException"}"#
+            )),
+            RetrievalError::AccessDenied(_)
+        ));
+        assert!(matches!(
+            classified(unknown_response(429, "Too many requests")),
+            RetrievalError::Throttled(_)
+        ));
+    }
+
+    #[test]
+    fn recognises_the_final_event_of_a_shard() {
+        let child_shard = ChildShard {
+            shard_id: "shard-2".to_string(),
+            parent_shards: vec!["shard-1".to_string()],
+            hash_key_range: HashKeyRange {
+                starting_hash_key: "0".to_string(),
+                ending_hash_key: "99".to_string(),
+            },
+        };
+        let event = SubscribeToShardEvent {
+            continuation_sequence_number: SEQUENCE_NUMBER.to_string(),
+            ..SubscribeToShardEvent::default()
+        };
+        assert!(!is_shard_end(&event));
+        // Children are also reported by events before the end while the shard is being resharded
+        let event = SubscribeToShardEvent {
+            child_shards: Some(vec![child_shard.clone()]),
+            ..event
+        };
+        assert!(!is_shard_end(&event));
+        let event = SubscribeToShardEvent {
+            continuation_sequence_number: String::new(),
+            child_shards: Some(vec![child_shard]),
+            ..SubscribeToShardEvent::default()
+        };
+        assert!(is_shard_end(&event));
+    }
+
+    #[tokio::test]
+    async fn polls_from_after_the_last_record_once_an_iterator_expires() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["1", "2"]),
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("ExpiredIteratorException", "too old"),
+            )
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-3" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["3"]),
+                        "ChildShards": [{
+                            "ShardId": "shard-2",
+                            "ParentShards": ["shard-1"],
+                            "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "1" },
+                        }],
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            );
+        let mut retriever = polling(&script, "LATEST");
+
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1", "2"]);
+        assert!(!batch.shard_end);
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::ExpiredIterator(_)))
+        ));
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["3"]);
+        assert!(batch.shard_end);
+        assert_eq!(batch.child_shards.len(), 1);
+        assert!(retriever.next_batch().await.is_none());
+
+        let iterator_requests = script.requests("GetShardIterator");
+        assert_eq!(iterator_requests[0]["ShardIteratorType"], "LATEST");
+        assert_eq!(
+            iterator_requests[1]["ShardIteratorType"],
+            "AFTER_SEQUENCE_NUMBER"
+        );
+        assert_eq!(iterator_requests[1]["StartingSequenceNumber"], "2");
+    }
+
+    #[tokio::test]
+    async fn keeps_a_latest_start_when_an_iterator_expires_before_any_record() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("ExpiredIteratorException", "too old"),
+            )
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-2" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["1"]),
+                        "NextShardIterator": "it-3",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            );
+        let kinesis = Arc::new(fake_aws::kinesis_client(script.fake()));
+        let source = stream_source(
+            &SchedulerConfig {
+                stream: StreamDescriptor::from_name("orders"),
+                retrieval_mode: RetrievalMode::Polling(PollingConfig {
+                    idle_interval: Duration::from_millis(0),
+                    ..PollingConfig::default()
+                }),
+                ..SchedulerConfig::default()
+            },
+            kinesis.clone(),
+            kinesis,
+            None,
+        );
+        let mut retriever = source.open_shard("shard-1", &Checkpoint::Latest);
+
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::ExpiredIterator(_)))
+        ));
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1"]);
+
+        let iterator_requests = script.requests("GetShardIterator");
+        assert_eq!(iterator_requests[0]["ShardIteratorType"], "AT_TIMESTAMP");
+        assert_eq!(iterator_requests[1]["ShardIteratorType"], "AT_TIMESTAMP");
+        assert_eq!(
+            iterator_requests[1]["Timestamp"],
+            iterator_requests[0]["Timestamp"]
+        );
+    }
+
+    #[tokio::test]
+    async fn classifies_get_records_errors() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("ProvisionedThroughputExceededException", "slow down"),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("KMSAccessDeniedException", "no key"),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["1"]),
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            )
+            .respond(
+                "GetRecords",
+                fake_aws::error("ResourceNotFoundException", "gone"),
+            );
+        let mut retriever = polling(&script, "TRIM_HORIZON");
+
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::Throttled(_)))
+        ));
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::AccessDenied(_)))
+        ));
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1"]);
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::ResourceNotFound(_)))
+        ));
+        // Failed calls are tried again with the same iterator
+        let iterators: Vec<Value> = script
+            .requests("GetRecords")
+            .into_iter()
+            .map(|request| request["ShardIterator"].clone())
+            .collect();
+        assert_eq!(
+            iterators,
+            vec![json!("it-1"), json!("it-1"), json!("it-1"), json!("it-2")]
+        );
+        assert_eq!(script.requests("GetShardIterator").len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_the_idle_interval_between_polls() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [],
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["1"]),
+                        "NextShardIterator": "it-3",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            );
+        let mut retriever = polling(&script, "TRIM_HORIZON");
+        retriever.config.idle_interval = Duration::from_secs(1);
+
+        let start = tokio::time::Instant::now();
+        // Empty responses are handed on too, for how far behind the shard is
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert!(batch.records.is_empty());
+        assert_eq!(batch.millis_behind_latest, Some(0));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1"]);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn registers_the_consumer_again_once_it_is_deleted_mid_subscription() {
+        let new_consumer_arn = format!("{}-new", CONSUMER_ARN);
+        let script = ScriptedAws::default();
+        script
+            .respond("DescribeStreamConsumer", active_consumer(CONSUMER_ARN))
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![subscribe_event(&["1"], "1", 0), consumer_not_found()]),
+            )
+            .respond("DescribeStreamConsumer", active_consumer(&new_consumer_arn))
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![subscribe_event(&["2"], "2", 0), consumer_not_found()]),
+            )
+            .respond("DescribeStreamConsumer", active_consumer(&new_consumer_arn))
+            .respond("SubscribeToShard", event_stream(vec![consumer_not_found()]));
+        let mut retriever = fan_out(&script);
+
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1"]);
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["2"]);
+        // Only once in a row, as a consumer that keeps disappearing won't be fixed by registering
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::ResourceNotFound(_)))
+        ));
+
+        let subscriptions = script.requests("SubscribeToShard");
+        assert_eq!(subscriptions[0]["ConsumerARN"], CONSUMER_ARN);
+        assert_eq!(subscriptions[1]["ConsumerARN"], new_consumer_arn);
+        assert_eq!(
+            subscriptions[1]["StartingPosition"],
+            json!({ "Type": "AFTER_SEQUENCE_NUMBER", "SequenceNumber": "1" })
+        );
+        assert_eq!(script.requests("DescribeStreamConsumer").len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resubscribes_from_the_continuation_sequence_number() {
+        let script = ScriptedAws::default();
+        script
+            .respond("DescribeStreamConsumer", active_consumer(CONSUMER_ARN))
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![subscribe_event(&["1", "2"], "2", 0)]),
+            )
+            // The subscription being replaced hasn't been released yet
+            .respond(
+                "SubscribeToShard",
+                fake_aws::error("ResourceInUseException", "still subscribed"),
+            )
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![subscribe_event(&["3"], "", 0)]),
+            );
+        let mut retriever = fan_out(&script);
+
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1", "2"]);
+        // The first subscription expired, and its replacement is refused until it's released,
+        // which is waited out
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["3"]);
+        assert!(batch.shard_end);
+        assert!(retriever.next_batch().await.is_none());
+
+        let subscriptions = script.requests("SubscribeToShard");
+        assert_eq!(subscriptions.len(), 3);
+        assert_eq!(subscriptions[0]["StartingPosition"]["Type"], "TRIM_HORIZON");
+        for subscription in &subscriptions[1..] {
+            assert_eq!(
+                subscription["StartingPosition"],
+                json!({ "Type": "AFTER_SEQUENCE_NUMBER", "SequenceNumber": "2" })
+            );
+        }
+        // The consumer was only looked up once
+        assert_eq!(script.requests("DescribeStreamConsumer").len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_subscription_failures_until_one_cannot_be_retried() {
+        let script = ScriptedAws::default();
+        script
+            .respond("DescribeStreamConsumer", active_consumer(CONSUMER_ARN))
+            .respond(
+                "SubscribeToShard",
+                fake_aws::error("LimitExceededException", "too many subscriptions"),
+            )
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![
+                    subscribe_event(&["1"], "1", 0),
+                    ("KMSThrottlingException", json!({ "message": "slow down" })),
+                ]),
+            )
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![(
+                    "KMSAccessDeniedException",
+                    json!({ "message": "no key" }),
+                )]),
+            );
+        let mut retriever = fan_out(&script);
+
+        // Throttling is waited out, however it's reported
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1"]);
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::AccessDenied(_)))
+        ));
+        // Each failure ended the subscription, and the next picked up after the last event
+        let subscriptions = script.requests("SubscribeToShard");
+        assert_eq!(
+            subscriptions[2]["StartingPosition"],
+            json!({ "Type": "AFTER_SEQUENCE_NUMBER", "SequenceNumber": "1" })
+        );
+    }
+
+    #[tokio::test]
+    async fn switches_between_polling_and_fan_out_at_the_last_record() {
+        let two_hours = 2 * 60 * 60 * 1000;
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["1"]),
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": two_hours,
+                    }),
+                ),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["2"]),
+                        "NextShardIterator": "it-3",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            )
+            .respond("DescribeStreamConsumer", active_consumer(CONSUMER_ARN))
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![subscribe_event(&["3"], "3", two_hours)]),
+            )
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-4" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": records(&["4"]),
+                        "NextShardIterator": "it-5",
+                        "MillisBehindLatest": two_hours,
+                    }),
+                ),
+            );
+        let mut retriever = hybrid(&script, "TRIM_HORIZON");
+
+        for expected in &["1", "2", "3", "4"] {
+            let batch = retriever.next_batch().await.unwrap().unwrap();
+            assert_eq!(sequence_numbers(&batch), vec![*expected]);
+        }
+        assert_eq!(
+            script.requests("SubscribeToShard")[0]["StartingPosition"],
+            json!({ "Type": "AFTER_SEQUENCE_NUMBER", "SequenceNumber": "2" })
+        );
+        let iterator_requests = script.requests("GetShardIterator");
+        assert_eq!(
+            iterator_requests[1]["ShardIteratorType"],
+            "AFTER_SEQUENCE_NUMBER"
+        );
+        assert_eq!(iterator_requests[1]["StartingSequenceNumber"], "3");
+    }
+
+    #[tokio::test]
+    async fn keeps_a_latest_start_when_switching_before_any_record() {
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [],
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            )
+            .respond("DescribeStreamConsumer", active_consumer(CONSUMER_ARN))
+            .respond(
+                "SubscribeToShard",
+                event_stream(vec![subscribe_event(&["1"], "1", 0)]),
+            );
+        let mut retriever = hybrid(&script, "LATEST");
+
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert!(batch.records.is_empty());
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        assert_eq!(sequence_numbers(&batch), vec!["1"]);
+
+        // Both start from when the shard was opened, rather than each from its own latest
+        let iterator_request = &script.requests("GetShardIterator")[0];
+        let subscription = &script.requests("SubscribeToShard")[0];
+        assert_eq!(iterator_request["ShardIteratorType"], "AT_TIMESTAMP");
+        assert_eq!(subscription["StartingPosition"]["Type"], "AT_TIMESTAMP");
+        assert_eq!(
+            subscription["StartingPosition"]["Timestamp"],
+            iterator_request["Timestamp"]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_the_stream_across_pages() {
+        let script = ScriptedAws::default();
+        let shard = |shard_id: &str| {
+            json!({
+                "ShardId": shard_id,
+                "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                "SequenceNumberRange": { "StartingSequenceNumber": "0" },
+            })
+        };
+        script
+            .respond(
+                "DescribeStreamSummary",
+                fake_aws::stream_summary("orders", 1_600_000_000.5),
+            )
+            .respond(
+                "ListShards",
+                (
+                    200,
+                    json!({ "Shards": [shard("shard-1")], "NextToken": "page-2" }),
+                ),
+            )
+            .respond("ListShards", (200, json!({ "Shards": [shard("shard-2")] })));
+        let kinesis = Arc::new(fake_aws::kinesis_client(script.fake()));
+        let source = stream_source(
+            &SchedulerConfig {
+                stream: StreamDescriptor::from_name("orders"),
+                retrieval_mode: RetrievalMode::Polling(PollingConfig::default()),
+                ..SchedulerConfig::default()
+            },
+            kinesis.clone(),
+            kinesis,
+            None,
+        );
+
+        assert_eq!(
+            source.stream_creation_millis().await.unwrap(),
+            1_600_000_000_500
+        );
+        let shard_ids: Vec<String> = source
+            .list_shards()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|shard| shard.shard_id)
+            .collect();
+        assert_eq!(shard_ids, vec!["shard-1", "shard-2"]);
+        let requests = script.requests("ListShards");
+        assert_eq!(requests[0]["StreamName"], "orders");
+        // The stream name can't be sent along with a token
+        assert_eq!(requests[1]["NextToken"], "page-2");
+        assert_eq!(requests[1].get("StreamName"), None);
+    }
+}
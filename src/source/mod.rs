@@ -0,0 +1,80 @@
+use std::{cmp::Ordering, time::Instant};
+
+use async_trait::async_trait;
+use rusoto_kinesis::{ChildShard, HashKeyRange, Record, Shard};
+
+use crate::{
+    checkpoint::{compare_sequence_numbers, Checkpoint},
+    interface::processor::RetrievalError,
+};
+
+mod channel;
+mod file;
+pub(crate) mod kinesis;
+
+pub use channel::{ChannelSource, ShardSender};
+pub use file::FileReplaySource;
+pub use kinesis::{FanOutSource, HybridSource, PollingSource};
+
+/// Where a scheduler's shards and their records come from. Defaults to the configured stream,
+/// read with the configured retrieval mode; other sources let the same record processors and
+/// leasing run on data from elsewhere, such as captured records in an integration test.
+#[async_trait]
+pub trait RecordSource: Send + Sync {
+    /// Every shard that can still be read, open or closed. A shard that drops out of the listing
+    /// is taken to have been trimmed.
+    async fn list_shards(&self) -> Result<Vec<Shard>, RetrievalError>;
+
+    /// When the stream was created, in milliseconds since the epoch. Leases created with a
+    /// different value are taken to belong to an earlier stream with the same name.
+    async fn stream_creation_millis(&self) -> Result<u64, RetrievalError>;
+
+    /// Starts reading a shard from a checkpoint, which is never `Checkpoint::ShardEnd`. Failing to
+    /// open the shard is reported by the first `next_batch`.
+    fn open_shard(&self, shard_id: &str, position: &Checkpoint) -> Box<dyn ShardRetriever>;
+}
+
+/// Records read from a shard in one go, however they were retrieved.
+pub struct RecordBatch {
+    pub records: Vec<Record>,
+    /// The shards that took over from this one, reported with the last batch.
+    pub child_shards: Vec<ChildShard>,
+    /// The shard was closed and this is the last of its records.
+    pub shard_end: bool,
+    pub millis_behind_latest: Option<i64>,
+    pub fetched_at: Instant,
+    /// When the batch was put in the prefetch buffer. Sources leave this unset.
+    pub cached_at: Option<Instant>,
+}
+
+/// Reads a shard's records from a starting position onwards.
+#[async_trait]
+pub trait ShardRetriever: Send {
+    /// Returns `None` once there's nothing more to read. After a retryable error the next call
+    /// carries on from where the last batch left off.
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>>;
+}
+
+/// Whether reading a shard from a position includes the record, going by its arrival time for
+/// timestamps.
+pub(crate) fn is_read_from(record: &Record, position: &Checkpoint) -> bool {
+    match position {
+        Checkpoint::TrimHorizon => true,
+        Checkpoint::Latest | Checkpoint::ShardEnd => false,
+        Checkpoint::AtTimestamp(millis) => match record.approximate_arrival_timestamp {
+            Some(arrival) => (arrival * 1000.0) as u64 >= *millis,
+            None => true,
+        },
+        Checkpoint::SequenceNumber(sequence_number) => {
+            compare_sequence_numbers(&record.sequence_number, sequence_number) == Ordering::Greater
+        }
+    }
+}
+
+/// Shards outside Kinesis have no hash key ranges, which leaves them out of the hash range audit.
+pub(crate) fn unranged_hash_key_range() -> HashKeyRange {
+    HashKeyRange {
+        starting_hash_key: String::new(),
+        ending_hash_key: String::new(),
+    }
+}
@@ -0,0 +1,68 @@
+/// The outcome of the most recent pass of lease cleanup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeaseCleanupReport {
+    /// Leases for shards that were finished and whose children have all started.
+    pub completed: Vec<String>,
+    /// Leases for shards the stream no longer lists.
+    pub trimmed: Vec<String>,
+    /// Whether the leases were only reported, rather than removed.
+    pub dry_run: bool,
+}
+
+/// An inclusive range of hash keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashKeyRange {
+    pub start: u128,
+    pub end: u128,
+}
+
+impl HashKeyRange {
+    pub fn contains(&self, hash_key: u128) -> bool {
+        self.start <= hash_key && hash_key <= self.end
+    }
+
+    pub fn overlaps(&self, other: &HashKeyRange) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+/// The outcome of the most recent check that open leases cover the whole hash key space.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashRangeAuditReport {
+    /// Hash keys that no open lease covers.
+    pub gaps: Vec<HashKeyRange>,
+    /// Whether gaps were looked for at all. They can't be told apart from shards left out by a
+    /// shard ID or predicate filter, or from what a lease without a hash key range covers.
+    pub gaps_checked: bool,
+    /// Pairs of open leases whose hash key ranges overlap.
+    pub overlaps: Vec<(String, String)>,
+    /// Open leases that were left out of the check because they don't record a hash key range.
+    pub leases_without_range: Vec<String>,
+    /// When gaps were found, how many leases the resync that followed created.
+    pub resync_created_leases: Option<usize>,
+}
+
+impl HashRangeAuditReport {
+    pub fn is_consistent(&self) -> bool {
+        self.gaps.is_empty() && self.overlaps.is_empty()
+    }
+}
+
+/// Leases that were created for an earlier stream of the same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamRecreation {
+    /// When the current stream was created, in milliseconds since the epoch.
+    pub stream_creation_millis: u64,
+    pub stale_leases: Vec<String>,
+}
+
+/// What a shard's worker is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardWorkerState {
+    /// A parent shard hasn't been processed to its end yet.
+    WaitingOnParents,
+    Initializing,
+    Processing,
+    ShuttingDown,
+    ShutDown,
+}
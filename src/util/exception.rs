@@ -1,5 +1,20 @@
+use crate::interface::processor::RetrievalError;
+
 #[allow(dead_code)]
+#[derive(Debug)]
 pub(crate) enum Exception {
     Retryable(String),
     NonRetryable(String),
 }
+
+impl From<RetrievalError> for Exception {
+    fn from(err: RetrievalError) -> Self {
+        let retryable = err.is_retryable();
+        let msg = format!("{:?}", err);
+        if retryable {
+            Exception::Retryable(msg)
+        } else {
+            Exception::NonRetryable(msg)
+        }
+    }
+}
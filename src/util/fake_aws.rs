@@ -0,0 +1,397 @@
+//! Stands in for AWS in tests, answering the requests rusoto clients make without a network.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dynomite::{
+    dynamodb::{AttributeValue, DynamoDbClient},
+    FromAttributes,
+};
+use http::{HeaderMap, StatusCode};
+use rusoto_core::{Region, RusotoError};
+use rusoto_kinesis::KinesisClient;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::{PollingConfig, RetrievalMode, SchedulerConfig},
+    kinesis::StreamDescriptor,
+    lease::Lease,
+    source::{kinesis::stream_source, RecordSource},
+};
+
+/// What a fake service answers a request with: a status code and a JSON body, or an
+/// [`event_stream`].
+pub(crate) type FakeResponse = (u16, Value);
+
+type Handler = dyn Fn(&str, Value) -> FakeResponse + Send + Sync;
+
+/// Hands every request to a handler, along with the operation named by its `x-amz-target`
+/// header.
+#[derive(Clone)]
+pub(crate) struct FakeAws {
+    handler: Arc<Handler>,
+}
+
+impl FakeAws {
+    pub(crate) fn new(
+        handler: impl Fn(&str, Value) -> FakeResponse + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            handler: Arc::new(handler),
+        }
+    }
+
+    fn respond(&self, target: &str, payload: &[u8]) -> (StatusCode, Vec<u8>) {
+        let body = serde_json::from_slice(payload).unwrap_or(Value::Null);
+        // Only the operation's name matters, not the API version it's prefixed with
+        let operation = target.rsplit('.').next().unwrap_or(target);
+        let (status, body) = (self.handler)(operation, body);
+        let body = match body.get(EVENT_STREAM) {
+            Some(Value::Array(events)) => events.iter().flat_map(encode_event).collect(),
+            _ => body.to_string().into_bytes(),
+        };
+        (
+            StatusCode::from_u16(status).expect("Invalid status code"),
+            body,
+        )
+    }
+}
+
+/// Marks a body to be sent as an event stream rather than as JSON.
+const EVENT_STREAM: &str = "__event_stream";
+
+/// An event stream, such as `SubscribeToShard` answers with, made of `(event type, payload)`
+/// pairs. Exceptions are sent as events too, named after their type.
+pub(crate) fn event_stream(events: Vec<(&str, Value)>) -> FakeResponse {
+    let events = events
+        .into_iter()
+        .map(|(event_type, payload)| json!([event_type, payload]))
+        .collect::<Vec<_>>();
+    (200, json!({ EVENT_STREAM: events }))
+}
+
+/// Frames an event the way the event stream encoding does: a prelude of lengths and its CRC,
+/// the headers, the payload, then the CRC of the whole message.
+fn encode_event(event: &Value) -> Vec<u8> {
+    let event_type = event[0].as_str().expect("Events are named");
+    let payload = event[1].to_string().into_bytes();
+
+    let mut headers = Vec::new();
+    for (name, value) in &[(":event-type", event_type), (":message-type", "event")] {
+        headers.push(name.len() as u8);
+        headers.extend_from_slice(name.as_bytes());
+        // String values
+        headers.push(7);
+        headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        headers.extend_from_slice(value.as_bytes());
+    }
+
+    let total_length = 12 + headers.len() + payload.len() + 4;
+    let mut message = Vec::with_capacity(total_length);
+    message.extend_from_slice(&(total_length as u32).to_be_bytes());
+    message.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+    let prelude_crc = crc32fast::hash(&message);
+    message.extend_from_slice(&prelude_crc.to_be_bytes());
+    message.extend_from_slice(&headers);
+    message.extend_from_slice(&payload);
+    let message_crc = crc32fast::hash(&message);
+    message.extend_from_slice(&message_crc.to_be_bytes());
+    message
+}
+
+/// An error as the JSON protocols report it.
+pub(crate) fn error(error_type: &str, message: &str) -> FakeResponse {
+    (400, json!({ "__type": error_type, "message": message }))
+}
+
+/// `DescribeStreamSummary`'s answer for an active stream created at `created_secs`.
+pub(crate) fn stream_summary(stream_name: &str, created_secs: f64) -> FakeResponse {
+    (
+        200,
+        json!({
+            "StreamDescriptionSummary": {
+                "EnhancedMonitoring": [],
+                "OpenShardCount": 1,
+                "RetentionPeriodHours": 24,
+                "StreamARN": format!("arn:aws:kinesis:us-east-1:123456789012:stream/{}", stream_name),
+                "StreamCreationTimestamp": created_secs,
+                "StreamName": stream_name,
+                "StreamStatus": "ACTIVE",
+            }
+        }),
+    )
+}
+
+/// A directory of the test's own, which isn't created until the test writes to it.
+pub(crate) fn temp_directory() -> PathBuf {
+    std::env::temp_dir().join(format!("kinesis_kcl-test-{:016x}", rand::random::<u64>()))
+}
+
+/// Both rusoto versions in use dispatch requests the same way, through types of their own.
+macro_rules! impl_dispatch {
+    ($core:ident) => {
+        impl $core::DispatchSignedRequest for FakeAws {
+            fn dispatch(
+                &self,
+                request: $core::signature::SignedRequest,
+                _timeout: Option<Duration>,
+            ) -> $core::request::DispatchSignedRequestFuture {
+                let target = request
+                    .headers
+                    .get("x-amz-target")
+                    .and_then(|values| values.first())
+                    .map(|value| String::from_utf8_lossy(value).to_string())
+                    .unwrap_or_default();
+                let payload = match &request.payload {
+                    Some($core::signature::SignedRequestPayload::Buffer(bytes)) => bytes.to_vec(),
+                    _ => Vec::new(),
+                };
+                let (status, body) = self.respond(&target, &payload);
+                Box::pin(async move {
+                    Ok($core::request::HttpResponse {
+                        status,
+                        body: $core::ByteStream::from(body),
+                        headers: HeaderMap::<String>::default(),
+                    })
+                })
+            }
+        }
+    };
+}
+
+impl_dispatch!(rusoto_core);
+impl_dispatch!(rusoto_core_dynamodb);
+
+pub(crate) fn kinesis_client(fake: FakeAws) -> KinesisClient {
+    KinesisClient::new_with(
+        fake,
+        rusoto_core::credential::StaticProvider::new_minimal("fake".into(), "fake".into()),
+        Region::UsEast1,
+    )
+}
+
+pub(crate) fn dynamodb_client(fake: FakeAws) -> DynamoDbClient {
+    DynamoDbClient::new_with(
+        fake,
+        rusoto_core_dynamodb::credential::StaticProvider::new_minimal("fake".into(), "fake".into()),
+        rusoto_core_dynamodb::Region::UsEast1,
+    )
+}
+
+/// The stream as a scheduler polling it would see it, through `script`.
+pub(crate) fn polled_stream(
+    script: &ScriptedAws,
+    stream: StreamDescriptor,
+) -> Arc<dyn RecordSource> {
+    let kinesis = Arc::new(kinesis_client(script.fake()));
+    stream_source(
+        &SchedulerConfig {
+            stream,
+            retrieval_mode: RetrievalMode::Polling(PollingConfig::default()),
+            ..SchedulerConfig::default()
+        },
+        kinesis.clone(),
+        kinesis,
+        None,
+    )
+}
+
+/// Makes a rusoto error for a call that never reached the service.
+pub(crate) fn dispatch_error<E>(message: &str) -> RusotoError<E> {
+    RusotoError::HttpDispatch(rusoto_core::request::HttpDispatchError::new(
+        message.to_string(),
+    ))
+}
+
+/// Answers each operation with the responses queued for it, in order, and keeps every request
+/// it was sent. An operation with nothing left to answer fails the test.
+#[derive(Clone, Default)]
+pub(crate) struct ScriptedAws {
+    responses: Arc<Mutex<HashMap<String, VecDeque<FakeResponse>>>>,
+    requests: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl ScriptedAws {
+    pub(crate) fn respond(&self, operation: &str, response: FakeResponse) -> &Self {
+        self.responses
+            .lock()
+            .expect("Script lock poisoned")
+            .entry(operation.to_string())
+            .or_default()
+            .push_back(response);
+        self
+    }
+
+    /// The bodies of the requests made for `operation`, oldest first.
+    pub(crate) fn requests(&self, operation: &str) -> Vec<Value> {
+        self.requests
+            .lock()
+            .expect("Script lock poisoned")
+            .iter()
+            .filter(|(requested, _)| requested == operation)
+            .map(|(_, body)| body.clone())
+            .collect()
+    }
+
+    pub(crate) fn fake(&self) -> FakeAws {
+        let script = self.clone();
+        FakeAws::new(move |operation, body| {
+            script
+                .requests
+                .lock()
+                .expect("Script lock poisoned")
+                .push((operation.to_string(), body));
+            script
+                .responses
+                .lock()
+                .expect("Script lock poisoned")
+                .get_mut(operation)
+                .and_then(VecDeque::pop_front)
+                .unwrap_or_else(|| panic!("Nothing scripted for {}", operation))
+        })
+    }
+}
+
+type Item = Map<String, Value>;
+
+/// A lease table kept in memory, supporting the expressions the lease broker writes. Items are
+/// keyed by `lease_key`.
+#[derive(Clone, Default)]
+pub(crate) struct FakeLeaseTable {
+    items: Arc<Mutex<HashMap<String, Item>>>,
+}
+
+impl FakeLeaseTable {
+    pub(crate) fn client(&self) -> DynamoDbClient {
+        let table = self.clone();
+        dynamodb_client(FakeAws::new(move |operation, body| {
+            table.handle(operation, body)
+        }))
+    }
+
+    pub(crate) fn put(&self, lease: &Lease) {
+        let attrs: HashMap<String, AttributeValue> = lease.clone().into();
+        let item = match serde_json::to_value(attrs).expect("Leases always serialize") {
+            Value::Object(item) => item,
+            _ => unreachable!("Attribute maps serialize to objects"),
+        };
+        self.items().insert(lease.lease_key.clone(), item);
+    }
+
+    pub(crate) fn get(&self, lease_key: &str) -> Option<Lease> {
+        self.items().get(lease_key).map(to_lease)
+    }
+
+    pub(crate) fn lease_keys(&self) -> Vec<String> {
+        let mut lease_keys: Vec<String> = self.items().keys().cloned().collect();
+        lease_keys.sort();
+        lease_keys
+    }
+
+    fn items(&self) -> std::sync::MutexGuard<'_, HashMap<String, Item>> {
+        self.items.lock().expect("Fake table lock poisoned")
+    }
+
+    fn handle(&self, operation: &str, body: Value) -> FakeResponse {
+        let values = body
+            .get("ExpressionAttributeValues")
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        let condition = body.get("ConditionExpression").and_then(Value::as_str);
+        let key = body
+            .get("Key")
+            .or_else(|| body.get("Item"))
+            .and_then(|key| key["lease_key"]["S"].as_str())
+            .map(str::to_string);
+        let mut items = self.items();
+
+        match operation {
+            "Scan" => {
+                let items: Vec<&Item> = items.values().collect();
+                (200, json!({ "Items": items, "Count": items.len() }))
+            }
+            "GetItem" => match key.and_then(|key| items.get(&key)) {
+                Some(item) => (200, json!({ "Item": item })),
+                None => (200, json!({})),
+            },
+            "PutItem" | "UpdateItem" | "DeleteItem" => {
+                let key = key.expect("Write without a lease key");
+                let existing = items.get(&key);
+                if !condition.is_none_or(|condition| matches(condition, existing, &values)) {
+                    return error(
+                        "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                        "The conditional request failed",
+                    );
+                }
+                match operation {
+                    "PutItem" => {
+                        let item = body["Item"].as_object().cloned().unwrap_or_default();
+                        items.insert(key, item);
+                    }
+                    "UpdateItem" => {
+                        let mut item = existing.cloned().unwrap_or_else(|| {
+                            let mut item = Map::new();
+                            item.insert("lease_key".to_string(), json!({ "S": key.clone() }));
+                            item
+                        });
+                        let update = body["UpdateExpression"].as_str().unwrap_or_default();
+                        apply_update(update, &mut item, &values);
+                        items.insert(key, item);
+                    }
+                    _ => {
+                        items.remove(&key);
+                    }
+                }
+                (200, json!({}))
+            }
+            _ => error("UnknownOperationException", operation),
+        }
+    }
+}
+
+fn to_lease(item: &Item) -> Lease {
+    let attrs: HashMap<String, AttributeValue> =
+        serde_json::from_value(Value::Object(item.clone())).expect("Items are attribute maps");
+    Lease::from_attrs(attrs).expect("Items are leases")
+}
+
+/// Evaluates conditions of the form `a = :a AND attribute_not_exists(b)`.
+fn matches(condition: &str, item: Option<&Item>, values: &Item) -> bool {
+    condition.split(" AND ").all(|term| {
+        let term = term.trim();
+        if let Some(name) = term
+            .strip_prefix("attribute_not_exists(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            return !matches!(item, Some(item) if item.contains_key(name));
+        }
+        let (name, value) = term.split_once(" = ").expect("Unsupported condition");
+        matches!(item, Some(item) if item.get(name) == values.get(value))
+    })
+}
+
+/// Applies updates of the form `SET a = :a, b = b + :one`.
+fn apply_update(update: &str, item: &mut Item, values: &Item) {
+    let assignments = update.strip_prefix("SET ").expect("Unsupported update");
+    for assignment in assignments.split(", ") {
+        let (name, expression) = assignment.split_once(" = ").expect("Unsupported update");
+        let value = match expression.split_once(" + ") {
+            Some((_, increment)) => {
+                let number = |value: Option<&Value>| {
+                    value
+                        .and_then(|value| value["N"].as_str())
+                        .and_then(|number| number.parse::<u64>().ok())
+                        .unwrap_or(0)
+                };
+                json!({ "N": (number(item.get(name)) + number(values.get(increment))).to_string() })
+            }
+            None => values[expression].clone(),
+        };
+        item.insert(name.to_string(), value);
+    }
+}
@@ -1,3 +1,5 @@
 pub(crate) mod exception;
+#[cfg(test)]
+pub(crate) mod fake_aws;
 pub(crate) mod retry;
 pub(crate) mod runnable;
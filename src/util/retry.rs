@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use futures_retry::{ErrorHandler, RetryPolicy};
+use rand::Rng;
 
 use super::exception::Exception;
 
@@ -32,3 +33,72 @@ impl ErrorHandler<Exception> for FixedCountWithDelayStrategy {
         }
     }
 }
+
+/// Exponential backoff with full jitter: each delay is picked at random below a ceiling that
+/// doubles with every attempt, up to `max`, so workers that failed together don't retry together.
+pub(crate) struct ExponentialBackoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl ExponentialBackoff {
+    pub(crate) fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(2_u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        ceiling.mul_f64(rand::thread_rng().gen())
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_ceiling_doubles_up_to_the_max() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+        let mut backoff = ExponentialBackoff::new(base, max);
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            assert!(backoff.next_delay() <= Duration::from_millis(ceiling));
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+
+    #[test]
+    fn backoff_delays_are_jittered() {
+        let mut delays = std::collections::HashSet::new();
+        for _ in 0..20 {
+            let mut backoff =
+                ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(1));
+            delays.insert(backoff.next_delay());
+        }
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn backoff_survives_many_attempts() {
+        let max = Duration::from_secs(30);
+        let mut backoff = ExponentialBackoff::new(Duration::from_millis(200), max);
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= max);
+        }
+    }
+}
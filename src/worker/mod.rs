@@ -1,109 +1,1307 @@
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, RwLock,
 };
 
-use futures::StreamExt;
-use rusoto_kinesis::{
-    Kinesis, KinesisClient, StartingPosition, SubscribeToShardEventStreamItem,
-    SubscribeToShardInput,
+use rusoto_kinesis::ChildShard;
+
+use tokio::{
+    sync::{Notify, Semaphore},
+    time::Instant,
 };
-use tokio::sync::Notify;
 
 use crate::{
+    checkpoint::{
+        load_checkpoint, AutoCheckpointTracker, Checkpoint, CheckpointError, CheckpointMode,
+        CheckpointStore, RecordProcessorCheckpointer,
+    },
+    config::SchedulerConfig,
     interface::{
-        processor::{InitializationInput, ProcessRecordsInput, RecordProcessor},
+        processor::{
+            InitializationInput, ProcessRecordsInput, RecordProcessor, RetrievalError,
+            RetrievalFailedInput, ShardEndedInput,
+        },
         record::KinesisClientRecord,
     },
-    lease::ShardInfo,
+    lease::{manager::LeaseManager, syncer::ShardSyncer, ShardInfo},
+    source::{RecordSource, ShardRetriever},
+    status::ShardWorkerState,
 };
 
+mod prefetch;
+mod shaping;
+
+pub(crate) use prefetch::prefetch_budget;
+use prefetch::PrefetchingRetriever;
+use shaping::{BatchShaper, ShapedBatch};
+
+/// Everything a `ShardWorker` shares with the scheduler and the other workers.
+pub(crate) struct WorkerContext {
+    pub(crate) config: Arc<SchedulerConfig>,
+    pub(crate) record_source: Arc<dyn RecordSource>,
+    pub(crate) lease_manager: Arc<LeaseManager>,
+    pub(crate) checkpoint_store: Arc<dyn CheckpointStore>,
+    pub(crate) shard_syncer: Arc<ShardSyncer>,
+    /// Bytes of record data every shard's prefetch buffer draws from.
+    pub(crate) prefetch_budget: Arc<Semaphore>,
+}
+
 pub(crate) struct ShardWorker {
     shard_info: ShardInfo,
     record_processor: Box<dyn RecordProcessor>,
-
-    kinesis: Arc<KinesisClient>,
+    context: Arc<WorkerContext>,
+    state: RwLock<ShardWorkerState>,
 
     should_shutdown: AtomicBool,
+    /// Wakes the worker up while it waits on its parents or for records.
+    shutdown_requested: Notify,
+    lease_lost: AtomicBool,
+    /// Set when the worker stopped on its own without finishing its shard, so its lease should be
+    /// given up for the shard to be tried again.
+    gave_up: AtomicBool,
     shutdown: Notify,
-    is_shutdown: AtomicBool,
+}
+
+/// Never finishes when there's no deadline.
+async fn deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
 }
 
 impl ShardWorker {
     pub(crate) fn new(
         shard_info: ShardInfo,
-        kinesis: Arc<KinesisClient>,
-        factory: fn() -> Box<dyn RecordProcessor>,
+        context: Arc<WorkerContext>,
+        record_processor: Box<dyn RecordProcessor>,
     ) -> Self {
         Self {
             shard_info,
-            record_processor: factory(),
-            kinesis,
+            record_processor,
+            context,
+            state: RwLock::new(ShardWorkerState::WaitingOnParents),
             should_shutdown: AtomicBool::new(false),
+            shutdown_requested: Notify::new(),
+            lease_lost: AtomicBool::new(false),
+            gave_up: AtomicBool::new(false),
             shutdown: Notify::new(),
-            is_shutdown: AtomicBool::new(false),
         }
     }
 
     pub(crate) fn start(self: Arc<Self>) {
         tokio::spawn(async move {
+            if !self.wait_for_parents().await {
+                self.set_state(ShardWorkerState::ShutDown);
+                self.shutdown.notify_waiters();
+                return;
+            }
+            self.set_state(ShardWorkerState::Initializing);
+
+            let initial_checkpoint = match load_checkpoint(
+                self.context.checkpoint_store.as_ref(),
+                &self.context.lease_manager,
+                &self.shard_info.lease_key,
+            )
+            .await
+            {
+                Ok(checkpoint) => checkpoint,
+                Err(err) => {
+                    log::error!(
+                        "Failed to load the checkpoint for {}: {}",
+                        self.shard_info.lease_key,
+                        err
+                    );
+                    // Starting from anywhere else risks skipping or replaying data
+                    self.gave_up.store(true, Ordering::SeqCst);
+                    self.set_state(ShardWorkerState::ShutDown);
+                    self.shutdown.notify_waiters();
+                    return;
+                }
+            };
+            let checkpointer = Arc::new(RecordProcessorCheckpointer::new(
+                self.shard_info.lease_key.clone(),
+                initial_checkpoint.clone(),
+                self.context.config.checkpoint_coalescing_window,
+                self.context.checkpoint_store.clone(),
+                self.context.lease_manager.clone(),
+            ));
+            let mut auto_checkpoint = match &self.context.config.checkpoint_mode {
+                CheckpointMode::Automatic(auto_config) => {
+                    Some(AutoCheckpointTracker::new(auto_config.clone()))
+                }
+                CheckpointMode::Manual => None,
+            };
+
             self.record_processor
                 .initialize(InitializationInput {
                     shard_id: self.shard_info.shard_id.clone(),
-                    // TODO: checkpoint stuff
+                    checkpoint: initial_checkpoint.clone(),
                     pending_checkpoint_state: None,
                 })
                 .await;
+            self.set_state(ShardWorkerState::Processing);
 
-            let mut res = self
-                .kinesis
-                .subscribe_to_shard(SubscribeToShardInput {
-                    consumer_arn: "TODO".to_string(),
-                    shard_id: self.shard_info.shard_id.clone(),
-                    starting_position: StartingPosition {
-                        sequence_number: None,
-                        timestamp: None,
-                        type_: "TRIM_HORIZON".to_string(),
-                    },
-                })
-                .await
-                .expect("I don't like errors");
+            let mut shard_ended = false;
+            let mut retrieval_error = None;
+            let mut child_leases_created = false;
+            if initial_checkpoint != Checkpoint::ShardEnd {
+                // Fan-out subscriptions are renewed within the retriever, so the same processor
+                // sees the shard through for as long as we hold the lease
+                let mut retriever = self.open_retriever(&initial_checkpoint);
+                let idle_batch_interval = self.context.config.idle_batch_interval;
+                let mut shaper = BatchShaper::new(self.context.config.batch_shaping.clone());
+                let mut last_delivery = Instant::now();
+                let mut millis_behind_latest = None;
 
-            while let Some(Ok(item)) = res.event_stream.next().await {
-                if self.should_shutdown.load(Ordering::SeqCst) {
-                    break;
-                }
-                match item {
-                    SubscribeToShardEventStreamItem::SubscribeToShardEvent(event) => {
-                        let records = event
+                loop {
+                    let idle_deadline = idle_batch_interval
+                        .filter(|_| shaper.is_empty())
+                        .map(|interval| last_delivery + interval);
+                    let flush_deadline = checkpointer.flush_deadline().await.map(Instant::from_std);
+                    let auto_checkpoint_deadline = auto_checkpoint
+                        .as_ref()
+                        .and_then(AutoCheckpointTracker::deadline)
+                        .map(Instant::from_std);
+                    let (batch, idle) = tokio::select! {
+                        next = retriever.next_batch() => match next {
+                            Some(Ok(batch)) => (Some(batch), false),
+                            // Anything retryable has already been retried
+                            Some(Err(err)) => {
+                                retrieval_error = Some(err);
+                                break;
+                            }
+                            None => break,
+                        },
+                        _ = deadline(idle_deadline) => (None, true),
+                        _ = deadline(shaper.linger_deadline()) => (None, false),
+                        // Quiet shards still get their held back and automatic checkpoints written
+                        _ = deadline(flush_deadline) => (None, false),
+                        _ = deadline(auto_checkpoint_deadline) => (None, false),
+                        _ = self.shutdown_requested.notified() => break,
+                    };
+                    if self.should_shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let mut child_shards = Vec::new();
+                    let mut empty_fetch = None;
+                    if let Some(batch) = batch {
+                        shard_ended = batch.shard_end;
+                        if batch.millis_behind_latest.is_some() {
+                            millis_behind_latest = batch.millis_behind_latest;
+                        }
+                        child_shards = batch.child_shards;
+                        let last_sequence_number = batch
                             .records
-                            .iter()
-                            .map(|record| KinesisClientRecord::from_record(record.clone()))
+                            .last()
+                            .map(|record| record.sequence_number.clone());
+                        let shard_filter = &self.context.config.shard_filter;
+                        let records: Vec<_> = batch
+                            .records
+                            .into_iter()
+                            .map(KinesisClientRecord::from_record)
+                            .filter(|record| shard_filter.matches_hash_key(record.hash_key()))
                             .collect();
+                        if records.is_empty() {
+                            empty_fetch = Some(ShapedBatch {
+                                fetched_at: Some(batch.fetched_at),
+                                cached_at: batch.cached_at,
+                                ..ShapedBatch::empty()
+                            });
+                        }
+                        shaper.push(
+                            records,
+                            last_sequence_number.clone(),
+                            batch.fetched_at,
+                            batch.cached_at,
+                        );
+                        // Nothing's held back, so the checkpointer can move past whatever the
+                        // shard filter dropped
+                        if shaper.is_empty() {
+                            if let Some(sequence_number) = last_sequence_number {
+                                checkpointer.set_largest_permitted(&sequence_number).await;
+                            }
+                        }
+                    }
+
+                    let mut deliveries = Vec::new();
+                    while let Some(shaped) = shaper.next_batch(shard_ended) {
+                        deliveries.push(shaped);
+                    }
+                    // With idle calls configured, batches with nothing in them only go to the
+                    // processor when it's owed one or has to be told the shard ended. Otherwise
+                    // every fetch is handed on, as long as no records are being held back.
+                    if deliveries.is_empty() {
+                        match empty_fetch {
+                            Some(empty_fetch)
+                                if idle_batch_interval.is_none() && shaper.is_empty() =>
+                            {
+                                deliveries.push(empty_fetch)
+                            }
+                            _ if shard_ended || idle => deliveries.push(ShapedBatch::empty()),
+                            _ => {}
+                        }
+                    }
+                    let delivery_count = deliveries.len();
+                    let mut record_count = 0;
+                    for (index, shaped) in deliveries.into_iter().enumerate() {
+                        let is_last = index + 1 == delivery_count;
+                        if let Some(sequence_number) = shaped.largest_permitted.as_ref() {
+                            checkpointer.set_largest_permitted(sequence_number).await;
+                        }
+                        record_count += shaped.records.len();
+                        last_delivery = Instant::now();
                         self.record_processor
                             .process_records(ProcessRecordsInput {
-                                records,
-                                is_at_shard_end: false,   // TODO
-                                child_shards: Vec::new(), // TODO
+                                shard_id: self.shard_info.shard_id.clone(),
+                                records: shaped.records,
+                                is_at_shard_end: shard_ended && is_last,
+                                child_shards: if is_last {
+                                    child_shards.clone()
+                                } else {
+                                    Vec::new()
+                                },
+                                checkpointer: checkpointer.clone(),
+                                millis_behind_latest,
+                                fetched_at: shaped.fetched_at,
+                                cached_at: shaped.cached_at,
                             })
-                            .await; // TODO: Errors and better awaiting
+                            .await;
+                        // Checkpoints made while the last records are handled stay at those
+                        // records, so `SHARD_END` never lands before they're done
+                        if shard_ended && is_last {
+                            checkpointer.mark_shard_end().await;
+                        }
+                    }
+
+                    if let Some(tracker) = auto_checkpoint.as_mut() {
+                        if tracker.record_batch(record_count) {
+                            match checkpointer.checkpoint().await {
+                                Ok(()) => tracker.checkpointed(),
+                                Err(CheckpointError::LeaseLost) => {
+                                    self.lease_lost.store(true, Ordering::SeqCst);
+                                    break;
+                                }
+                                Err(err) => {
+                                    log::warn!(
+                                        "Failed to checkpoint {} automatically: {}",
+                                        self.shard_info.lease_key,
+                                        err
+                                    );
+                                    tracker.checkpoint_failed();
+                                }
+                            }
+                        }
+                    }
+                    checkpointer.flush_if_due().await;
+
+                    // The periodic shard sync is only a safety net, so the children get their
+                    // leases as soon as we hear about them
+                    if !child_leases_created && !child_shards.is_empty() {
+                        child_leases_created = self.create_child_leases(&child_shards).await;
+                    }
+                    if shard_ended {
+                        // Without children reported, as with DynamoDB streams, or with their
+                        // leases not created, they'd otherwise wait for the periodic sync
+                        if !child_leases_created {
+                            self.sync_shards().await;
+                        }
+                        break;
                     }
-                    _ => break,
                 }
             }
 
-            self.record_processor.shutdown_requested().await;
+            self.set_state(ShardWorkerState::ShuttingDown);
+            if self.lease_lost.load(Ordering::SeqCst) {
+                checkpointer.mark_lease_lost().await;
+                self.record_processor.lease_lost().await;
+            } else if shard_ended {
+                self.stop_coalescing(&checkpointer).await;
+                self.record_processor
+                    .shard_ended(ShardEndedInput {
+                        checkpointer: checkpointer.clone(),
+                    })
+                    .await;
+                if auto_checkpoint.is_some() {
+                    self.final_checkpoint(&checkpointer).await;
+                }
+                // Children wait on `SHARD_END`, so without it the shard is given up and read to
+                // its end again by whoever takes the lease next
+                if checkpointer.last_checkpoint().await != Checkpoint::ShardEnd {
+                    log::warn!(
+                        "{} ended without a SHARD_END checkpoint; releasing it to be retried",
+                        self.shard_info.lease_key
+                    );
+                    self.gave_up.store(true, Ordering::SeqCst);
+                }
+                checkpointer.mark_shut_down().await;
+            } else if let Some(error) = retrieval_error {
+                // A shard that can't be found is more likely gone for good, trimmed or with its
+                // stream recreated, than failing in a way another try gets past. Its lease is then
+                // kept until lease cleanup or the stream recreation policy deals with it, rather
+                // than taken and failed on over and over.
+                let shard_gone =
+                    matches!(error, RetrievalError::ResourceNotFound(_)) && self.shard_gone().await;
+                if !shard_gone {
+                    self.gave_up.store(true, Ordering::SeqCst);
+                }
+                self.stop_coalescing(&checkpointer).await;
+                self.record_processor
+                    .retrieval_failed(RetrievalFailedInput {
+                        error,
+                        checkpointer: checkpointer.clone(),
+                    })
+                    .await;
+                checkpointer.mark_shut_down().await;
+            } else {
+                self.stop_coalescing(&checkpointer).await;
+                self.record_processor.shutdown_requested().await;
+                if let Some(tracker) = auto_checkpoint.as_ref() {
+                    if tracker.has_pending() {
+                        self.final_checkpoint(&checkpointer).await;
+                    }
+                }
+                checkpointer.mark_shut_down().await;
+            }
+            self.set_state(ShardWorkerState::ShutDown);
             self.shutdown.notify_waiters();
         });
     }
 
+    /// Saves what write coalescing held back before the processor is told the shard is being let
+    /// go, so its last checkpoints are written straight away and it sees their failures.
+    async fn stop_coalescing(&self, checkpointer: &RecordProcessorCheckpointer) {
+        if let Err(err) = checkpointer.stop_coalescing().await {
+            log::warn!(
+                "Failed to save the held back checkpoint for {}: {}",
+                self.shard_info.lease_key,
+                err
+            );
+        }
+    }
+
+    /// Syncs the stream's shards, to find out whether the shard was trimmed or its stream
+    /// recreated.
+    async fn shard_gone(&self) -> bool {
+        let shard_syncer = &self.context.shard_syncer;
+        self.sync_shards().await;
+        shard_syncer.stream_recreation().await.is_some()
+            || shard_syncer.is_trimmed(&self.shard_info.shard_id).await
+    }
+
+    /// Failures are logged and otherwise left to the periodic sync.
+    async fn sync_shards(&self) {
+        if let Err(err) = self.context.shard_syncer.sync_shards().await {
+            log::warn!(
+                "Failed to sync shards for {}: {:?}",
+                self.shard_info.lease_key,
+                err
+            );
+        }
+    }
+
+    /// Returns whether the leases were created. Failures are logged and left to the caller to try
+    /// again.
+    async fn create_child_leases(&self, child_shards: &[ChildShard]) -> bool {
+        match self
+            .context
+            .shard_syncer
+            .create_child_leases(child_shards)
+            .await
+        {
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!(
+                    "Failed to create leases for the children of {}: {:?}",
+                    self.shard_info.lease_key,
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    /// Checkpoints on the processor's behalf once it's done with the shard.
+    async fn final_checkpoint(&self, checkpointer: &RecordProcessorCheckpointer) {
+        if let Err(err) = checkpointer.checkpoint().await {
+            log::warn!(
+                "Failed to checkpoint {} on shutdown: {}",
+                self.shard_info.lease_key,
+                err
+            );
+        }
+    }
+
+    fn open_retriever(&self, position: &Checkpoint) -> Box<dyn ShardRetriever> {
+        let retriever = self
+            .context
+            .record_source
+            .open_shard(&self.shard_info.shard_id, position);
+        Box::new(PrefetchingRetriever::new(
+            retriever,
+            &self.context.config.prefetch,
+            self.context.prefetch_budget.clone(),
+        ))
+    }
+
+    /// Holds off until every parent shard has been processed to its end, so records for a partition
+    /// key are never handled out of order across a split or merge. Returns `false` if the worker was
+    /// told to shut down while waiting.
+    async fn wait_for_parents(&self) -> bool {
+        loop {
+            if self.should_shutdown.load(Ordering::SeqCst) {
+                return false;
+            }
+            if self.parents_complete().await {
+                return true;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(self.context.config.parent_shard_poll_interval) => {}
+                _ = self.shutdown_requested.notified() => {}
+            }
+        }
+    }
+
+    async fn parents_complete(&self) -> bool {
+        let lease_broker = self.context.lease_manager.lease_broker();
+        for parent_shard_id in self.shard_info.parent_shard_ids.iter() {
+            let parent_lease_key = self.context.config.stream.lease_key(parent_shard_id);
+            match lease_broker.get_lease(&parent_lease_key).await {
+                // A parent without a lease has already been completed and cleaned up
+                Ok(None) => {}
+                Ok(Some(parent)) => {
+                    let at_shard_end = parent
+                        .checkpoint
+                        .as_ref()
+                        .and_then(|value| Checkpoint::from_lease_value(value))
+                        == Some(Checkpoint::ShardEnd);
+                    if !at_shard_end && !self.context.shard_syncer.is_trimmed(parent_shard_id).await
+                    {
+                        return false;
+                    }
+                }
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    fn set_state(&self, state: ShardWorkerState) {
+        *self.state.write().expect("Worker state lock poisoned") = state;
+    }
+
+    pub(crate) fn state(&self) -> ShardWorkerState {
+        *self.state.read().expect("Worker state lock poisoned")
+    }
+
     pub(crate) async fn await_shutdown(&self) {
         self.should_shutdown.store(true, Ordering::SeqCst);
-        self.shutdown.notified().await;
-        self.is_shutdown.store(true, Ordering::SeqCst);
+        // Leaves a permit behind if the worker isn't waiting on its parents or for records right
+        // now
+        self.shutdown_requested.notify_one();
+        let shutdown = self.shutdown.notified();
+        if !self.is_shutdown() {
+            shutdown.await;
+        }
+    }
+
+    pub(crate) async fn await_lease_lost(&self) {
+        self.lease_lost.store(true, Ordering::SeqCst);
+        self.await_shutdown().await;
     }
 
     pub(crate) fn is_shutdown(&self) -> bool {
-        self.is_shutdown.load(Ordering::SeqCst)
+        self.state() == ShardWorkerState::ShutDown
+    }
+
+    /// Whether the worker shut down on its own without finishing its shard.
+    pub(crate) fn has_given_up(&self) -> bool {
+        self.is_shutdown() && self.gave_up.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+
+    use crate::{
+        checkpoint::{AutoCheckpointConfig, LeaseCheckpointStore},
+        config::{
+            BatchShapingConfig, PollingConfig, PrefetchConfig, RetrievalMode, ShardFilter,
+            StreamRecreationPolicy,
+        },
+        kinesis::{consumer::StreamConsumer, StreamDescriptor},
+        lease::{broker::LeaseBroker, Lease},
+        source::{kinesis::stream_source, ChannelSource},
+        util::fake_aws::{self, event_stream, kinesis_client, FakeLeaseTable, ScriptedAws},
+    };
+
+    use super::*;
+
+    const SEQUENCE_NUMBER: &str = "49590338271490256608559692538361571095921575989136588898";
+    const CONSUMER_ARN: &str =
+        "arn:aws:kinesis:us-east-1:123456789012:stream/test/consumer/app:1600000000";
+
+    struct NoopProcessor;
+
+    #[async_trait]
+    impl RecordProcessor for NoopProcessor {
+        async fn initialize(&self, _input: InitializationInput) {}
+        async fn process_records(&self, _input: ProcessRecordsInput) {}
+        async fn lease_lost(&self) {}
+        async fn shard_ended(&self, _input: ShardEndedInput) {}
+        async fn shutdown_requested(&self) {}
+    }
+
+    fn worker(
+        table: &FakeLeaseTable,
+        script: &ScriptedAws,
+        shard_info: ShardInfo,
+        config: SchedulerConfig,
+    ) -> ShardWorker {
+        let kinesis = Arc::new(kinesis_client(script.fake()));
+        let lease_manager = Arc::new(LeaseManager::new(
+            LeaseBroker::new(table.client(), "leases".to_string()),
+            "worker-1".to_string(),
+            StreamDescriptor::from_name("test"),
+            ShardFilter::All,
+        ));
+        let stream_consumer = Arc::new(StreamConsumer::new(
+            kinesis.clone(),
+            StreamDescriptor::from_arn("arn:aws:kinesis:us-east-1:123456789012:stream/test")
+                .unwrap(),
+            "app".to_string(),
+        ));
+        let record_source = stream_source(&config, kinesis.clone(), kinesis, Some(stream_consumer));
+        let shard_syncer = Arc::new(ShardSyncer::new(
+            StreamDescriptor::from_name("test"),
+            record_source.clone(),
+            lease_manager.lease_broker(),
+            Checkpoint::TrimHorizon,
+            ShardFilter::All,
+            StreamRecreationPolicy::default(),
+        ));
+        let context = Arc::new(WorkerContext {
+            config: Arc::new(config),
+            record_source,
+            checkpoint_store: Arc::new(LeaseCheckpointStore::new(lease_manager.clone())),
+            lease_manager,
+            shard_syncer,
+            prefetch_budget: prefetch_budget(&PrefetchConfig::default()),
+        });
+        ShardWorker::new(shard_info, context, Box::new(NoopProcessor))
+    }
+
+    fn child_worker(table: &FakeLeaseTable, parent_shard_poll_interval: Duration) -> ShardWorker {
+        worker(
+            table,
+            &ScriptedAws::default(),
+            ShardInfo {
+                shard_id: "shard-2".to_string(),
+                lease_key: "shard-2".to_string(),
+                parent_shard_ids: vec!["shard-1".to_string()],
+            },
+            SchedulerConfig {
+                parent_shard_poll_interval,
+                ..SchedulerConfig::default()
+            },
+        )
+    }
+
+    /// Waits for the worker to stop on its own.
+    async fn stopped(worker: &ShardWorker) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !worker.is_shutdown() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The worker didn't stop");
+    }
+
+    fn parent_lease(checkpoint: Checkpoint) -> Lease {
+        Lease::new(
+            "shard-1".to_string(),
+            checkpoint.to_lease_value(),
+            Vec::new(),
+            "0".to_string(),
+            "99".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn children_wait_for_their_parents_to_end() {
+        let table = FakeLeaseTable::default();
+        let worker = child_worker(&table, Duration::from_secs(10));
+        table.put(&parent_lease(Checkpoint::SequenceNumber(
+            SEQUENCE_NUMBER.to_string(),
+        )));
+        assert!(!worker.parents_complete().await);
+
+        table.put(&parent_lease(Checkpoint::ShardEnd));
+        assert!(worker.parents_complete().await);
+
+        // Parents that were finished and cleaned up have no lease left
+        let table = FakeLeaseTable::default();
+        let worker = child_worker(&table, Duration::from_secs(10));
+        assert!(worker.parents_complete().await);
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_for_parents_at_shutdown() {
+        let table = FakeLeaseTable::default();
+        table.put(&parent_lease(Checkpoint::TrimHorizon));
+        let worker = Arc::new(child_worker(&table, Duration::from_secs(3600)));
+        worker.clone().start();
+
+        tokio::time::timeout(Duration::from_secs(5), worker.await_shutdown())
+            .await
+            .expect("The worker is still waiting on its parent");
+        assert_eq!(worker.state(), ShardWorkerState::ShutDown);
+    }
+
+    #[tokio::test]
+    async fn creates_leases_for_children_reported_before_the_shard_ends() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script.respond(
+            "DescribeStreamConsumer",
+            (
+                200,
+                json!({
+                    "ConsumerDescription": {
+                        "ConsumerARN": CONSUMER_ARN,
+                        "ConsumerCreationTimestamp": 1_600_000_000.0,
+                        "ConsumerName": "app",
+                        "ConsumerStatus": "ACTIVE",
+                        "StreamARN": "arn:aws:kinesis:us-east-1:123456789012:stream/test",
+                    }
+                }),
+            ),
+        );
+        script.respond(
+            "SubscribeToShard",
+            event_stream(vec![(
+                "SubscribeToShardEvent",
+                json!({
+                    "Records": [],
+                    "ContinuationSequenceNumber": SEQUENCE_NUMBER,
+                    "MillisBehindLatest": 0,
+                    "ChildShards": [{
+                        "ShardId": "shard-2",
+                        "ParentShards": ["shard-1"],
+                        "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                    }],
+                }),
+            )]),
+        );
+        // The subscription expires, and the next one picks up where it left off
+        script.respond(
+            "SubscribeToShard",
+            event_stream(vec![(
+                "SubscribeToShardEvent",
+                json!({
+                    "Records": [],
+                    "ContinuationSequenceNumber": "",
+                    "MillisBehindLatest": 0,
+                    "ChildShards": [{
+                        "ShardId": "shard-2",
+                        "ParentShards": ["shard-1"],
+                        "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                    }],
+                }),
+            )]),
+        );
+        let worker = Arc::new(worker(
+            &table,
+            &script,
+            ShardInfo {
+                shard_id: "shard-1".to_string(),
+                lease_key: "shard-1".to_string(),
+                parent_shard_ids: Vec::new(),
+            },
+            SchedulerConfig::default(),
+        ));
+        worker.clone().start();
+
+        stopped(&worker).await;
+        let subscriptions = script.requests("SubscribeToShard");
+        assert_eq!(subscriptions[0]["ConsumerARN"], CONSUMER_ARN);
+        assert_eq!(
+            subscriptions[1]["StartingPosition"],
+            json!({ "Type": "AFTER_SEQUENCE_NUMBER", "SequenceNumber": SEQUENCE_NUMBER })
+        );
+        assert_eq!(table.lease_keys(), vec!["shard-2"]);
+        let child = table.get("shard-2").unwrap();
+        assert_eq!(child.parent_shard_ids, vec!["shard-1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn reads_shards_with_get_records_when_polling() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [{
+                            "SequenceNumber": SEQUENCE_NUMBER,
+                            "Data": "ZGF0YQ==",
+                            "PartitionKey": "key",
+                        }],
+                        "MillisBehindLatest": 0,
+                        "ChildShards": [{
+                            "ShardId": "shard-2",
+                            "ParentShards": ["shard-1"],
+                            "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                        }],
+                    }),
+                ),
+            );
+        let worker = Arc::new(worker(
+            &table,
+            &script,
+            ShardInfo {
+                shard_id: "shard-1".to_string(),
+                lease_key: "shard-1".to_string(),
+                parent_shard_ids: Vec::new(),
+            },
+            SchedulerConfig {
+                retrieval_mode: RetrievalMode::Polling(PollingConfig::default()),
+                ..SchedulerConfig::default()
+            },
+        ));
+        worker.clone().start();
+
+        stopped(&worker).await;
+        assert!(script.requests("SubscribeToShard").is_empty());
+        assert_eq!(
+            script.requests("GetShardIterator")[0]["ShardIteratorType"],
+            "TRIM_HORIZON"
+        );
+        // The shard ended without a next iterator, so its children are leased
+        assert_eq!(table.lease_keys(), vec!["shard-2"]);
+    }
+
+    fn polling_worker(
+        table: &FakeLeaseTable,
+        script: &ScriptedAws,
+        checkpoint_mode: CheckpointMode,
+    ) -> ShardWorker {
+        worker(
+            table,
+            script,
+            ShardInfo {
+                shard_id: "shard-1".to_string(),
+                lease_key: "shard-1".to_string(),
+                parent_shard_ids: Vec::new(),
+            },
+            SchedulerConfig {
+                retrieval_mode: RetrievalMode::Polling(PollingConfig::default()),
+                checkpoint_mode,
+                ..SchedulerConfig::default()
+            },
+        )
+    }
+
+    /// Puts shard-1's lease in the table, owned by the worker.
+    async fn hold_lease(table: &FakeLeaseTable, worker: &ShardWorker) {
+        let lease = Lease {
+            lease_owner: Some("worker-1".to_string()),
+            ..Lease::new(
+                "shard-1".to_string(),
+                Checkpoint::TrimHorizon.to_lease_value(),
+                Vec::new(),
+                "0".to_string(),
+                "99".to_string(),
+            )
+        };
+        table.put(&lease);
+        worker.context.lease_manager.hold_lease(lease).await;
+    }
+
+    fn lease_checkpoint(table: &FakeLeaseTable) -> Option<Checkpoint> {
+        table
+            .get("shard-1")
+            .and_then(|lease| lease.checkpoint)
+            .and_then(|value| Checkpoint::from_lease_value(&value))
+    }
+
+    /// Scripts the calls of one shard sync, listing `shards`.
+    fn script_shard_sync(script: &ScriptedAws, shards: Value) {
+        script
+            .respond(
+                "DescribeStreamSummary",
+                fake_aws::stream_summary("test", 1_600_000_000.0),
+            )
+            .respond("ListShards", (200, json!({ "Shards": shards })));
+    }
+
+    #[tokio::test]
+    async fn gives_up_the_shard_when_its_records_cannot_be_read() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script.respond(
+            "GetShardIterator",
+            fake_aws::error("InvalidArgumentException", "Invalid starting position"),
+        );
+        let worker = Arc::new(polling_worker(&table, &script, CheckpointMode::Manual));
+        worker.clone().start();
+
+        stopped(&worker).await;
+        assert!(worker.has_given_up());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_lease_of_a_shard_that_no_longer_exists() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script.respond(
+            "GetShardIterator",
+            fake_aws::error("ResourceNotFoundException", "Shard not found"),
+        );
+        // The shard has been trimmed from the stream
+        script_shard_sync(&script, json!([]));
+        let worker = Arc::new(polling_worker(&table, &script, CheckpointMode::Manual));
+        hold_lease(&table, &worker).await;
+        worker.clone().start();
+
+        stopped(&worker).await;
+        assert_eq!(script.requests("ListShards").len(), 1);
+        assert!(!worker.has_given_up());
+    }
+
+    #[tokio::test]
+    async fn syncs_shards_when_a_shard_ends_without_reporting_its_children() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (200, json!({ "Records": [], "MillisBehindLatest": 0 })),
+            );
+        script_shard_sync(
+            &script,
+            json!([
+                {
+                    "ShardId": "shard-1",
+                    "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                    "SequenceNumberRange": {
+                        "StartingSequenceNumber": "1",
+                        "EndingSequenceNumber": "9",
+                    },
+                },
+                {
+                    "ShardId": "shard-2",
+                    "ParentShardId": "shard-1",
+                    "HashKeyRange": { "StartingHashKey": "0", "EndingHashKey": "99" },
+                    "SequenceNumberRange": { "StartingSequenceNumber": "10" },
+                },
+            ]),
+        );
+        let worker = Arc::new(polling_worker(
+            &table,
+            &script,
+            CheckpointMode::Automatic(AutoCheckpointConfig::default()),
+        ));
+        hold_lease(&table, &worker).await;
+        worker.clone().start();
+
+        stopped(&worker).await;
+        assert_eq!(script.requests("ListShards").len(), 1);
+        assert_eq!(table.lease_keys(), vec!["shard-1", "shard-2"]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_a_shard_that_ends_without_a_shard_end_checkpoint() {
+        for (checkpoint_mode, gives_up) in [
+            (CheckpointMode::Manual, true),
+            (
+                CheckpointMode::Automatic(AutoCheckpointConfig::default()),
+                false,
+            ),
+        ] {
+            let table = FakeLeaseTable::default();
+            let script = ScriptedAws::default();
+            script
+                .respond(
+                    "GetShardIterator",
+                    (200, json!({ "ShardIterator": "it-1" })),
+                )
+                .respond(
+                    "GetRecords",
+                    (200, json!({ "Records": [], "MillisBehindLatest": 0 })),
+                );
+            script_shard_sync(&script, json!([]));
+            let worker = Arc::new(polling_worker(&table, &script, checkpoint_mode));
+            hold_lease(&table, &worker).await;
+            worker.clone().start();
+
+            stopped(&worker).await;
+            // The no-op processor never checkpoints for itself
+            assert_eq!(worker.has_given_up(), gives_up);
+            assert_eq!(
+                lease_checkpoint(&table),
+                Some(if gives_up {
+                    Checkpoint::TrimHorizon
+                } else {
+                    Checkpoint::ShardEnd
+                })
+            );
+        }
+    }
+
+    /// What a processor was told about a batch, apart from its records.
+    #[derive(Debug, PartialEq)]
+    struct BatchCall {
+        shard_id: String,
+        record_count: usize,
+        millis_behind_latest: Option<i64>,
+        fetched: bool,
+        cached: bool,
+    }
+
+    struct BatchCallRecorder {
+        calls: Arc<Mutex<Vec<BatchCall>>>,
+    }
+
+    #[async_trait]
+    impl RecordProcessor for BatchCallRecorder {
+        async fn initialize(&self, _input: InitializationInput) {}
+        async fn process_records(&self, input: ProcessRecordsInput) {
+            self.calls.lock().unwrap().push(BatchCall {
+                shard_id: input.shard_id,
+                record_count: input.records.len(),
+                millis_behind_latest: input.millis_behind_latest,
+                fetched: input.fetched_at.is_some(),
+                cached: input.cached_at.is_some(),
+            });
+        }
+        async fn lease_lost(&self) {}
+        async fn shard_ended(&self, _input: ShardEndedInput) {}
+        async fn shutdown_requested(&self) {}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_idle_processors_with_an_empty_batch() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [{
+                            "SequenceNumber": SEQUENCE_NUMBER,
+                            "Data": "ZGF0YQ==",
+                            "PartitionKey": "key",
+                        }],
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": 1000,
+                    }),
+                ),
+            );
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let worker = worker(
+            &table,
+            &script,
+            ShardInfo {
+                shard_id: "shard-1".to_string(),
+                lease_key: "shard-1".to_string(),
+                parent_shard_ids: Vec::new(),
+            },
+            SchedulerConfig {
+                // The shard goes quiet after the first poll
+                retrieval_mode: RetrievalMode::Polling(PollingConfig {
+                    idle_interval: Duration::from_secs(3600),
+                    ..PollingConfig::default()
+                }),
+                idle_batch_interval: Some(Duration::from_secs(10)),
+                ..SchedulerConfig::default()
+            },
+        );
+        let worker = Arc::new(ShardWorker {
+            record_processor: Box::new(BatchCallRecorder {
+                calls: calls.clone(),
+            }),
+            ..worker
+        });
+        worker.clone().start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        worker.await_shutdown().await;
+        let idle_call = || BatchCall {
+            shard_id: "shard-1".to_string(),
+            record_count: 0,
+            // The lag is still the last one Kinesis reported
+            millis_behind_latest: Some(1000),
+            fetched: false,
+            cached: false,
+        };
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                BatchCall {
+                    record_count: 1,
+                    fetched: true,
+                    cached: true,
+                    ..idle_call()
+                },
+                idle_call(),
+                idle_call(),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hands_on_empty_fetches_without_an_idle_batch_interval() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [],
+                        "NextShardIterator": "it-2",
+                        "MillisBehindLatest": 1000,
+                    }),
+                ),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [{
+                            "SequenceNumber": SEQUENCE_NUMBER,
+                            "Data": "ZGF0YQ==",
+                            "PartitionKey": "key",
+                        }],
+                        "NextShardIterator": "it-3",
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            );
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let worker = worker(
+            &table,
+            &script,
+            ShardInfo {
+                shard_id: "shard-1".to_string(),
+                lease_key: "shard-1".to_string(),
+                parent_shard_ids: Vec::new(),
+            },
+            SchedulerConfig {
+                retrieval_mode: RetrievalMode::Polling(PollingConfig {
+                    idle_interval: Duration::from_secs(10),
+                    ..PollingConfig::default()
+                }),
+                ..SchedulerConfig::default()
+            },
+        );
+        let worker = Arc::new(ShardWorker {
+            record_processor: Box::new(BatchCallRecorder {
+                calls: calls.clone(),
+            }),
+            ..worker
+        });
+        worker.clone().start();
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        worker.await_shutdown().await;
+        let empty_call = || BatchCall {
+            shard_id: "shard-1".to_string(),
+            record_count: 0,
+            millis_behind_latest: Some(1000),
+            fetched: true,
+            cached: true,
+        };
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                empty_call(),
+                BatchCall {
+                    record_count: 1,
+                    millis_behind_latest: Some(0),
+                    ..empty_call()
+                },
+            ]
+        );
+    }
+
+    /// Checkpoints at the end of every batch and of the shard, and notes where each checkpoint
+    /// landed.
+    struct CheckpointingProcessor {
+        checkpoints: Arc<Mutex<Vec<Checkpoint>>>,
+    }
+
+    #[async_trait]
+    impl RecordProcessor for CheckpointingProcessor {
+        async fn initialize(&self, _input: InitializationInput) {}
+        async fn process_records(&self, input: ProcessRecordsInput) {
+            input.checkpointer.checkpoint().await.unwrap();
+            let checkpoint = input.checkpointer.last_checkpoint().await;
+            self.checkpoints.lock().unwrap().push(checkpoint);
+        }
+        async fn lease_lost(&self) {}
+        async fn shard_ended(&self, input: ShardEndedInput) {
+            input.checkpointer.checkpoint().await.unwrap();
+            let checkpoint = input.checkpointer.last_checkpoint().await;
+            self.checkpoints.lock().unwrap().push(checkpoint);
+        }
+        async fn shutdown_requested(&self) {}
+    }
+
+    #[tokio::test]
+    async fn checkpoints_shard_end_only_once_the_last_records_are_processed() {
+        let table = FakeLeaseTable::default();
+        let script = ScriptedAws::default();
+        script
+            .respond(
+                "GetShardIterator",
+                (200, json!({ "ShardIterator": "it-1" })),
+            )
+            .respond(
+                "GetRecords",
+                (
+                    200,
+                    json!({
+                        "Records": [
+                            { "SequenceNumber": "1", "Data": "ZGF0YQ==", "PartitionKey": "key" },
+                            { "SequenceNumber": "2", "Data": "ZGF0YQ==", "PartitionKey": "key" },
+                            { "SequenceNumber": "3", "Data": "ZGF0YQ==", "PartitionKey": "key" },
+                        ],
+                        "MillisBehindLatest": 0,
+                    }),
+                ),
+            );
+        script_shard_sync(&script, json!([]));
+        let checkpoints = Arc::new(Mutex::new(Vec::new()));
+        let worker = worker(
+            &table,
+            &script,
+            ShardInfo {
+                shard_id: "shard-1".to_string(),
+                lease_key: "shard-1".to_string(),
+                parent_shard_ids: Vec::new(),
+            },
+            SchedulerConfig {
+                retrieval_mode: RetrievalMode::Polling(PollingConfig::default()),
+                batch_shaping: BatchShapingConfig {
+                    max_records: Some(2),
+                    ..BatchShapingConfig::default()
+                },
+                ..SchedulerConfig::default()
+            },
+        );
+        let worker = Arc::new(ShardWorker {
+            record_processor: Box::new(CheckpointingProcessor {
+                checkpoints: checkpoints.clone(),
+            }),
+            ..worker
+        });
+        hold_lease(&table, &worker).await;
+        worker.clone().start();
+
+        stopped(&worker).await;
+        assert_eq!(
+            *checkpoints.lock().unwrap(),
+            vec![
+                Checkpoint::SequenceNumber("2".to_string()),
+                // The last split is checkpointed at its own records
+                Checkpoint::SequenceNumber("3".to_string()),
+                Checkpoint::ShardEnd,
+            ]
+        );
+        assert!(!worker.has_given_up());
+    }
+
+    /// Checkpoints at the end of the shard, recording the records it was given.
+    struct RecordingProcessor {
+        records: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl RecordProcessor for RecordingProcessor {
+        async fn initialize(&self, _input: InitializationInput) {}
+        async fn process_records(&self, input: ProcessRecordsInput) {
+            self.records.lock().unwrap().extend(
+                input
+                    .records
+                    .into_iter()
+                    .map(|record| record.sequence_number),
+            );
+        }
+        async fn lease_lost(&self) {}
+        async fn shard_ended(&self, input: ShardEndedInput) {
+            input.checkpointer.checkpoint().await.unwrap();
+        }
+        async fn shutdown_requested(&self) {}
+    }
+
+    #[tokio::test]
+    async fn reads_shards_from_another_record_source() {
+        let table = FakeLeaseTable::default();
+        let source = Arc::new(ChannelSource::new());
+        let sender = source.add_shard("shard-1", Vec::new());
+        let sent = vec![sender.send("key", "first"), sender.send("key", "second")];
+        drop(sender);
+        let worker = polling_worker(&table, &ScriptedAws::default(), CheckpointMode::Manual);
+        let context = Arc::new(WorkerContext {
+            shard_syncer: Arc::new(ShardSyncer::new(
+                StreamDescriptor::from_name("test"),
+                source.clone(),
+                worker.context.lease_manager.lease_broker(),
+                Checkpoint::TrimHorizon,
+                ShardFilter::All,
+                StreamRecreationPolicy::default(),
+            )),
+            record_source: source,
+            config: worker.context.config.clone(),
+            lease_manager: worker.context.lease_manager.clone(),
+            checkpoint_store: worker.context.checkpoint_store.clone(),
+            prefetch_budget: worker.context.prefetch_budget.clone(),
+        });
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let worker = Arc::new(ShardWorker {
+            context,
+            record_processor: Box::new(RecordingProcessor {
+                records: records.clone(),
+            }),
+            ..worker
+        });
+        hold_lease(&table, &worker).await;
+        worker.clone().start();
+
+        // Nothing is asked of Kinesis, which isn't scripted
+        stopped(&worker).await;
+        assert_eq!(*records.lock().unwrap(), sent);
+        assert_eq!(lease_checkpoint(&table), Some(Checkpoint::ShardEnd));
+        assert!(!worker.has_given_up());
     }
 }
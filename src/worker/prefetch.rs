@@ -0,0 +1,366 @@
+use std::{
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::PrefetchConfig,
+    interface::processor::RetrievalError,
+    source::{RecordBatch, ShardRetriever},
+    util::retry::ExponentialBackoff,
+};
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// A fetched batch, with the share of the buffer's limits it holds until it's been processed.
+type PrefetchedBatch = (
+    Result<RecordBatch, RetrievalError>,
+    Vec<OwnedSemaphorePermit>,
+);
+
+/// Per-shard and scheduler-wide limits, each counted with a semaphore's permits.
+struct PrefetchLimits {
+    records: Arc<Semaphore>,
+    max_records: usize,
+    bytes: Arc<Semaphore>,
+    max_bytes: usize,
+    total_bytes: Arc<Semaphore>,
+    max_total_bytes: usize,
+}
+
+impl PrefetchLimits {
+    /// Waits until the buffer has room for the batch. A batch bigger than a limit takes the whole
+    /// of it, so it's let through once everything before it has been handed out. Returns `None`
+    /// if the scheduler-wide budget has been closed.
+    async fn reserve(&self, batch: &RecordBatch) -> Option<Vec<OwnedSemaphorePermit>> {
+        // Empty batches still take a slot, so an idle shard can't queue them up without limit
+        let record_count = batch.records.len().max(1);
+        let byte_size = batch
+            .records
+            .iter()
+            .map(|record| record.data.len() + record.partition_key.len())
+            .sum::<usize>();
+
+        let mut permits = Vec::with_capacity(3);
+        for (semaphore, wanted, limit) in [
+            (&self.records, record_count, self.max_records),
+            (&self.bytes, byte_size, self.max_bytes),
+            (&self.total_bytes, byte_size, self.max_total_bytes),
+        ] {
+            let wanted = u32::try_from(wanted.min(limit)).unwrap_or(u32::MAX);
+            permits.push(semaphore.clone().acquire_many_owned(wanted).await.ok()?);
+        }
+        Some(permits)
+    }
+}
+
+fn semaphore(permits: usize) -> Arc<Semaphore> {
+    Arc::new(Semaphore::new(permits.min(Semaphore::MAX_PERMITS)))
+}
+
+/// The scheduler-wide budget shared by every shard's prefetch buffer.
+pub(crate) fn prefetch_budget(config: &PrefetchConfig) -> Arc<Semaphore> {
+    semaphore(config.max_total_bytes)
+}
+
+/// Reads batches ahead of the record processor on a task of its own, so fetching and processing
+/// overlap. Once the buffer is full the task stops reading, which for fan-out leaves the
+/// subscription's events unread until there's room for them.
+pub(crate) struct PrefetchingRetriever {
+    receiver: mpsc::UnboundedReceiver<PrefetchedBatch>,
+    fetcher: JoinHandle<()>,
+    /// The permits of the batch last handed out, kept until the next one is asked for, by which
+    /// time it has been processed.
+    in_flight: Vec<OwnedSemaphorePermit>,
+}
+
+impl PrefetchingRetriever {
+    pub(crate) fn new(
+        retriever: Box<dyn ShardRetriever>,
+        config: &PrefetchConfig,
+        budget: Arc<Semaphore>,
+    ) -> Self {
+        let limits = PrefetchLimits {
+            records: semaphore(config.max_records),
+            max_records: config.max_records,
+            bytes: semaphore(config.max_bytes),
+            max_bytes: config.max_bytes,
+            total_bytes: budget,
+            max_total_bytes: config.max_total_bytes,
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let fetcher = tokio::spawn(fetch(retriever, limits, sender));
+        Self {
+            receiver,
+            fetcher,
+            in_flight: Vec::new(),
+        }
+    }
+}
+
+/// Retries throttling and transient errors with backoff for as long as it takes, and stops after
+/// passing on any other error.
+async fn fetch(
+    mut retriever: Box<dyn ShardRetriever>,
+    limits: PrefetchLimits,
+    sender: mpsc::UnboundedSender<PrefetchedBatch>,
+) {
+    let mut backoff = ExponentialBackoff::new(RETRY_BASE_DELAY, RETRY_MAX_DELAY);
+    while let Some(mut result) = retriever.next_batch().await {
+        let failed = result.is_err();
+        let permits = match result.as_mut() {
+            Ok(batch) => {
+                backoff.reset();
+                let permits = match limits.reserve(batch).await {
+                    Some(permits) => permits,
+                    None => return,
+                };
+                // Only counts as cached once there's room for it
+                batch.cached_at = Some(Instant::now());
+                permits
+            }
+            // The retriever just needs a new iterator
+            Err(RetrievalError::ExpiredIterator(_)) => continue,
+            Err(err) if err.is_retryable() => {
+                tokio::time::sleep(backoff.next_delay()).await;
+                continue;
+            }
+            Err(_) => Vec::new(),
+        };
+        if sender.send((result, permits)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl ShardRetriever for PrefetchingRetriever {
+    async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+        // Dropping the permits makes room for the next batch
+        self.in_flight.clear();
+        let (result, permits) = self.receiver.recv().await?;
+        self.in_flight = permits;
+        Some(result)
+    }
+}
+
+impl Drop for PrefetchingRetriever {
+    fn drop(&mut self) {
+        self.fetcher.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use rusoto_kinesis::Record;
+
+    use super::*;
+
+    /// Hands out the results it was given, then reports the end of the shard.
+    struct ScriptedRetriever {
+        results: VecDeque<Result<RecordBatch, RetrievalError>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ShardRetriever for ScriptedRetriever {
+        async fn next_batch(&mut self) -> Option<Result<RecordBatch, RetrievalError>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.results.pop_front()
+        }
+    }
+
+    fn batch(sequence_numbers: &[&str]) -> RecordBatch {
+        RecordBatch {
+            records: sequence_numbers
+                .iter()
+                .map(|sequence_number| Record {
+                    sequence_number: sequence_number.to_string(),
+                    data: Bytes::from_static(b"data"),
+                    partition_key: "key".to_string(),
+                    ..Record::default()
+                })
+                .collect(),
+            child_shards: Vec::new(),
+            shard_end: false,
+            millis_behind_latest: None,
+            fetched_at: Instant::now(),
+            cached_at: None,
+        }
+    }
+
+    fn prefetching(
+        results: Vec<Result<RecordBatch, RetrievalError>>,
+        config: &PrefetchConfig,
+    ) -> (PrefetchingRetriever, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let retriever = PrefetchingRetriever::new(
+            Box::new(ScriptedRetriever {
+                results: results.into(),
+                calls: calls.clone(),
+            }),
+            config,
+            prefetch_budget(config),
+        );
+        (retriever, calls)
+    }
+
+    async fn sequence_numbers(retriever: &mut PrefetchingRetriever) -> Vec<String> {
+        let batch = retriever.next_batch().await.unwrap().unwrap();
+        batch
+            .records
+            .into_iter()
+            .map(|record| record.sequence_number)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn hands_out_batches_in_order() {
+        let (mut retriever, _) = prefetching(
+            vec![Ok(batch(&["1", "2"])), Ok(batch(&[])), Ok(batch(&["3"]))],
+            &PrefetchConfig::default(),
+        );
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["1", "2"]);
+        assert!(sequence_numbers(&mut retriever).await.is_empty());
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["3"]);
+        assert!(retriever.next_batch().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_retryable_errors() {
+        let (mut retriever, calls) = prefetching(
+            vec![
+                Err(RetrievalError::Throttled("slow down".to_string())),
+                Ok(batch(&["1"])),
+                Err(RetrievalError::Transient("connection reset".to_string())),
+                Err(RetrievalError::ExpiredIterator("expired".to_string())),
+                Ok(batch(&["2"])),
+            ],
+            &PrefetchConfig::default(),
+        );
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["1"]);
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["2"]);
+        assert!(retriever.next_batch().await.is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn stops_after_passing_on_other_errors() {
+        let (mut retriever, calls) = prefetching(
+            vec![
+                Ok(batch(&["1"])),
+                Err(RetrievalError::AccessDenied("no".to_string())),
+                Ok(batch(&["2"])),
+            ],
+            &PrefetchConfig::default(),
+        );
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["1"]);
+        assert!(matches!(
+            retriever.next_batch().await,
+            Some(Err(RetrievalError::AccessDenied(_)))
+        ));
+        assert!(retriever.next_batch().await.is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_reading_while_the_buffer_is_full() {
+        let config = PrefetchConfig {
+            max_records: 2,
+            ..PrefetchConfig::default()
+        };
+        let (mut retriever, calls) = prefetching(
+            vec![
+                Ok(batch(&["1", "2"])),
+                Ok(batch(&["3", "4"])),
+                Ok(batch(&["5"])),
+            ],
+            &config,
+        );
+
+        // The second batch is fetched straight away, but waits for room before the third is read
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["1", "2"]);
+        // The first batch keeps its room while it's processed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["3", "4"]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(sequence_numbers(&mut retriever).await, vec!["5"]);
+    }
+
+    #[tokio::test]
+    async fn caches_batches_once_there_is_room_for_them() {
+        let config = PrefetchConfig {
+            max_records: 2,
+            ..PrefetchConfig::default()
+        };
+        let (mut retriever, _) =
+            prefetching(vec![Ok(batch(&["1", "2"])), Ok(batch(&["3"]))], &config);
+
+        // The second batch is fetched straight away, but waits for the first to be processed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let handed_out = Instant::now();
+        let first = retriever.next_batch().await.unwrap().unwrap();
+        assert!(first.cached_at.unwrap() < handed_out);
+        let second = retriever.next_batch().await.unwrap().unwrap();
+        assert!(second.cached_at.unwrap() >= handed_out);
+        assert!(second.fetched_at < handed_out);
+    }
+
+    #[tokio::test]
+    async fn shares_the_budget_between_shards() {
+        let config = PrefetchConfig {
+            // Room for two records' data across every shard
+            max_total_bytes: 2 * "datakey".len(),
+            ..PrefetchConfig::default()
+        };
+        let budget = prefetch_budget(&config);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let retriever = |results: Vec<Result<RecordBatch, RetrievalError>>| {
+            PrefetchingRetriever::new(
+                Box::new(ScriptedRetriever {
+                    results: results.into(),
+                    calls: calls.clone(),
+                }),
+                &config,
+                budget.clone(),
+            )
+        };
+        let mut first = retriever(vec![Ok(batch(&["1", "2"]))]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut second = retriever(vec![Ok(batch(&["3"]))]);
+
+        // The second shard's batch waits for the first shard's to be processed
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), second.next_batch())
+                .await
+                .is_err()
+        );
+        assert_eq!(sequence_numbers(&mut first).await, vec!["1", "2"]);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), second.next_batch())
+                .await
+                .is_err()
+        );
+        assert!(first.next_batch().await.is_none());
+        assert_eq!(sequence_numbers(&mut second).await, vec!["3"]);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::VecDeque, time::Instant as StdInstant};
+
+use tokio::time::Instant;
+
+use crate::{config::BatchShapingConfig, interface::record::KinesisClientRecord};
+
+/// Records to hand to the processor in one `process_records` call.
+pub(crate) struct ShapedBatch {
+    pub(crate) records: Vec<KinesisClientRecord>,
+    /// How far the checkpointer may go once these records have been handed over.
+    pub(crate) largest_permitted: Option<String>,
+    pub(crate) fetched_at: Option<StdInstant>,
+    pub(crate) cached_at: Option<StdInstant>,
+}
+
+impl ShapedBatch {
+    pub(crate) fn empty() -> Self {
+        Self {
+            records: Vec::new(),
+            largest_permitted: None,
+            fetched_at: None,
+            cached_at: None,
+        }
+    }
+}
+
+fn record_size(record: &KinesisClientRecord) -> usize {
+    record.data.len() + record.partition_key.len()
+}
+
+/// Splits fetched batches that are over the configured limits and, with a linger time, holds on
+/// to smaller ones until enough records have built up to fill a call.
+pub(crate) struct BatchShaper {
+    config: BatchShapingConfig,
+    pending: VecDeque<KinesisClientRecord>,
+    pending_bytes: usize,
+    /// The last sequence number read, including records dropped by the shard filter.
+    last_sequence_number: Option<String>,
+    /// When the oldest pending records were fetched and buffered.
+    fetched_at: Option<StdInstant>,
+    cached_at: Option<StdInstant>,
+    /// When the pending records have to go out, however few there are.
+    linger_deadline: Option<Instant>,
+}
+
+impl BatchShaper {
+    pub(crate) fn new(config: BatchShapingConfig) -> Self {
+        Self {
+            config,
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            last_sequence_number: None,
+            fetched_at: None,
+            cached_at: None,
+            linger_deadline: None,
+        }
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        records: Vec<KinesisClientRecord>,
+        last_sequence_number: Option<String>,
+        fetched_at: StdInstant,
+        cached_at: Option<StdInstant>,
+    ) {
+        if last_sequence_number.is_some() {
+            self.last_sequence_number = last_sequence_number;
+        }
+        if records.is_empty() {
+            return;
+        }
+        if self.pending.is_empty() {
+            self.fetched_at = Some(fetched_at);
+            self.cached_at = cached_at;
+            self.linger_deadline = self.config.linger.map(|linger| Instant::now() + linger);
+        }
+        self.pending_bytes += records.iter().map(record_size).sum::<usize>();
+        self.pending.extend(records);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub(crate) fn linger_deadline(&self) -> Option<Instant> {
+        self.linger_deadline.filter(|_| !self.pending.is_empty())
+    }
+
+    fn is_full(&self) -> bool {
+        matches!(self.config.max_records, Some(max_records) if self.pending.len() >= max_records)
+            || matches!(self.config.max_bytes, Some(max_bytes) if self.pending_bytes >= max_bytes)
+    }
+
+    /// Takes the next call's worth of records, if it's time for them to go out. `flush` sends
+    /// everything regardless of the linger time, at the end of a shard.
+    pub(crate) fn next_batch(&mut self, flush: bool) -> Option<ShapedBatch> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let lingering = match self.linger_deadline {
+            Some(deadline) => Instant::now() < deadline,
+            None => false,
+        };
+        if lingering && !flush && !self.is_full() {
+            return None;
+        }
+
+        let max_records = self.config.max_records.unwrap_or(usize::MAX).max(1);
+        let max_bytes = self.config.max_bytes.unwrap_or(usize::MAX);
+        let mut records = Vec::new();
+        let mut bytes = 0;
+        while let Some(record) = self.pending.front() {
+            let size = record_size(record);
+            // A record bigger than the byte limit still goes out, on its own
+            if records.len() == max_records || (!records.is_empty() && bytes + size > max_bytes) {
+                break;
+            }
+            bytes += size;
+            records.extend(self.pending.pop_front());
+        }
+        self.pending_bytes -= bytes;
+
+        let largest_permitted = if self.pending.is_empty() {
+            self.linger_deadline = None;
+            self.last_sequence_number.clone()
+        } else {
+            records.last().map(|record| record.sequence_number.clone())
+        };
+        Some(ShapedBatch {
+            records,
+            largest_permitted,
+            fetched_at: self.fetched_at,
+            cached_at: self.cached_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn record(sequence_number: u64, size: usize) -> KinesisClientRecord {
+        KinesisClientRecord {
+            sequence_number: sequence_number.to_string(),
+            data: Bytes::from(vec![0; size]),
+            partition_key: String::new(),
+            encryption_type: None,
+            sub_sequence_number: None,
+            explicit_hash_key: None,
+            aggregated: false,
+        }
+    }
+
+    fn records(sequence_numbers: std::ops::RangeInclusive<u64>) -> Vec<KinesisClientRecord> {
+        sequence_numbers.map(|n| record(n, 10)).collect()
+    }
+
+    fn sequence_numbers(batch: &ShapedBatch) -> Vec<u64> {
+        batch
+            .records
+            .iter()
+            .map(|record| record.sequence_number.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn passes_fetched_batches_through_by_default() {
+        let mut shaper = BatchShaper::new(BatchShapingConfig::default());
+        shaper.push(
+            records(1..=5),
+            Some("5".to_string()),
+            StdInstant::now(),
+            None,
+        );
+
+        let batch = shaper.next_batch(false).unwrap();
+        assert_eq!(sequence_numbers(&batch), vec![1, 2, 3, 4, 5]);
+        assert_eq!(batch.largest_permitted.as_deref(), Some("5"));
+        assert!(shaper.next_batch(false).is_none());
+    }
+
+    #[test]
+    fn splits_batches_over_the_record_limit() {
+        let mut shaper = BatchShaper::new(BatchShapingConfig {
+            max_records: Some(2),
+            ..BatchShapingConfig::default()
+        });
+        shaper.push(
+            records(1..=5),
+            Some("5".to_string()),
+            StdInstant::now(),
+            None,
+        );
+
+        let batches: Vec<ShapedBatch> = std::iter::from_fn(|| shaper.next_batch(false)).collect();
+        let split: Vec<Vec<u64>> = batches.iter().map(sequence_numbers).collect();
+        assert_eq!(split, vec![vec![1, 2], vec![3, 4], vec![5]]);
+        // Each split can only be checkpointed up to its own records
+        let permitted: Vec<Option<&str>> = batches
+            .iter()
+            .map(|batch| batch.largest_permitted.as_deref())
+            .collect();
+        assert_eq!(permitted, vec![Some("2"), Some("4"), Some("5")]);
+    }
+
+    #[test]
+    fn splits_batches_over_the_byte_limit() {
+        let mut shaper = BatchShaper::new(BatchShapingConfig {
+            max_bytes: Some(25),
+            ..BatchShapingConfig::default()
+        });
+        let fetched = vec![record(1, 10), record(2, 10), record(3, 40), record(4, 10)];
+        shaper.push(fetched, Some("4".to_string()), StdInstant::now(), None);
+
+        let split: Vec<Vec<u64>> = std::iter::from_fn(|| shaper.next_batch(false))
+            .map(|batch| sequence_numbers(&batch))
+            .collect();
+        // The oversized record still goes out, on its own
+        assert_eq!(split, vec![vec![1, 2], vec![3], vec![4]]);
+    }
+
+    #[test]
+    fn permits_checkpoints_past_filtered_records() {
+        let mut shaper = BatchShaper::new(BatchShapingConfig::default());
+        shaper.push(
+            records(1..=2),
+            Some("9".to_string()),
+            StdInstant::now(),
+            None,
+        );
+
+        let batch = shaper.next_batch(false).unwrap();
+        assert_eq!(batch.largest_permitted.as_deref(), Some("9"));
+    }
+
+    #[tokio::test]
+    async fn coalesces_small_batches_while_lingering() {
+        let mut shaper = BatchShaper::new(BatchShapingConfig {
+            max_records: Some(4),
+            linger: Some(Duration::from_secs(60)),
+            ..BatchShapingConfig::default()
+        });
+        shaper.push(
+            records(1..=2),
+            Some("2".to_string()),
+            StdInstant::now(),
+            None,
+        );
+        assert!(shaper.next_batch(false).is_none());
+        assert!(shaper.linger_deadline().is_some());
+
+        shaper.push(
+            records(3..=5),
+            Some("5".to_string()),
+            StdInstant::now(),
+            None,
+        );
+        let full = shaper.next_batch(false).unwrap();
+        assert_eq!(sequence_numbers(&full), vec![1, 2, 3, 4]);
+        // The rest waits for more, unless the shard has ended
+        assert!(shaper.next_batch(false).is_none());
+        let rest = shaper.next_batch(true).unwrap();
+        assert_eq!(sequence_numbers(&rest), vec![5]);
+        assert_eq!(rest.largest_permitted.as_deref(), Some("5"));
+        assert!(shaper.is_empty());
+        assert!(shaper.linger_deadline().is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_lingering_records_once_the_deadline_passes() {
+        let mut shaper = BatchShaper::new(BatchShapingConfig {
+            linger: Some(Duration::from_millis(10)),
+            ..BatchShapingConfig::default()
+        });
+        shaper.push(
+            records(1..=2),
+            Some("2".to_string()),
+            StdInstant::now(),
+            None,
+        );
+        assert!(shaper.next_batch(false).is_none());
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let batch = shaper.next_batch(false).unwrap();
+        assert_eq!(sequence_numbers(&batch), vec![1, 2]);
+    }
+}